@@ -8,13 +8,47 @@ pub struct Model {
     #[sea_orm(primary_key)]
     pub id: i32,
     pub label: String,
-    pub url: String,
+    pub source_url: Option<String>,
+    pub mime_type: Option<String>,
+    pub storage_key: Option<String>,
+    pub content_hash: Option<String>,
+    pub perceptual_hash: Option<i64>,
+    #[sea_orm(column_type = "JsonBinary", nullable)]
+    pub color_histogram: Option<Json>,
+    pub camera_make: Option<String>,
+    pub camera_model: Option<String>,
+    pub lens_model: Option<String>,
+    pub taken_at: Option<DateTime>,
+    pub orientation: Option<i32>,
+    #[sea_orm(column_type = "JsonBinary", nullable)]
+    pub exif: Option<Json>,
+    #[sea_orm(column_type = "Double", nullable)]
+    pub latitude: Option<f64>,
+    #[sea_orm(column_type = "Double", nullable)]
+    pub longitude: Option<f64>,
+    pub width: Option<i32>,
+    pub height: Option<i32>,
+    pub byte_size: Option<i64>,
+    pub blurhash: Option<String>,
+    pub sharpness: Option<f32>,
+    pub brightness: Option<f32>,
+    pub contrast: Option<f32>,
+    pub noise: Option<f32>,
+    pub variants_generated: bool,
 }
 
 #[derive(Copy, Clone, Debug, EnumIter, DeriveRelation)]
 pub enum Relation {
     #[sea_orm(has_many = "super::image_tag::Entity")]
     ImageTag,
+    #[sea_orm(has_many = "super::image_category::Entity")]
+    ImageCategory,
+    #[sea_orm(has_many = "super::image_crop::Entity")]
+    ImageCrop,
+    #[sea_orm(has_many = "super::tagger_call::Entity")]
+    TaggerCall,
+    #[sea_orm(has_many = "super::image_variant::Entity")]
+    ImageVariant,
 }
 
 impl Related<super::tag::Entity> for Entity {
@@ -26,4 +60,25 @@ impl Related<super::tag::Entity> for Entity {
     }
 }
 
+impl Related<super::image_crop::Entity> for Entity {
+    fn to() -> RelationDef {
+        Relation::ImageCrop.def()
+    }
+}
+
+impl Related<super::image_variant::Entity> for Entity {
+    fn to() -> RelationDef {
+        Relation::ImageVariant.def()
+    }
+}
+
+impl Related<super::category::Entity> for Entity {
+    fn to() -> RelationDef {
+        super::image_category::Relation::Category.def()
+    }
+    fn via() -> Option<RelationDef> {
+        Some(super::image_category::Relation::Image.def().rev())
+    }
+}
+
 impl ActiveModelBehavior for ActiveModel {}
@@ -2,6 +2,11 @@
 
 pub mod prelude;
 
+pub mod category;
 pub mod image;
+pub mod image_category;
+pub mod image_crop;
 pub mod image_tag;
+pub mod image_variant;
 pub mod tag;
+pub mod tagger_call;
@@ -1,5 +1,10 @@
 //! SeaORM Entity. Generated by sea-orm-codegen 0.9.2
 
+pub use super::category::Entity as Category;
 pub use super::image::Entity as Image;
+pub use super::image_category::Entity as ImageCategory;
+pub use super::image_crop::Entity as ImageCrop;
 pub use super::image_tag::Entity as ImageTag;
+pub use super::image_variant::Entity as ImageVariant;
 pub use super::tag::Entity as Tag;
+pub use super::tagger_call::Entity as TaggerCall;
@@ -3,9 +3,29 @@
 // the lower-level SeaQuery query builder (e.g. for serving a request like
 // `GET /images?objects=cat,dog` where we need more advanced joins.
 pub use m20220101_000001_create_table::{Image, Tag, ImageTag};
+pub use m20221018_000002_create_category_tables::{Category, ImageCategory};
+pub use m20221018_000003_create_image_crop_table::ImageCrop;
+pub use m20221018_000004_create_tagger_call_table::TaggerCall;
+pub use m20221018_000008_create_image_variant_table::ImageVariant;
 pub use sea_orm_migration::prelude::*;
 
 mod m20220101_000001_create_table;
+mod m20221018_000002_create_category_tables;
+mod m20221018_000003_create_image_crop_table;
+mod m20221018_000004_create_tagger_call_table;
+mod m20221018_000005_add_image_mime_type;
+mod m20221018_000006_store_storage_keys;
+mod m20221018_000007_rename_image_url_to_source_url;
+mod m20221018_000008_create_image_variant_table;
+mod m20221018_000009_add_image_content_hash;
+mod m20221018_000010_add_image_perceptual_hash;
+mod m20221018_000011_add_image_color_histogram;
+mod m20221018_000012_add_image_exif;
+mod m20221018_000013_add_image_location;
+mod m20221018_000014_add_image_dimensions;
+mod m20221018_000015_add_image_blurhash;
+mod m20221018_000016_add_image_quality;
+mod m20221018_000017_add_image_variants_generated;
 
 // We export this so our server can run migrations on startup if they have
 // not already been run. This makes deployment easier. SeaORM itself manages
@@ -16,6 +36,24 @@ pub struct Migrator;
 #[async_trait::async_trait]
 impl MigratorTrait for Migrator {
     fn migrations() -> Vec<Box<dyn MigrationTrait>> {
-        vec![Box::new(m20220101_000001_create_table::Migration)]
+        vec![
+            Box::new(m20220101_000001_create_table::Migration),
+            Box::new(m20221018_000002_create_category_tables::Migration),
+            Box::new(m20221018_000003_create_image_crop_table::Migration),
+            Box::new(m20221018_000004_create_tagger_call_table::Migration),
+            Box::new(m20221018_000005_add_image_mime_type::Migration),
+            Box::new(m20221018_000006_store_storage_keys::Migration),
+            Box::new(m20221018_000007_rename_image_url_to_source_url::Migration),
+            Box::new(m20221018_000008_create_image_variant_table::Migration),
+            Box::new(m20221018_000009_add_image_content_hash::Migration),
+            Box::new(m20221018_000010_add_image_perceptual_hash::Migration),
+            Box::new(m20221018_000011_add_image_color_histogram::Migration),
+            Box::new(m20221018_000012_add_image_exif::Migration),
+            Box::new(m20221018_000013_add_image_location::Migration),
+            Box::new(m20221018_000014_add_image_dimensions::Migration),
+            Box::new(m20221018_000015_add_image_blurhash::Migration),
+            Box::new(m20221018_000016_add_image_quality::Migration),
+            Box::new(m20221018_000017_add_image_variants_generated::Migration),
+        ]
     }
 }
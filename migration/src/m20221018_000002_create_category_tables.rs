@@ -0,0 +1,131 @@
+use sea_orm_migration::prelude::*;
+
+use crate::m20220101_000001_create_table::Image;
+
+#[derive(DeriveMigrationName)]
+pub struct Migration;
+
+/// This migration adds the tables used to store Imagga categorizer results.
+/// Categories are kept separate from object tags because they describe the
+/// image as a whole (e.g. "interior objects") rather than an object in it,
+/// and because each one comes with a confidence that we want to keep.
+///
+/// ┌────────────────────┐
+/// │ Category           │
+/// ├────────────────────┤
+/// │*id (integer)       │
+/// │ name (string)      │
+/// │ categorizer(string)│
+/// └────────────────────┘
+///   ▲
+///   │  ┌────────────────────────┐
+///   │  │ ImageCategory          │
+///   │  ├────────────────────────┤
+///   │  │*image_id (integer FK)  ├──► Image
+///   └──┤*category_id (int FK)   │
+///      │ confidence (float)     │
+///      └────────────────────────┘
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+    /// Create the tables if they do not already exist
+    async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .create_table(
+                Table::create()
+                    .table(Category::Table)
+                    .if_not_exists()
+                    .col(
+                        ColumnDef::new(Category::Id)
+                            .integer()
+                            .not_null()
+                            .auto_increment()
+                            .primary_key()
+                    )
+                    .col(ColumnDef::new(Category::Name).string().not_null())
+                    .col(ColumnDef::new(Category::Categorizer).string().not_null())
+                    .to_owned()
+            )
+            .await?;
+
+        // The same category name can be produced by different categorizers,
+        // so a category is only unique per categorizer
+        manager
+            .create_index(
+                Index::create()
+                    .name("IDX_Category_Categorizer_Name")
+                    .table(Category::Table)
+                    .col(Category::Categorizer)
+                    .col(Category::Name)
+                    .unique()
+                    .to_owned()
+            )
+            .await?;
+
+        manager
+            .create_table(
+                Table::create()
+                    .table(ImageCategory::Table)
+                    .if_not_exists()
+                    .col(
+                        ColumnDef::new(ImageCategory::ImageId)
+                            .integer()
+                            .not_null()
+                    )
+                    .col(
+                        ColumnDef::new(ImageCategory::CategoryId)
+                            .integer()
+                            .not_null()
+                    )
+                    .col(ColumnDef::new(ImageCategory::Confidence).float().not_null())
+                    .primary_key(
+                        Index::create()
+                            .col(ImageCategory::ImageId)
+                            .col(ImageCategory::CategoryId)
+                    )
+                    .foreign_key(
+                        ForeignKey::create()
+                            .name("FK_ImageCategory_ImageId")
+                            .from(ImageCategory::Table, ImageCategory::ImageId)
+                            .to(Image::Table, Image::Id)
+                            .on_delete(ForeignKeyAction::Cascade)
+                            .on_update(ForeignKeyAction::Cascade)
+                    )
+                    .foreign_key(
+                        ForeignKey::create()
+                            .name("FK_ImageCategory_CategoryId")
+                            .from(ImageCategory::Table, ImageCategory::CategoryId)
+                            .to(Category::Table, Category::Id)
+                            .on_delete(ForeignKeyAction::Cascade)
+                            .on_update(ForeignKeyAction::Cascade)
+                    )
+                    .to_owned()
+            )
+            .await
+    }
+
+    // Drop the category tables (the junction table first because of its foreign keys)
+    async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .drop_table(Table::drop().table(ImageCategory::Table).to_owned())
+            .await?;
+        manager
+            .drop_table(Table::drop().table(Category::Table).to_owned())
+            .await
+    }
+}
+
+#[derive(Iden)]
+pub enum Category {
+    Table,
+    Id,
+    Name,
+    Categorizer
+}
+
+#[derive(Iden)]
+pub enum ImageCategory {
+    Table,
+    ImageId,
+    CategoryId,
+    Confidence
+}
@@ -0,0 +1,74 @@
+use sea_orm_migration::prelude::*;
+
+use crate::m20220101_000001_create_table::Image;
+
+#[derive(DeriveMigrationName)]
+pub struct Migration;
+
+/// This migration adds the ImageCrop table, which keeps track of the
+/// cropped versions (e.g. square, 16:9) generated for an uploaded image.
+/// Each crop is identified by the image it belongs to and the name of its
+/// shape, and stores where it was uploaded to along with its size.
+///
+/// ┌──────────────────────┐
+/// │ ImageCrop            │
+/// ├──────────────────────┤
+/// │*image_id (integer FK)├──► Image
+/// │*name (string)        │
+/// │ url (string)         │
+/// │ width (integer)      │
+/// │ height (integer)     │
+/// └──────────────────────┘
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+    /// Create the table if it does not already exist
+    async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .create_table(
+                Table::create()
+                    .table(ImageCrop::Table)
+                    .if_not_exists()
+                    .col(
+                        ColumnDef::new(ImageCrop::ImageId)
+                            .integer()
+                            .not_null()
+                    )
+                    .col(ColumnDef::new(ImageCrop::Name).string().not_null())
+                    .col(ColumnDef::new(ImageCrop::Url).string().not_null())
+                    .col(ColumnDef::new(ImageCrop::Width).integer().not_null())
+                    .col(ColumnDef::new(ImageCrop::Height).integer().not_null())
+                    .primary_key(
+                        Index::create()
+                            .col(ImageCrop::ImageId)
+                            .col(ImageCrop::Name)
+                    )
+                    .foreign_key(
+                        ForeignKey::create()
+                            .name("FK_ImageCrop_ImageId")
+                            .from(ImageCrop::Table, ImageCrop::ImageId)
+                            .to(Image::Table, Image::Id)
+                            .on_delete(ForeignKeyAction::Cascade)
+                            .on_update(ForeignKeyAction::Cascade)
+                    )
+                    .to_owned()
+            )
+            .await
+    }
+
+    // Drop the table, reverting the database to the previous migration
+    async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .drop_table(Table::drop().table(ImageCrop::Table).to_owned())
+            .await
+    }
+}
+
+#[derive(Iden)]
+pub enum ImageCrop {
+    Table,
+    ImageId,
+    Name,
+    Url,
+    Width,
+    Height
+}
@@ -0,0 +1,91 @@
+use sea_orm_migration::prelude::*;
+
+use crate::m20220101_000001_create_table::Image;
+
+#[derive(DeriveMigrationName)]
+pub struct Migration;
+
+/// This migration adds the TaggerCall table, which has one row for every
+/// request we make to Imagga. It lets us see how much of our Imagga quota
+/// we are using (and on what) before we run out of it.
+/// The image id is optional because calls can fail before an image is
+/// inserted, and it is cleared if the image is later deleted.
+///
+/// ┌──────────────────────────────┐
+/// │ TaggerCall                   │
+/// ├──────────────────────────────┤
+/// │*id (integer)                 │
+/// │ endpoint (string)            │
+/// │ image_id (integer FK, null)  ├──► Image
+/// │ status (integer, null)       │
+/// │ latency_ms (integer)         │
+/// │ created_at (timestamp w/ tz) │
+/// └──────────────────────────────┘
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+    /// Create the table if it does not already exist
+    async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .create_table(
+                Table::create()
+                    .table(TaggerCall::Table)
+                    .if_not_exists()
+                    .col(
+                        ColumnDef::new(TaggerCall::Id)
+                            .integer()
+                            .not_null()
+                            .auto_increment()
+                            .primary_key()
+                    )
+                    .col(ColumnDef::new(TaggerCall::Endpoint).string().not_null())
+                    .col(ColumnDef::new(TaggerCall::ImageId).integer().null())
+                    // The HTTP status code, or null if no response was received
+                    .col(ColumnDef::new(TaggerCall::Status).integer().null())
+                    .col(ColumnDef::new(TaggerCall::LatencyMs).integer().not_null())
+                    .col(
+                        ColumnDef::new(TaggerCall::CreatedAt)
+                            .timestamp_with_time_zone()
+                            .not_null()
+                    )
+                    .foreign_key(
+                        ForeignKey::create()
+                            .name("FK_TaggerCall_ImageId")
+                            .from(TaggerCall::Table, TaggerCall::ImageId)
+                            .to(Image::Table, Image::Id)
+                            .on_delete(ForeignKeyAction::SetNull)
+                            .on_update(ForeignKeyAction::Cascade)
+                    )
+                    .to_owned()
+            )
+            .await?;
+
+        // Usage reports always filter and group by when the call was made
+        manager
+            .create_index(
+                Index::create()
+                    .name("IDX_TaggerCall_CreatedAt")
+                    .table(TaggerCall::Table)
+                    .col(TaggerCall::CreatedAt)
+                    .to_owned()
+            )
+            .await
+    }
+
+    // Drop the table, reverting the database to the previous migration
+    async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .drop_table(Table::drop().table(TaggerCall::Table).to_owned())
+            .await
+    }
+}
+
+#[derive(Iden)]
+pub enum TaggerCall {
+    Table,
+    Id,
+    Endpoint,
+    ImageId,
+    Status,
+    LatencyMs,
+    CreatedAt
+}
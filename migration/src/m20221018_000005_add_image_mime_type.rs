@@ -0,0 +1,49 @@
+use sea_orm_migration::prelude::*;
+
+#[derive(DeriveMigrationName)]
+pub struct Migration;
+
+/// This migration adds the mime_type column to the Image table, which records
+/// the format that an uploaded image was stored in (e.g. `image/jpeg`).
+/// It is null for images that were specified by URL, since we don't store those.
+///
+/// ┌──────────────────────┐
+/// │ Image                │
+/// ├──────────────────────┤
+/// │ ...                  │
+/// │ mime_type (string?)  │
+/// └──────────────────────┘
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+    /// Add the column. It is left null for existing images, since we can't tell
+    /// which of them were uploaded to us from their URL alone.
+    async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .alter_table(
+                Table::alter()
+                    .table(Image::Table)
+                    .add_column(ColumnDef::new(Image::MimeType).string())
+                    .to_owned()
+            )
+            .await
+    }
+
+    // Drop the column, reverting the database to the previous migration
+    async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .alter_table(
+                Table::alter()
+                    .table(Image::Table)
+                    .drop_column(Image::MimeType)
+                    .to_owned()
+            )
+            .await
+    }
+}
+
+// The columns of the Image table used by this migration
+#[derive(Iden)]
+enum Image {
+    Table,
+    MimeType
+}
@@ -0,0 +1,106 @@
+use sea_orm_migration::prelude::*;
+use sea_orm_migration::sea_orm::{ConnectionTrait, Statement};
+
+#[derive(DeriveMigrationName)]
+pub struct Migration;
+
+/// This migration stops storing the full URLs of files we store ourselves, so that
+/// those URLs can change (e.g. when the API moves to a new domain). Instead, we store
+/// each file's storage key (its filename, e.g. `1.png`) and build the URL from it.
+/// The url column of the Image table is now only used for images specified by URL,
+/// so it becomes nullable.
+///
+/// ┌──────────────────────┐    ┌─────────────────────────┐
+/// │ Image                │    │ ImageCrop               │
+/// ├──────────────────────┤    ├─────────────────────────┤
+/// │ ...                  │    │ ...                     │
+/// │ url (string?)        │    │ storage_key (string)    │ (renamed from url)
+/// │ storage_key (string?)│    │ ...                     │
+/// └──────────────────────┘    └─────────────────────────┘
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+    /// Add the storage keys, and derive them from the URLs of existing files
+    async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .alter_table(
+                Table::alter()
+                    .table(Image::Table)
+                    .add_column(ColumnDef::new(Image::StorageKey).string())
+                    .modify_column(ColumnDef::new(Image::Url).string().null())
+                    .to_owned()
+            )
+            .await?;
+        manager
+            .alter_table(
+                Table::alter()
+                    .table(ImageCrop::Table)
+                    .rename_column(ImageCrop::Url, ImageCrop::StorageKey)
+                    .to_owned()
+            )
+            .await?;
+        // The key is the last segment of the URL. Uploaded images either have a
+        // mime type (see the previous migration) or were stored as `{id}.png`.
+        execute(
+            manager,
+            "UPDATE image SET storage_key = regexp_replace(url, '^.*/', ''), url = NULL \
+             WHERE mime_type IS NOT NULL OR url LIKE '%/' || id || '.png'",
+        )
+        .await?;
+        execute(
+            manager,
+            "UPDATE image_crop SET storage_key = regexp_replace(storage_key, '^.*/', '')",
+        )
+        .await
+    }
+
+    // Revert to storing URLs. Since we don't know the URLs that the files were served
+    // under any more, the storage keys are used as (relative) URLs.
+    async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        execute(
+            manager,
+            "UPDATE image SET url = storage_key WHERE url IS NULL",
+        )
+        .await?;
+        manager
+            .alter_table(
+                Table::alter()
+                    .table(ImageCrop::Table)
+                    .rename_column(ImageCrop::StorageKey, ImageCrop::Url)
+                    .to_owned()
+            )
+            .await?;
+        manager
+            .alter_table(
+                Table::alter()
+                    .table(Image::Table)
+                    .drop_column(Image::StorageKey)
+                    .modify_column(ColumnDef::new(Image::Url).string().not_null())
+                    .to_owned()
+            )
+            .await
+    }
+}
+
+/// Run raw SQL, for updates that are easier to write that way
+async fn execute(manager: &SchemaManager<'_>, sql: &str) -> Result<(), DbErr> {
+    let db = manager.get_connection();
+    db.execute(Statement::from_string(db.get_database_backend(), sql.to_owned()))
+        .await?;
+    Ok(())
+}
+
+// The columns of the Image table used by this migration
+#[derive(Iden)]
+enum Image {
+    Table,
+    Url,
+    StorageKey
+}
+
+// The columns of the ImageCrop table used by this migration
+#[derive(Iden)]
+enum ImageCrop {
+    Table,
+    Url,
+    StorageKey
+}
@@ -0,0 +1,50 @@
+use sea_orm_migration::prelude::*;
+
+#[derive(DeriveMigrationName)]
+pub struct Migration;
+
+/// This migration renames the url column of the Image table to source_url, since
+/// it now only records where an image came from. Images specified by URL can be
+/// mirrored, in which case they have both a source URL and a storage key.
+///
+/// ┌──────────────────────┐
+/// │ Image                │
+/// ├──────────────────────┤
+/// │ ...                  │
+/// │ source_url (string?) │ (renamed from url)
+/// │ storage_key (string?)│
+/// └──────────────────────┘
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+    /// Rename the column
+    async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .alter_table(
+                Table::alter()
+                    .table(Image::Table)
+                    .rename_column(Image::Url, Image::SourceUrl)
+                    .to_owned()
+            )
+            .await
+    }
+
+    // Rename the column back, reverting the database to the previous migration
+    async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .alter_table(
+                Table::alter()
+                    .table(Image::Table)
+                    .rename_column(Image::SourceUrl, Image::Url)
+                    .to_owned()
+            )
+            .await
+    }
+}
+
+// The columns of the Image table used by this migration
+#[derive(Iden)]
+enum Image {
+    Table,
+    Url,
+    SourceUrl
+}
@@ -0,0 +1,77 @@
+use sea_orm_migration::prelude::*;
+
+use crate::m20220101_000001_create_table::Image;
+
+#[derive(DeriveMigrationName)]
+pub struct Migration;
+
+/// This migration adds the ImageVariant table, which keeps track of the
+/// resized versions (e.g. thumbnails) generated for an image we store.
+/// Each variant is identified by the image it belongs to and its name
+/// (e.g. `600` for the one that is at most 600 pixels on its longest side).
+///
+/// ┌──────────────────────┐
+/// │ ImageVariant         │
+/// ├──────────────────────┤
+/// │*image_id (integer FK)├──► Image
+/// │*name (string)        │
+/// │ storage_key (string) │
+/// │ mime_type (string)   │
+/// │ width (integer)      │
+/// │ height (integer)     │
+/// └──────────────────────┘
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+    /// Create the table if it does not already exist
+    async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .create_table(
+                Table::create()
+                    .table(ImageVariant::Table)
+                    .if_not_exists()
+                    .col(
+                        ColumnDef::new(ImageVariant::ImageId)
+                            .integer()
+                            .not_null()
+                    )
+                    .col(ColumnDef::new(ImageVariant::Name).string().not_null())
+                    .col(ColumnDef::new(ImageVariant::StorageKey).string().not_null())
+                    .col(ColumnDef::new(ImageVariant::MimeType).string().not_null())
+                    .col(ColumnDef::new(ImageVariant::Width).integer().not_null())
+                    .col(ColumnDef::new(ImageVariant::Height).integer().not_null())
+                    .primary_key(
+                        Index::create()
+                            .col(ImageVariant::ImageId)
+                            .col(ImageVariant::Name)
+                    )
+                    .foreign_key(
+                        ForeignKey::create()
+                            .name("FK_ImageVariant_ImageId")
+                            .from(ImageVariant::Table, ImageVariant::ImageId)
+                            .to(Image::Table, Image::Id)
+                            .on_delete(ForeignKeyAction::Cascade)
+                            .on_update(ForeignKeyAction::Cascade)
+                    )
+                    .to_owned()
+            )
+            .await
+    }
+
+    // Drop the table, reverting the database to the previous migration
+    async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .drop_table(Table::drop().table(ImageVariant::Table).to_owned())
+            .await
+    }
+}
+
+#[derive(Iden)]
+pub enum ImageVariant {
+    Table,
+    ImageId,
+    Name,
+    StorageKey,
+    MimeType,
+    Width,
+    Height
+}
@@ -0,0 +1,60 @@
+use sea_orm_migration::prelude::*;
+
+#[derive(DeriveMigrationName)]
+pub struct Migration;
+
+/// This migration adds the content_hash column to the Image table, which is the
+/// SHA-256 hash (in hex) of a stored image's original file. Files are stored under
+/// their hash, so images with the same content share a single file. The column is
+/// null for images given by URL, and for images stored before this migration.
+///
+/// ┌───────────────────────┐
+/// │ Image                 │
+/// ├───────────────────────┤
+/// │ ...                   │
+/// │ content_hash (string?)│
+/// └───────────────────────┘
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+    async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .alter_table(
+                Table::alter()
+                    .table(Image::Table)
+                    .add_column(ColumnDef::new(Image::ContentHash).string())
+                    .to_owned()
+            )
+            .await?;
+
+        // New uploads are looked up by their hash to find duplicates. The index isn't
+        // unique, since several images can be linked to the same file.
+        manager
+            .create_index(
+                Index::create()
+                    .name("IDX_Image_ContentHash")
+                    .table(Image::Table)
+                    .col(Image::ContentHash)
+                    .to_owned()
+            )
+            .await
+    }
+
+    // Drop the column (and with it, its index), reverting the database to the previous migration
+    async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .alter_table(
+                Table::alter()
+                    .table(Image::Table)
+                    .drop_column(Image::ContentHash)
+                    .to_owned()
+            )
+            .await
+    }
+}
+
+// The columns of the Image table used by this migration
+#[derive(Iden)]
+enum Image {
+    Table,
+    ContentHash
+}
@@ -0,0 +1,49 @@
+use sea_orm_migration::prelude::*;
+
+#[derive(DeriveMigrationName)]
+pub struct Migration;
+
+/// This migration adds the perceptual_hash column to the Image table, which is a
+/// 64-bit difference hash (dHash) of a stored image. Unlike the content_hash, it
+/// barely changes when an image is resized or recompressed, so it is used to find
+/// near-duplicates. It is null for images given by URL, and for images stored
+/// before this migration.
+///
+/// ┌──────────────────────────┐
+/// │ Image                    │
+/// ├──────────────────────────┤
+/// │ ...                      │
+/// │ perceptual_hash (bigint?)│
+/// └──────────────────────────┘
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+    async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .alter_table(
+                Table::alter()
+                    .table(Image::Table)
+                    .add_column(ColumnDef::new(Image::PerceptualHash).big_integer())
+                    .to_owned()
+            )
+            .await
+    }
+
+    // Drop the column, reverting the database to the previous migration
+    async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .alter_table(
+                Table::alter()
+                    .table(Image::Table)
+                    .drop_column(Image::PerceptualHash)
+                    .to_owned()
+            )
+            .await
+    }
+}
+
+// The columns of the Image table used by this migration
+#[derive(Iden)]
+enum Image {
+    Table,
+    PerceptualHash
+}
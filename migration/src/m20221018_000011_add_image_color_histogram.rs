@@ -0,0 +1,49 @@
+use sea_orm_migration::prelude::*;
+
+#[derive(DeriveMigrationName)]
+pub struct Migration;
+
+/// This migration adds the color_histogram column to the Image table, which is the
+/// share of a stored image's pixels that falls into each of 64 color bins (a JSON
+/// array of numbers). It is used together with the perceptual_hash to find the stored
+/// images that look most like a given image. It is null for images given by URL, and
+/// for images stored before this migration.
+///
+/// ┌──────────────────────────┐
+/// │ Image                    │
+/// ├──────────────────────────┤
+/// │ ...                      │
+/// │ color_histogram (jsonb?) │
+/// └──────────────────────────┘
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+    async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .alter_table(
+                Table::alter()
+                    .table(Image::Table)
+                    .add_column(ColumnDef::new(Image::ColorHistogram).json_binary())
+                    .to_owned()
+            )
+            .await
+    }
+
+    // Drop the column, reverting the database to the previous migration
+    async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .alter_table(
+                Table::alter()
+                    .table(Image::Table)
+                    .drop_column(Image::ColorHistogram)
+                    .to_owned()
+            )
+            .await
+    }
+}
+
+// The columns of the Image table used by this migration
+#[derive(Iden)]
+enum Image {
+    Table,
+    ColorHistogram
+}
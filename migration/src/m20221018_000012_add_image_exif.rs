@@ -0,0 +1,82 @@
+use sea_orm_migration::prelude::*;
+
+#[derive(DeriveMigrationName)]
+pub struct Migration;
+
+/// This migration adds columns to the Image table for the EXIF metadata of stored
+/// images (e.g. which camera took a photo, and when). The fields we filter and sort
+/// by get their own columns, and every field we could read is kept in the exif column
+/// as a JSON object (e.g. `{"Model": "Pixel 6", "ExposureTime": "1/120 s", ...}`).
+/// The columns are null for images given by URL, images without EXIF metadata, and
+/// images stored before this migration.
+///
+/// ┌───────────────────────┐
+/// │ Image                 │
+/// ├───────────────────────┤
+/// │ ...                   │
+/// │ camera_make (string?) │
+/// │ camera_model (string?)│
+/// │ lens_model (string?)  │
+/// │ taken_at (timestamp?) │
+/// │ orientation (int?)    │
+/// │ exif (jsonb?)         │
+/// └───────────────────────┘
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+    async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .alter_table(
+                Table::alter()
+                    .table(Image::Table)
+                    .add_column(ColumnDef::new(Image::CameraMake).string())
+                    .add_column(ColumnDef::new(Image::CameraModel).string())
+                    .add_column(ColumnDef::new(Image::LensModel).string())
+                    // EXIF dates are in the camera's local time, without a time zone
+                    .add_column(ColumnDef::new(Image::TakenAt).timestamp())
+                    .add_column(ColumnDef::new(Image::Orientation).integer())
+                    .add_column(ColumnDef::new(Image::Exif).json_binary())
+                    .to_owned()
+            )
+            .await?;
+
+        // Images are filtered by when they were taken (e.g. `GET /images?taken_after=2022-01-01`)
+        manager
+            .create_index(
+                Index::create()
+                    .name("IDX_Image_TakenAt")
+                    .table(Image::Table)
+                    .col(Image::TakenAt)
+                    .to_owned()
+            )
+            .await
+    }
+
+    // Drop the columns (and with them, the index), reverting the database to the previous migration
+    async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .alter_table(
+                Table::alter()
+                    .table(Image::Table)
+                    .drop_column(Image::CameraMake)
+                    .drop_column(Image::CameraModel)
+                    .drop_column(Image::LensModel)
+                    .drop_column(Image::TakenAt)
+                    .drop_column(Image::Orientation)
+                    .drop_column(Image::Exif)
+                    .to_owned()
+            )
+            .await
+    }
+}
+
+// The columns of the Image table used by this migration
+#[derive(Iden)]
+enum Image {
+    Table,
+    CameraMake,
+    CameraModel,
+    LensModel,
+    TakenAt,
+    Orientation,
+    Exif
+}
@@ -0,0 +1,63 @@
+use sea_orm_migration::prelude::*;
+
+#[derive(DeriveMigrationName)]
+pub struct Migration;
+
+/// This migration adds the latitude and longitude columns to the Image table, which
+/// record where a photo was taken (in degrees, from its EXIF GPS metadata or set by
+/// hand). They are null for images whose location we don't know.
+///
+/// ┌──────────────────────┐
+/// │ Image                │
+/// ├──────────────────────┤
+/// │ ...                  │
+/// │ latitude (double?)   │
+/// │ longitude (double?)  │
+/// └──────────────────────┘
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+    async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .alter_table(
+                Table::alter()
+                    .table(Image::Table)
+                    .add_column(ColumnDef::new(Image::Latitude).double())
+                    .add_column(ColumnDef::new(Image::Longitude).double())
+                    .to_owned()
+            )
+            .await?;
+
+        // Images are filtered by bounding boxes (which radius queries also start with)
+        manager
+            .create_index(
+                Index::create()
+                    .name("IDX_Image_Location")
+                    .table(Image::Table)
+                    .col(Image::Latitude)
+                    .col(Image::Longitude)
+                    .to_owned()
+            )
+            .await
+    }
+
+    // Drop the columns (and with them, the index), reverting the database to the previous migration
+    async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .alter_table(
+                Table::alter()
+                    .table(Image::Table)
+                    .drop_column(Image::Latitude)
+                    .drop_column(Image::Longitude)
+                    .to_owned()
+            )
+            .await
+    }
+}
+
+// The columns of the Image table used by this migration
+#[derive(Iden)]
+enum Image {
+    Table,
+    Latitude,
+    Longitude
+}
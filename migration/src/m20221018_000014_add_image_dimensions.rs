@@ -0,0 +1,69 @@
+use sea_orm_migration::prelude::*;
+
+#[derive(DeriveMigrationName)]
+pub struct Migration;
+
+/// This migration adds the width, height and byte_size columns to the Image table,
+/// so that clients can lay out images without downloading them. The width and height
+/// are those of the upright image, in pixels, and byte_size is the size of its file.
+/// They are null for images stored before this migration (and for images given by URL
+/// that we couldn't download).
+///
+/// ┌───────────────────────┐
+/// │ Image                 │
+/// ├───────────────────────┤
+/// │ ...                   │
+/// │ width (int?)          │
+/// │ height (int?)         │
+/// │ byte_size (bigint?)   │
+/// └───────────────────────┘
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+    async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .alter_table(
+                Table::alter()
+                    .table(Image::Table)
+                    .add_column(ColumnDef::new(Image::Width).integer())
+                    .add_column(ColumnDef::new(Image::Height).integer())
+                    .add_column(ColumnDef::new(Image::ByteSize).big_integer())
+                    .to_owned()
+            )
+            .await?;
+
+        // Images are filtered by their minimum width and height
+        manager
+            .create_index(
+                Index::create()
+                    .name("IDX_Image_Dimensions")
+                    .table(Image::Table)
+                    .col(Image::Width)
+                    .col(Image::Height)
+                    .to_owned()
+            )
+            .await
+    }
+
+    // Drop the columns (and with them, the index), reverting the database to the previous migration
+    async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .alter_table(
+                Table::alter()
+                    .table(Image::Table)
+                    .drop_column(Image::Width)
+                    .drop_column(Image::Height)
+                    .drop_column(Image::ByteSize)
+                    .to_owned()
+            )
+            .await
+    }
+}
+
+// The columns of the Image table used by this migration
+#[derive(Iden)]
+enum Image {
+    Table,
+    Width,
+    Height,
+    ByteSize
+}
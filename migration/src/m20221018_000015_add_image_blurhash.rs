@@ -0,0 +1,48 @@
+use sea_orm_migration::prelude::*;
+
+#[derive(DeriveMigrationName)]
+pub struct Migration;
+
+/// This migration adds the blurhash column to the Image table, which holds a
+/// BlurHash placeholder for stored images (see https://blurha.sh). It is null for
+/// images given by URL, and for images stored before this migration until they
+/// are backfilled (`cargo run -- backfill-blurhashes`).
+///
+/// ┌──────────────────────┐
+/// │ Image                │
+/// ├──────────────────────┤
+/// │ ...                  │
+/// │ blurhash (string?)   │
+/// └──────────────────────┘
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+    async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .alter_table(
+                Table::alter()
+                    .table(Image::Table)
+                    .add_column(ColumnDef::new(Image::Blurhash).string())
+                    .to_owned()
+            )
+            .await
+    }
+
+    // Drop the column, reverting the database to the previous migration
+    async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .alter_table(
+                Table::alter()
+                    .table(Image::Table)
+                    .drop_column(Image::Blurhash)
+                    .to_owned()
+            )
+            .await
+    }
+}
+
+// The columns of the Image table used by this migration
+#[derive(Iden)]
+enum Image {
+    Table,
+    Blurhash
+}
@@ -0,0 +1,70 @@
+use sea_orm_migration::prelude::*;
+
+#[derive(DeriveMigrationName)]
+pub struct Migration;
+
+/// This migration adds the sharpness, brightness, contrast and noise columns to the
+/// Image table, which measure how usable a stored photo looks (see image_quality.rs).
+/// They are null for images given by URL and images stored before this migration.
+///
+/// ┌───────────────────────┐
+/// │ Image                 │
+/// ├───────────────────────┤
+/// │ ...                   │
+/// │ sharpness (float?)    │
+/// │ brightness (float?)   │
+/// │ contrast (float?)     │
+/// │ noise (float?)        │
+/// └───────────────────────┘
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+    async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .alter_table(
+                Table::alter()
+                    .table(Image::Table)
+                    .add_column(ColumnDef::new(Image::Sharpness).float())
+                    .add_column(ColumnDef::new(Image::Brightness).float())
+                    .add_column(ColumnDef::new(Image::Contrast).float())
+                    .add_column(ColumnDef::new(Image::Noise).float())
+                    .to_owned()
+            )
+            .await?;
+
+        // Images are filtered by their minimum sharpness
+        manager
+            .create_index(
+                Index::create()
+                    .name("IDX_Image_Sharpness")
+                    .table(Image::Table)
+                    .col(Image::Sharpness)
+                    .to_owned()
+            )
+            .await
+    }
+
+    // Drop the columns (and with them, the index), reverting the database to the previous migration
+    async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .alter_table(
+                Table::alter()
+                    .table(Image::Table)
+                    .drop_column(Image::Sharpness)
+                    .drop_column(Image::Brightness)
+                    .drop_column(Image::Contrast)
+                    .drop_column(Image::Noise)
+                    .to_owned()
+            )
+            .await
+    }
+}
+
+// The columns of the Image table used by this migration
+#[derive(Iden)]
+enum Image {
+    Table,
+    Sharpness,
+    Brightness,
+    Contrast,
+    Noise
+}
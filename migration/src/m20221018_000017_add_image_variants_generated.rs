@@ -0,0 +1,63 @@
+use sea_orm_migration::prelude::*;
+use sea_orm_migration::sea_orm::{ConnectionTrait, Statement};
+
+#[derive(DeriveMigrationName)]
+pub struct Migration;
+
+/// This migration adds the variants_generated column to the Image table, which records
+/// whether the variants of a stored image have been generated (see variants.rs). Images
+/// smaller than every variant size have no variants, so without it, `backfill-variants`
+/// would process them again on every run. Images that already have variants are marked.
+///
+/// ┌───────────────────────────┐
+/// │ Image                     │
+/// ├───────────────────────────┤
+/// │ ...                       │
+/// │ variants_generated (bool) │
+/// └───────────────────────────┘
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+    async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .alter_table(
+                Table::alter()
+                    .table(Image::Table)
+                    .add_column(
+                        ColumnDef::new(Image::VariantsGenerated)
+                            .boolean()
+                            .not_null()
+                            .default(false)
+                    )
+                    .to_owned()
+            )
+            .await?;
+        let db = manager.get_connection();
+        db.execute(Statement::from_string(
+            db.get_database_backend(),
+            "UPDATE image SET variants_generated = TRUE \
+             WHERE id IN (SELECT image_id FROM image_variant)"
+                .to_owned(),
+        ))
+        .await?;
+        Ok(())
+    }
+
+    // Drop the column, reverting the database to the previous migration
+    async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .alter_table(
+                Table::alter()
+                    .table(Image::Table)
+                    .drop_column(Image::VariantsGenerated)
+                    .to_owned()
+            )
+            .await
+    }
+}
+
+// The columns of the Image table used by this migration
+#[derive(Iden)]
+enum Image {
+    Table,
+    VariantsGenerated
+}
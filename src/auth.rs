@@ -0,0 +1,48 @@
+use async_trait::async_trait;
+use axum::{
+    body::Body,
+    extract::{FromRequest, RequestParts},
+    http::{header::AUTHORIZATION, StatusCode},
+    Extension,
+};
+
+use crate::config::AuthConfig;
+use crate::error::ServerError;
+
+/// Whether a request was made by one of our internal users, i.e. whether it has an
+/// `Authorization: Bearer <token>` header with the configured internal token.
+/// Internal users can see private data (e.g. where a photo was taken). Requests
+/// without a valid token aren't rejected; they are just treated as public.
+pub struct InternalUser(pub bool);
+
+#[async_trait]
+impl FromRequest<Body> for InternalUser {
+    type Rejection = ServerError;
+
+    async fn from_request(req: &mut RequestParts<Body>) -> Result<InternalUser, ServerError> {
+        // A missing extension is a bug on our side rather than the client's
+        let Extension(auth_config) = Extension::<AuthConfig>::from_request(req)
+            .await
+            .map_err(|rejection| ServerError::new(StatusCode::INTERNAL_SERVER_ERROR, rejection.to_string()))?;
+        let token = req
+            .headers()
+            .get(AUTHORIZATION)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.strip_prefix("Bearer "));
+        Ok(InternalUser(match (token, &auth_config.internal_token) {
+            (Some(token), Some(internal_token)) => tokens_match(token.trim(), internal_token),
+            _ => false,
+        }))
+    }
+}
+
+/// Compare the tokens in constant time, so that how long the comparison takes
+/// doesn't tell an attacker how much of the token they guessed right
+fn tokens_match(token: &str, internal_token: &str) -> bool {
+    token.len() == internal_token.len()
+        && token
+            .bytes()
+            .zip(internal_token.bytes())
+            .fold(0, |difference, (a, b)| difference | (a ^ b))
+            == 0
+}
@@ -0,0 +1,207 @@
+use std::f32::consts::PI;
+
+use entity::image;
+use entity::prelude::*;
+use ::image::DynamicImage;
+use photon_rs::PhotonImage;
+use sea_orm::{ActiveModelTrait, ColumnTrait, DatabaseConnection, EntityTrait, QueryFilter, Set};
+
+use crate::config::{BlurhashConfig, UploadLimits};
+use crate::error::ServerError;
+use crate::storage::Storage;
+use crate::upload_image::to_photon_image;
+use crate::validate_image::validate_image;
+
+// The characters BlurHash encodes numbers with (in base 83)
+static BASE83_CHARACTERS: &[u8; 83] =
+    b"0123456789ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz#$%*+,-.:;=?@[]^_{|}~";
+// BlurHashes are computed on the image shrunk to fit in this many pixels wide and high,
+// since they only describe its overall colors (and every component looks at every pixel)
+static BLURHASH_SIZE: u32 = 32;
+
+/// Compute the BlurHash of an image (see https://blurha.sh): a short string that
+/// clients can decode into a blurry placeholder while the image itself loads.
+/// The image is shrunk first, so this is cheap even for large images, but it should
+/// still be run on a blocking thread.
+pub fn image_blurhash(image: &DynamicImage, config: &BlurhashConfig) -> String {
+    let thumbnail = image.thumbnail(BLURHASH_SIZE, BLURHASH_SIZE);
+    encode_blurhash(&to_photon_image(&thumbnail), config.components_x, config.components_y)
+}
+
+/// Encode an image as a BlurHash with `components_x` by `components_y` components
+/// (each from 1 to 9), where more components give a more detailed placeholder.
+/// Each component is the image's average color weighted by a cosine wave, the first
+/// being the plain average color (the DC component) and the rest adding detail.
+fn encode_blurhash(image: &PhotonImage, components_x: u32, components_y: u32) -> String {
+    let (width, height) = (image.get_width() as usize, image.get_height() as usize);
+    let pixels = image.get_raw_pixels();
+    let linear: Vec<[f32; 3]> = pixels
+        .chunks_exact(4)
+        .map(|pixel| [srgb_to_linear(pixel[0]), srgb_to_linear(pixel[1]), srgb_to_linear(pixel[2])])
+        .collect();
+
+    let mut components = Vec::with_capacity((components_x * components_y) as usize);
+    for j in 0..components_y {
+        for i in 0..components_x {
+            let normalisation = if i == 0 && j == 0 { 1.0 } else { 2.0 };
+            let mut component = [0.0; 3];
+            for y in 0..height {
+                let basis_y = (PI * j as f32 * y as f32 / height as f32).cos();
+                for x in 0..width {
+                    let basis = (PI * i as f32 * x as f32 / width as f32).cos() * basis_y;
+                    for (sum, value) in component.iter_mut().zip(linear[y * width + x]) {
+                        *sum += basis * value;
+                    }
+                }
+            }
+            let scale = normalisation / (width * height) as f32;
+            components.push(component.map(|value| value * scale));
+        }
+    }
+
+    let mut hash = String::with_capacity(4 + 2 * components.len());
+    encode_base83(&mut hash, (components_x - 1) + (components_y - 1) * 9, 1);
+    let (dc, ac) = components.split_first().expect("BlurHashes have at least one component");
+    // The AC components are quantised relative to the largest of them
+    let maximum = if ac.is_empty() {
+        encode_base83(&mut hash, 0, 1);
+        1.0
+    } else {
+        let actual_maximum = ac.iter().flatten().fold(0.0_f32, |max, value| max.max(value.abs()));
+        let quantised_maximum = ((actual_maximum * 166.0 - 0.5).floor()).clamp(0.0, 82.0) as u32;
+        encode_base83(&mut hash, quantised_maximum, 1);
+        (quantised_maximum + 1) as f32 / 166.0
+    };
+    let [red, green, blue] = dc.map(linear_to_srgb);
+    encode_base83(&mut hash, (red << 16) + (green << 8) + blue, 4);
+    for component in ac {
+        let [red, green, blue] = component.map(|value| {
+            let value = value / maximum;
+            let quantised = (value.signum() * value.abs().sqrt() * 9.0 + 9.5).floor();
+            quantised.clamp(0.0, 18.0) as u32
+        });
+        encode_base83(&mut hash, red * 19 * 19 + green * 19 + blue, 2);
+    }
+    hash
+}
+
+/// Append a number to the hash as `length` base 83 digits
+fn encode_base83(hash: &mut String, value: u32, length: u32) {
+    for position in (0..length).rev() {
+        let digit = value / 83_u32.pow(position) % 83;
+        hash.push(BASE83_CHARACTERS[digit as usize] as char);
+    }
+}
+
+fn srgb_to_linear(value: u8) -> f32 {
+    let value = value as f32 / 255.0;
+    if value <= 0.04045 {
+        value / 12.92
+    } else {
+        ((value + 0.055) / 1.055).powf(2.4)
+    }
+}
+
+fn linear_to_srgb(value: f32) -> u32 {
+    let value = value.clamp(0.0, 1.0);
+    let srgb = if value <= 0.003_130_8 {
+        value * 12.92
+    } else {
+        1.055 * value.powf(1.0 / 2.4) - 0.055
+    };
+    (srgb * 255.0 + 0.5) as u32
+}
+
+/// Compute the BlurHash of every stored image that doesn't have one yet (e.g. images
+/// stored before BlurHashes were introduced). This is run with
+/// `cargo run -- backfill-blurhashes`. Like `backfill_variants`, files are decoded (and
+/// turned upright) like uploads, and images whose file can't be found or decoded within
+/// the upload limits are skipped, so that one bad image doesn't stop the backfill.
+pub async fn backfill_blurhashes(
+    db: &DatabaseConnection,
+    storage: &dyn Storage,
+    config: &BlurhashConfig,
+    limits: UploadLimits,
+) -> Result<(), ServerError> {
+    let images = Image::find()
+        .filter(image::Column::StorageKey.is_not_null())
+        .filter(image::Column::Blurhash.is_null())
+        .all(db)
+        .await?;
+    println!("Computing BlurHashes for {} images", images.len());
+
+    for image in images {
+        let storage_key = image.storage_key.clone().unwrap_or_default();
+        let bytes = match storage.get(&storage_key).await? {
+            Some(bytes) => bytes,
+            None => {
+                eprintln!("Skipping image {}: {storage_key} is missing from storage", image.id);
+                continue;
+            }
+        };
+        let config = *config;
+        let blurhash = tokio::task::spawn_blocking(move || {
+            validate_image(bytes, &limits).map(|decoded| image_blurhash(&decoded.image, &config))
+        })
+        .await?;
+        let blurhash = match blurhash {
+            Ok(blurhash) => blurhash,
+            Err(err) => {
+                eprintln!("Skipping image {}: unable to decode {storage_key}: {}", image.id, err.msg());
+                continue;
+            }
+        };
+        println!("Image {}: {blurhash}", image.id);
+        let active_model: image::ActiveModel = image.into();
+        image::ActiveModel {
+            blurhash: Set(Some(blurhash)),
+            ..active_model
+        }
+        .update(db)
+        .await?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use ::image::{Rgb, RgbImage};
+
+    use super::*;
+
+    fn solid(red: u8, green: u8, blue: u8) -> PhotonImage {
+        to_photon_image(&DynamicImage::ImageRgb8(RgbImage::from_pixel(8, 8, Rgb([red, green, blue]))))
+    }
+
+    #[test]
+    fn solid_images_only_have_their_average_color() {
+        assert_eq!(encode_blurhash(&solid(255, 255, 255), 1, 1), "00TSUA");
+        assert_eq!(encode_blurhash(&solid(0, 0, 0), 1, 1), "000000");
+    }
+
+    #[test]
+    fn hashes_have_two_characters_per_ac_component() {
+        let image = to_photon_image(&DynamicImage::ImageRgb8(RgbImage::from_fn(16, 16, |x, y| {
+            Rgb([(x * 16) as u8, (y * 16) as u8, 128])
+        })));
+        for (components_x, components_y) in [(1, 1), (4, 3), (9, 9)] {
+            let hash = encode_blurhash(&image, components_x, components_y);
+            assert_eq!(hash.len(), 4 + 2 * (components_x * components_y) as usize);
+            assert!(hash.bytes().all(|character| BASE83_CHARACTERS.contains(&character)));
+        }
+    }
+
+    #[test]
+    fn horizontal_detail_is_in_the_horizontal_components() {
+        let halves = |left: u8, right: u8| {
+            to_photon_image(&DynamicImage::ImageRgb8(RgbImage::from_fn(16, 16, |x, _| {
+                let shade = if x < 8 { left } else { right };
+                Rgb([shade, shade, shade])
+            })))
+        };
+        let (dark_left, dark_right) = (halves(0, 255), halves(255, 0));
+        assert_ne!(encode_blurhash(&dark_left, 2, 1), encode_blurhash(&dark_right, 2, 1));
+        // Each row has the same colors, so the vertical components can't tell them apart
+        assert_eq!(encode_blurhash(&dark_left, 1, 2), encode_blurhash(&dark_right, 1, 2));
+    }
+}
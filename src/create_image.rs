@@ -1,8 +1,16 @@
+use std::sync::Arc;
+
+use axum::http::StatusCode;
+use entity::category;
 use entity::image;
+use entity::image_category;
+use entity::image_crop;
 use entity::image_tag;
+use entity::image_variant;
 use entity::prelude::*;
 use entity::tag;
 use futures::future::join_all;
+use ::image::GenericImageView;
 use migration::DbErr;
 use sea_orm::ActiveModelTrait;
 use sea_orm::ColumnTrait;
@@ -10,32 +18,174 @@ use sea_orm::DatabaseConnection;
 use sea_orm::DatabaseTransaction;
 use sea_orm::EntityTrait;
 use sea_orm::QueryFilter;
+use sea_orm::QueryOrder;
 use sea_orm::TransactionTrait;
 use sea_orm::{ActiveValue::NotSet, Set};
 
+use crate::config::VariantConfig;
+use crate::crop_image::{crop_keys, save_crops, SavedCrop};
 use crate::error::ServerError;
-use crate::imagga_client::ImageInput;
-use crate::upload_image::upload;
+use crate::image_quality::ImageQuality;
+use crate::imagga_client::{ImageCategory as NewImageCategory, ImageCropping, ImageInput};
+use crate::mirror_image::RemoteFile;
+use crate::storage::Storage;
+use crate::upload_image::{file_key, mime_type, to_photon_image, upload};
+use crate::validate_image::UploadedImage;
+use crate::variants::{insert_variants, save_variants, variant_key, SavedVariant};
 
 type ImageId = i32;
+
+/// What the user told us about a new image
+pub struct ImageDetails {
+    // Generated from the tags if it isn't given
+    pub label: Option<String>,
+    // Where the image was taken as (latitude, longitude), which takes precedence
+    // over the location in the image's metadata
+    pub location: Option<(f64, f64)>,
+}
+
+/// Everything we found out about an image (e.g. from Imagga)
+/// before inserting it. Fields are left empty for any analysis
+/// that the user did not request.
+#[derive(Default)]
+pub struct ImageAnalysis {
+    pub tags: Vec<String>,
+    pub categories: Vec<NewImageCategory>,
+    // Suggested crops for uploaded images (see crop_image.rs)
+    pub croppings: Vec<ImageCropping>,
+    // The file of an image given by URL (see mirror_image.rs)
+    pub remote_file: Option<RemoteFile>,
+    // The placeholder for the image (see blurhash.rs)
+    pub blurhash: Option<String>,
+    // How usable the image looks (see image_quality.rs)
+    pub quality: Option<ImageQuality>,
+}
+
 /// A function that accesses the database and inserts an image.
-/// An image can be specified by a URL or by base64 encoding.
+/// An image can be specified by a URL or be uploaded (and already validated).
 /// A label can be provided; otherwise, it will be generated from
-/// the image's provided tags.
+/// the image's provided tags. Likewise, a location can be provided;
+/// otherwise, the location in an uploaded image's metadata (if any) is used.
 /// This function will also insert the tags into the database if
 /// they do not already exist and link them to the image via the 
-/// ImageTag junction table. A single database transaction is used
-/// such that any errors will cause all database mutations to be
+/// ImageTag junction table. Categories are handled the same way,
+/// except that their confidence is stored in the ImageCategory
+/// junction table. Uploaded images also get cropped versions and
+/// resized versions, which are recorded in the ImageCrop and
+/// ImageVariant tables. If the upload has the same content as
+/// `linked_image`, the new image shares its files instead.
+/// The files of a new upload are stored before the database transaction
+/// starts, and are deleted again if inserting the image fails, so that
+/// they aren't left behind without an image. A single database transaction
+/// is used such that any errors will cause all database mutations to be
 /// rolled back.
 pub async fn execute_insert_image(
     image_input: ImageInput,
-    tags: Vec<String>,
-    label: Option<String>,
-    db: &DatabaseConnection, // Here we use a DatabaseTransaction so if anything fails, the changes will all be rolled back
+    analysis: ImageAnalysis,
+    details: ImageDetails,
+    db: &DatabaseConnection, // insert_image starts the transaction on this connection
+    storage: &dyn Storage,
+    variant_config: &VariantConfig,
+    linked_image: Option<image::Model>,
+) -> Result<ImageId, ServerError> {
+    // Duplicates of an image we already have don't need their files stored
+    let new_upload = match (&image_input, &linked_image) {
+        (ImageInput::ImageUpload(uploaded_image), None) => Some(uploaded_image.clone()),
+        _ => None,
+    };
+    let saved_files = match &new_upload {
+        Some(uploaded_image) => {
+            match save_files(uploaded_image.clone(), &analysis.croppings, storage, variant_config).await {
+                Ok(saved_files) => Some(saved_files),
+                Err(err) => return Err(delete_files(err, uploaded_image, db, storage, variant_config).await),
+            }
+        }
+        None => None,
+    };
+    let inserted = insert_image(image_input, analysis, details, db, linked_image, saved_files).await;
+    match (inserted, new_upload) {
+        (Err(err), Some(uploaded_image)) => Err(delete_files(err, &uploaded_image, db, storage, variant_config).await),
+        (inserted, _) => inserted,
+    }
+}
+
+/// The files of an uploaded image that have been stored
+struct SavedFiles {
+    storage_key: String,
+    crops: Vec<SavedCrop>,
+    variants: Vec<SavedVariant>,
+}
+
+/// Store an uploaded image (in its original format), its crops and its variants,
+/// all named after the hash of its content
+async fn save_files(
+    uploaded_image: Arc<UploadedImage>,
+    croppings: &[ImageCropping],
+    storage: &dyn Storage,
+    variant_config: &VariantConfig,
+) -> Result<SavedFiles, ServerError> {
+    let content_hash = &uploaded_image.content_hash;
+    let decoded_image = {
+        let uploaded_image = uploaded_image.clone();
+        tokio::task::spawn_blocking(move || to_photon_image(&uploaded_image.image)).await?
+    };
+    let crops = save_crops(storage, &decoded_image, content_hash, uploaded_image.format, croppings).await?;
+    let variants = save_variants(storage, &decoded_image, content_hash, variant_config).await?;
+    let storage_key = upload(storage, &uploaded_image).await?;
+    Ok(SavedFiles {
+        storage_key,
+        crops,
+        variants,
+    })
+}
+
+/// Delete any files stored for an upload that couldn't be inserted, and return the
+/// error it failed with. Files are named after their content, so they are kept if
+/// an image with the same content was inserted in the meantime.
+async fn delete_files(
+    err: ServerError,
+    uploaded_image: &UploadedImage,
+    db: &DatabaseConnection,
+    storage: &dyn Storage,
+    variant_config: &VariantConfig,
+) -> ServerError {
+    let content_hash = &uploaded_image.content_hash;
+    match find_duplicate(content_hash, db).await {
+        Ok(None) => {}
+        Ok(Some(_)) => return err,
+        Err(find_err) => return err.with_context(&format!("Unable to clean up its files: {find_err}")),
+    }
+    let mut keys = vec![file_key(content_hash, uploaded_image.format)];
+    keys.extend(crop_keys(content_hash, uploaded_image.format));
+    keys.extend(variant_config.sizes.iter().map(|size| variant_key(content_hash, *size)));
+    for key in keys {
+        if let Err(delete_err) = storage.delete(&key).await {
+            return err.with_context(&format!("Unable to clean up its files: {}", delete_err.msg()));
+        }
+    }
+    err
+}
+
+/// Insert an image (and its tags, categories, crops and variants) into the database
+async fn insert_image(
+    image_input: ImageInput,
+    analysis: ImageAnalysis,
+    details: ImageDetails,
+    db: &DatabaseConnection,
+    linked_image: Option<image::Model>,
+    saved_files: Option<SavedFiles>,
 ) -> Result<ImageId, ServerError> {
     // Perform everything in a transaction
     // so that if something goes wrong, all the database changes get rolled back
     let txn = db.begin().await?;
+    let ImageAnalysis {
+        tags,
+        categories,
+        croppings: _,
+        remote_file,
+        blurhash,
+        quality,
+    } = analysis;
     // Get the list of tag IDs from the database
     // (creating new tags as needed)
     // 1. wait for all async queries to finish
@@ -48,14 +198,18 @@ pub async fn execute_insert_image(
     // We then use `?` to return an error if any of the queries failed.
     let tag_ids = tag_ids.into_iter().collect::<Result<Vec<_>, DbErr>>()?;
 
-    // Construct and insert the image metadata
-    let url = match &image_input {
-        ImageInput::ImageUrl(url) => url.to_owned(),
-        // If no URL is available, we give it a temporary one
-        // (since we need the ID in order to include the ID in the image name)
-        ImageInput::ImageBase64(_) => "temporary".to_owned(),
+    // Construct and insert the image metadata. Uploaded images don't have a source URL
+    // (unless they were mirrored); instead, their storage key (which is named after the
+    // hash of their content) and the details of their file are filled in below, along
+    // with their crops and variants
+    let source_url = match &image_input {
+        ImageInput::ImageUrl(url) => Some(url.to_owned()),
+        ImageInput::ImageUpload(uploaded_image) => uploaded_image.source_url.clone(),
     };
-    let new_image = create_image_model(url, &tags, label).insert(&txn).await?;
+    let location = details.location;
+    let new_image = create_image_model(source_url, &tags, details, remote_file, blurhash, quality)
+        .insert(&txn)
+        .await?;
     let image_id = new_image.id;
 
     // Now we pair the image with the associated tags
@@ -71,15 +225,84 @@ pub async fn execute_insert_image(
         ImageTag::insert_many(image_tags).exec(&txn).await?;
     }
 
-    // Now that we have an image id, we now use it in the filename of the uploaded
-    // image (if the image was specified by base64 encoding). Here we upload the image
-    // and then update the Image's URL in the database.
-    if let ImageInput::ImageBase64(image_base64) = image_input {
-        let new_image_url = upload(&image_base64, new_image.id);
+    // Same idea for the categories, except that here we also store
+    // the confidence Imagga gave for each category
+    let mut image_categories = Vec::with_capacity(categories.len());
+    for category in categories {
+        let category_id = get_category_id(&category, &txn).await?;
+        image_categories.push(image_category::ActiveModel {
+            image_id: Set(image_id),
+            category_id: Set(category_id),
+            confidence: Set(category.confidence),
+        });
+    }
+    if image_categories.len() > 0 {
+        ImageCategory::insert_many(image_categories).exec(&txn).await?;
+    }
+
+    // If the image was uploaded to us, we record where we stored the image and its
+    // crops and variants. Duplicates of an image we already have share its files.
+    if let ImageInput::ImageUpload(uploaded_image) = image_input {
+        let format = uploaded_image.format;
+        let content_hash = uploaded_image.content_hash.clone();
+        // Linked duplicates only have variants if the image they duplicate does
+        let (storage_key, variants_generated) = match (linked_image, saved_files) {
+            (Some(linked_image), _) => {
+                link_files(linked_image.id, image_id, &txn).await?;
+                (linked_image.storage_key.unwrap_or_default(), linked_image.variants_generated)
+            }
+            (None, None) => {
+                return Err(ServerError::new(
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    "The image's files have not been stored".to_owned(),
+                ))
+            }
+            (None, Some(SavedFiles { storage_key, crops, variants })) => {
+                let image_crops = crops
+                    .into_iter()
+                    .map(|crop| image_crop::ActiveModel {
+                        image_id: Set(image_id),
+                        name: Set(crop.name),
+                        storage_key: Set(crop.storage_key),
+                        width: Set(crop.width as i32),
+                        height: Set(crop.height as i32),
+                    })
+                    .collect::<Vec<_>>();
+                if image_crops.len() > 0 {
+                    ImageCrop::insert_many(image_crops).exec(&txn).await?;
+                }
+                insert_variants(image_id, variants, &txn).await?;
+                (storage_key, true)
+            }
+        };
 
+        let exif = uploaded_image.exif.clone();
+        let (latitude, longitude) = match location {
+            Some((latitude, longitude)) => (Some(latitude), Some(longitude)),
+            None => (
+                exif.as_ref().and_then(|exif| exif.latitude),
+                exif.as_ref().and_then(|exif| exif.longitude),
+            ),
+        };
         let active_model: image::ActiveModel = new_image.into();
         let updated_model = image::ActiveModel {
-            url: Set(new_image_url),
+            storage_key: Set(Some(storage_key)),
+            variants_generated: Set(variants_generated),
+            mime_type: Set(Some(mime_type(format).to_owned())),
+            width: Set(Some(uploaded_image.image.width() as i32)),
+            height: Set(Some(uploaded_image.image.height() as i32)),
+            byte_size: Set(Some(uploaded_image.bytes.len() as i64)),
+            content_hash: Set(Some(content_hash)),
+            perceptual_hash: Set(Some(uploaded_image.perceptual_hash)),
+            color_histogram: Set(Some(uploaded_image.color_histogram.clone().into())),
+            camera_make: Set(exif.as_ref().and_then(|exif| exif.camera_make.clone())),
+            camera_model: Set(exif.as_ref().and_then(|exif| exif.camera_model.clone())),
+            lens_model: Set(exif.as_ref().and_then(|exif| exif.lens_model.clone())),
+            taken_at: Set(exif.as_ref().and_then(|exif| exif.taken_at)),
+            orientation: Set(exif.as_ref().and_then(|exif| exif.orientation).map(|orientation| orientation as i32)),
+            exif: Set(exif.map(|exif| exif.fields)),
+            latitude: Set(latitude),
+            longitude: Set(longitude),
             ..active_model
         };
         updated_model.update(&txn).await?;
@@ -89,6 +312,56 @@ pub async fn execute_insert_image(
     Ok(image_id)
 }
 
+/// Find the first stored image whose file has the given SHA-256 hash, if any
+pub async fn find_duplicate(
+    content_hash: &str,
+    db: &DatabaseConnection,
+) -> Result<Option<image::Model>, DbErr> {
+    Image::find()
+        .filter(image::Column::ContentHash.eq(content_hash))
+        .filter(image::Column::StorageKey.is_not_null())
+        .order_by_asc(image::Column::Id)
+        .one(db)
+        .await
+}
+
+/// Give the image `image_id` the same crops and variants as `linked_image_id`.
+/// The files themselves are shared, so nothing is uploaded.
+async fn link_files(
+    linked_image_id: ImageId,
+    image_id: ImageId,
+    db: &DatabaseTransaction,
+) -> Result<(), DbErr> {
+    let image_crops = ImageCrop::find()
+        .filter(image_crop::Column::ImageId.eq(linked_image_id))
+        .all(db)
+        .await?
+        .into_iter()
+        .map(|crop| image_crop::ActiveModel {
+            image_id: Set(image_id),
+            ..crop.into()
+        })
+        .collect::<Vec<_>>();
+    if image_crops.len() > 0 {
+        ImageCrop::insert_many(image_crops).exec(db).await?;
+    }
+
+    let image_variants = ImageVariant::find()
+        .filter(image_variant::Column::ImageId.eq(linked_image_id))
+        .all(db)
+        .await?
+        .into_iter()
+        .map(|variant| image_variant::ActiveModel {
+            image_id: Set(image_id),
+            ..variant.into()
+        })
+        .collect::<Vec<_>>();
+    if image_variants.len() > 0 {
+        ImageVariant::insert_many(image_variants).exec(db).await?;
+    }
+    Ok(())
+}
+
 /// If a tag exists by name, return its id
 /// else insert a new tag and return its id
 async fn get_tag_id(name: String, db: &DatabaseTransaction) -> Result<i32, DbErr> {
@@ -112,6 +385,34 @@ async fn get_tag_id(name: String, db: &DatabaseTransaction) -> Result<i32, DbErr
     }
 }
 
+/// If a category exists for the given categorizer, return its id
+/// else insert a new category and return its id
+async fn get_category_id(
+    new_category: &NewImageCategory,
+    db: &DatabaseTransaction,
+) -> Result<i32, DbErr> {
+    let existing = Category::find()
+        .filter(category::Column::Name.eq(new_category.name.to_owned()))
+        .filter(category::Column::Categorizer.eq(new_category.categorizer.to_owned()))
+        .one(db)
+        .await?;
+
+    match existing {
+        Some(category) => Ok(category.id),
+        None => {
+            let inserted = category::ActiveModel {
+                id: NotSet,
+                name: Set(new_category.name.to_owned()),
+                categorizer: Set(new_category.categorizer.to_owned()),
+            }
+            .insert(db)
+            .await?;
+
+            Ok(inserted.id)
+        }
+    }
+}
+
 /// A small helper function to generate a label from a list of 
 /// tags by separating them with commas.
 fn generate_label(tags: &Vec<String>) -> String {
@@ -127,11 +428,14 @@ fn generate_label(tags: &Vec<String>) -> String {
 /// A small helper function to construct an Image ActiveModel, i.e.
 /// a model that can be inserted into the database
 fn create_image_model(
-    url: String,
+    source_url: Option<String>,
     tags: &Vec<String>,
-    label: Option<String>,
+    details: ImageDetails,
+    remote_file: Option<RemoteFile>,
+    blurhash: Option<String>,
+    quality: Option<ImageQuality>,
 ) -> image::ActiveModel {
-    let label = match label {
+    let label = match details.label {
         Some(label) => label,
         None => generate_label(&tags),
     };
@@ -139,6 +443,30 @@ fn create_image_model(
     image::ActiveModel {
         id: NotSet,
         label: Set(label),
-        url: Set(url),
+        source_url: Set(source_url),
+        // Only known once an uploaded image has been stored
+        storage_key: Set(None),
+        variants_generated: Set(false),
+        // For uploaded images, these are filled in along with the storage key
+        mime_type: Set(remote_file.as_ref().map(|file| mime_type(file.format).to_owned())),
+        width: Set(remote_file.as_ref().map(|file| file.width as i32)),
+        height: Set(remote_file.as_ref().map(|file| file.height as i32)),
+        byte_size: Set(remote_file.as_ref().map(|file| file.byte_size as i64)),
+        blurhash: Set(blurhash),
+        sharpness: Set(quality.as_ref().map(|quality| quality.sharpness)),
+        brightness: Set(quality.as_ref().map(|quality| quality.brightness)),
+        contrast: Set(quality.as_ref().map(|quality| quality.contrast)),
+        noise: Set(quality.as_ref().map(|quality| quality.noise)),
+        content_hash: Set(remote_file.as_ref().map(|file| file.content_hash.clone())),
+        perceptual_hash: Set(remote_file.as_ref().map(|file| file.perceptual_hash)),
+        color_histogram: Set(remote_file.as_ref().map(|file| file.color_histogram.clone().into())),
+        camera_make: Set(None),
+        camera_model: Set(None),
+        lens_model: Set(None),
+        taken_at: Set(None),
+        orientation: Set(None),
+        exif: Set(None),
+        latitude: Set(details.location.map(|(latitude, _)| latitude)),
+        longitude: Set(details.location.map(|(_, longitude)| longitude)),
     }
 }
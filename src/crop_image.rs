@@ -0,0 +1,206 @@
+use image::ImageFormat;
+use photon_rs::{transform::crop, PhotonImage};
+
+use crate::error::ServerError;
+use crate::imagga_client::ImageCropping;
+use crate::storage::Storage;
+use crate::upload_image::{encode_in_format, file_extension, mime_type, to_dynamic_image};
+
+/// A shape of crop that we generate for every uploaded image.
+/// The aspect ratio is given as width:height (e.g. 16:9).
+pub struct CropShape {
+    pub name: &'static str,
+    pub aspect_width: u32,
+    pub aspect_height: u32,
+}
+
+/// The crops we generate for every uploaded image: a square one
+/// (e.g. for avatars and grids) and a 16:9 one (e.g. for banners)
+pub static CROP_SHAPES: [CropShape; 2] = [
+    CropShape {
+        name: "square",
+        aspect_width: 1,
+        aspect_height: 1,
+    },
+    CropShape {
+        name: "wide",
+        aspect_width: 16,
+        aspect_height: 9,
+    },
+];
+
+/// The resolutions we ask Imagga to suggest crops for. Only the
+/// aspect ratio matters, since we crop from the full-size image.
+pub fn crop_resolutions() -> Vec<(u32, u32)> {
+    CROP_SHAPES
+        .iter()
+        .map(|shape| (shape.aspect_width * 100, shape.aspect_height * 100))
+        .collect()
+}
+
+/// A crop that has been uploaded to storage
+pub struct SavedCrop {
+    pub name: String,
+    pub storage_key: String,
+    pub width: u32,
+    pub height: u32,
+}
+
+/// A crop that has been cropped and encoded, but not uploaded yet
+struct EncodedCrop {
+    name: &'static str,
+    bytes: Vec<u8>,
+    width: u32,
+    height: u32,
+}
+
+/// Generate and upload one crop of the image for each of the CROP_SHAPES, in the
+/// format of the original image (e.g. `<hash>_square.jpg`).
+/// The crops suggested by Imagga are used when available (and valid for
+/// this image); otherwise we fall back to cropping around the center.
+/// Cropping and encoding are CPU-intensive, so they are done on a blocking thread.
+pub async fn save_crops(
+    storage: &dyn Storage,
+    image: &PhotonImage,
+    name: &str,
+    format: ImageFormat,
+    suggestions: &[ImageCropping],
+) -> Result<Vec<SavedCrop>, ServerError> {
+    let image = image.clone();
+    let suggestions = suggestions.to_vec();
+    let encoded = tokio::task::spawn_blocking(move || encode_crops(&image, format, &suggestions)).await??;
+
+    let mut crops = Vec::with_capacity(encoded.len());
+    for crop in encoded {
+        let storage_key = crop_key(name, crop.name, format);
+        storage.put(&storage_key, crop.bytes, mime_type(format)).await?;
+        crops.push(SavedCrop {
+            name: crop.name.to_owned(),
+            storage_key,
+            width: crop.width,
+            height: crop.height,
+        });
+    }
+    Ok(crops)
+}
+
+/// Crop the image to each of the CROP_SHAPES and encode the results in the given format
+fn encode_crops(
+    image: &PhotonImage,
+    format: ImageFormat,
+    suggestions: &[ImageCropping],
+) -> Result<Vec<EncodedCrop>, ServerError> {
+    let (width, height) = (image.get_width(), image.get_height());
+    // photon's crop takes a mutable image, although it doesn't change it
+    let mut image = image.clone();
+    CROP_SHAPES
+        .iter()
+        .map(|shape| {
+            let (x1, y1, x2, y2) = suggestions
+                .iter()
+                .find(|suggestion| matches_shape(suggestion, shape) && fits_image(suggestion, width, height))
+                .map(|suggestion| (suggestion.x1, suggestion.y1, suggestion.x2, suggestion.y2))
+                .unwrap_or_else(|| center_crop(width, height, shape));
+            let cropped = crop(&mut image, x1, y1, x2, y2);
+            let bytes = encode_in_format(&to_dynamic_image(&cropped)?, format)?;
+            Ok(EncodedCrop {
+                name: shape.name,
+                bytes,
+                width: x2 - x1,
+                height: y2 - y1,
+            })
+        })
+        .collect()
+}
+
+/// The storage key of a crop of an image, which is stored next to the original
+/// image and named after it and the crop (e.g. `<hash>_square.jpg`)
+pub fn crop_key(name: &str, crop_name: &str, format: ImageFormat) -> String {
+    format!("{name}_{crop_name}.{}", file_extension(format))
+}
+
+/// The storage keys of all the crops of an image (whether or not they have been
+/// uploaded), e.g. for cleaning up after a failed upload
+pub fn crop_keys(name: &str, format: ImageFormat) -> Vec<String> {
+    CROP_SHAPES
+        .iter()
+        .map(|shape| crop_key(name, shape.name, format))
+        .collect()
+}
+
+/// Whether Imagga's suggestion was made for the given shape, i.e. whether
+/// its target resolution has the same aspect ratio as the shape
+fn matches_shape(suggestion: &ImageCropping, shape: &CropShape) -> bool {
+    suggestion.target_width * shape.aspect_height == suggestion.target_height * shape.aspect_width
+}
+
+/// Whether a suggested crop is non-empty and lies within the image.
+/// We check this so that a bad suggestion can't make us crop out of bounds.
+fn fits_image(suggestion: &ImageCropping, width: u32, height: u32) -> bool {
+    suggestion.x1 < suggestion.x2
+        && suggestion.y1 < suggestion.y2
+        && suggestion.x2 <= width
+        && suggestion.y2 <= height
+}
+
+/// Compute the largest crop with the shape's aspect ratio that fits in the
+/// image, centered in the image. Returns (x1, y1, x2, y2).
+fn center_crop(width: u32, height: u32, shape: &CropShape) -> (u32, u32, u32, u32) {
+    // Use u64 so that large images can't overflow the multiplications
+    let (width, height) = (width as u64, height as u64);
+    let (aspect_width, aspect_height) = (shape.aspect_width as u64, shape.aspect_height as u64);
+    let (crop_width, crop_height) = if width * aspect_height > height * aspect_width {
+        // The image is wider than the shape, so we use the full height
+        (height * aspect_width / aspect_height, height)
+    } else {
+        // The image is taller than the shape, so we use the full width
+        (width, width * aspect_height / aspect_width)
+    };
+    // Never produce an empty crop for tiny images
+    let (crop_width, crop_height) = (crop_width.max(1), crop_height.max(1));
+    let x1 = (width - crop_width) / 2;
+    let y1 = (height - crop_height) / 2;
+    (
+        x1 as u32,
+        y1 as u32,
+        (x1 + crop_width) as u32,
+        (y1 + crop_height) as u32,
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn wide_images_are_cropped_to_their_full_height() {
+        // A 1600x900 crop of a 2000x900 image is already 16:9
+        assert_eq!(center_crop(2000, 900, &CROP_SHAPES[1]), (200, 0, 1800, 900));
+        assert_eq!(center_crop(2000, 900, &CROP_SHAPES[0]), (550, 0, 1450, 900));
+    }
+
+    #[test]
+    fn tall_images_are_cropped_to_their_full_width() {
+        assert_eq!(center_crop(900, 2000, &CROP_SHAPES[0]), (0, 550, 900, 1450));
+        assert_eq!(center_crop(1600, 1600, &CROP_SHAPES[1]), (0, 350, 1600, 1250));
+    }
+
+    #[test]
+    fn images_with_the_shape_are_not_cropped() {
+        assert_eq!(center_crop(500, 500, &CROP_SHAPES[0]), (0, 0, 500, 500));
+        assert_eq!(center_crop(1920, 1080, &CROP_SHAPES[1]), (0, 0, 1920, 1080));
+    }
+
+    #[test]
+    fn crops_of_tiny_images_are_never_empty() {
+        assert_eq!(center_crop(1, 1, &CROP_SHAPES[1]), (0, 0, 1, 1));
+        assert_eq!(center_crop(3, 1, &CROP_SHAPES[0]), (1, 0, 2, 1));
+    }
+
+    #[test]
+    fn huge_images_do_not_overflow() {
+        let (x1, y1, x2, y2) = center_crop(u32::MAX, 100_000, &CROP_SHAPES[1]);
+        assert_eq!((y1, y2), (0, 100_000));
+        assert_eq!(x2 - x1, 177_777);
+    }
+}
@@ -3,7 +3,9 @@ use std::io::Error;
 
 use axum::{http::StatusCode, response::IntoResponse};
 use migration::DbErr;
+use s3::error::S3Error;
 use sea_orm::TransactionError;
+use tokio::task::JoinError;
 
 /// The main error type we use. This error type lets us easily specify a status
 /// code (e.g. 400, 500, etc) as well as an additional string message.
@@ -30,6 +32,18 @@ impl ServerError {
     pub fn new(code: StatusCode, msg: String)  -> ServerError {
         ServerError { code, msg }
     }
+
+    /// The message that will be sent to the client (e.g. for reporting
+    /// errors that happen outside of a request)
+    pub fn msg(&self) -> &str {
+        &self.msg
+    }
+
+    /// Add something else that went wrong while handling this error (e.g.
+    /// while cleaning up after it) to its message, keeping its status code
+    pub fn with_context(self, context: &str) -> ServerError {
+        ServerError::new(self.code, format!("{} ({context})", self.msg))
+    }
 }
 
 // Database errors should automatically be converted to HTTP 500 Internal Server Errors.
@@ -75,4 +89,22 @@ impl From<ureq::Error> for ServerError {
         ServerError::new(StatusCode::INTERNAL_SERVER_ERROR, 
         format!("Error while making request: ureq: {err}"))
     }
-}
\ No newline at end of file
+}
+
+/// Used when work we moved to a separate thread (e.g. CPU-intensive
+/// image processing) panics or is cancelled.
+impl From<JoinError> for ServerError {
+    fn from(err: JoinError) -> Self {
+        ServerError::new(StatusCode::INTERNAL_SERVER_ERROR,
+        format!("Error while running task: {err}"))
+    }
+}
+
+/// Used when we can't reach our S3-compatible storage at all, which
+/// (like a database error) is not the user's fault.
+impl From<S3Error> for ServerError {
+    fn from(err: S3Error) -> Self {
+        ServerError::new(StatusCode::INTERNAL_SERVER_ERROR,
+        format!("Storage error: {err}"))
+    }
+}
@@ -0,0 +1,101 @@
+use std::io::Cursor;
+
+use chrono::NaiveDate;
+use exif::{Exif, In, Reader, Tag, Value};
+use sea_orm::prelude::DateTime;
+use serde_json::{Map, Value as JsonValue};
+
+/// The EXIF metadata of an image, as read from its original file (e.g. which camera
+/// took a photo, and when). The fields we filter by are pulled out, and every field
+/// is also kept in `fields` (by tag name, e.g. `ExposureTime`) for clients to inspect.
+#[derive(Clone)]
+pub struct ExifMetadata {
+    pub camera_make: Option<String>,
+    pub camera_model: Option<String>,
+    pub lens_model: Option<String>,
+    // When the photo was taken, in the camera's local time
+    pub taken_at: Option<DateTime>,
+    // How the image should be rotated or flipped to be upright (1 to 8, where 1 means as-is)
+    pub orientation: Option<u32>,
+    // Where the photo was taken, in degrees (negative for south and west)
+    pub latitude: Option<f64>,
+    pub longitude: Option<f64>,
+    pub fields: JsonValue,
+}
+
+/// Read the EXIF metadata of an image file (JPEG, TIFF, PNG or WebP), or None if
+/// it has none. Unreadable metadata is treated the same as missing metadata, since
+/// it shouldn't stop the image from being stored.
+pub fn read_exif(bytes: &[u8]) -> Option<ExifMetadata> {
+    let exif = Reader::new()
+        .read_from_container(&mut Cursor::new(bytes))
+        .ok()?;
+    // The fields of the embedded thumbnail (if any) describe the thumbnail, not the image
+    let fields: Map<String, JsonValue> = exif
+        .fields()
+        .filter(|field| field.ifd_num == In::PRIMARY)
+        .map(|field| {
+            let value = field.display_value().with_unit(&exif).to_string();
+            (field.tag.to_string(), JsonValue::String(value))
+        })
+        .collect();
+    Some(ExifMetadata {
+        camera_make: ascii_field(&exif, Tag::Make),
+        camera_model: ascii_field(&exif, Tag::Model),
+        lens_model: ascii_field(&exif, Tag::LensModel),
+        taken_at: date_field(&exif, Tag::DateTimeOriginal).or_else(|| date_field(&exif, Tag::DateTime)),
+        orientation: exif
+            .get_field(Tag::Orientation, In::PRIMARY)
+            .and_then(|field| field.value.get_uint(0))
+            .filter(|orientation| (1..=8).contains(orientation)),
+        latitude: coordinate_field(&exif, Tag::GPSLatitude, Tag::GPSLatitudeRef, "S", 90.0),
+        longitude: coordinate_field(&exif, Tag::GPSLongitude, Tag::GPSLongitudeRef, "W", 180.0),
+        fields: JsonValue::Object(fields),
+    })
+}
+
+/// A text field, without the padding that some cameras add (or None if it is empty)
+fn ascii_field(exif: &Exif, tag: Tag) -> Option<String> {
+    let field = exif.get_field(tag, In::PRIMARY)?;
+    match &field.value {
+        Value::Ascii(values) => {
+            let text = String::from_utf8_lossy(values.first()?);
+            let text = text.trim_matches(|c: char| c == '\0' || c.is_whitespace());
+            (!text.is_empty()).then(|| text.to_owned())
+        }
+        _ => None,
+    }
+}
+
+/// A GPS coordinate in degrees, from a field with the degrees, minutes and seconds
+/// and a field saying which hemisphere it is in (`negative_ref`, e.g. `S`, makes it negative).
+/// Gives None if either field is missing or the coordinate is out of range.
+fn coordinate_field(exif: &Exif, tag: Tag, ref_tag: Tag, negative_ref: &str, max: f64) -> Option<f64> {
+    let degrees = match &exif.get_field(tag, In::PRIMARY)?.value {
+        Value::Rational(parts) if parts.len() == 3 => {
+            parts[0].to_f64() + parts[1].to_f64() / 60.0 + parts[2].to_f64() / 3600.0
+        }
+        _ => return None,
+    };
+    let degrees = if ascii_field(exif, ref_tag)?.eq_ignore_ascii_case(negative_ref) {
+        -degrees
+    } else {
+        degrees
+    };
+    (degrees.is_finite() && degrees.abs() <= max).then_some(degrees)
+}
+
+/// A date field (e.g. `2022:10:18 07:28:00`), or None if it is missing or invalid
+/// (some cameras write `0000:00:00 00:00:00` when their clock isn't set)
+fn date_field(exif: &Exif, tag: Tag) -> Option<DateTime> {
+    let field = exif.get_field(tag, In::PRIMARY)?;
+    let date = match &field.value {
+        Value::Ascii(values) => exif::DateTime::from_ascii(values.first()?).ok()?,
+        _ => return None,
+    };
+    NaiveDate::from_ymd_opt(date.year as i32, date.month as u32, date.day as u32)?.and_hms_opt(
+        date.hour as u32,
+        date.minute as u32,
+        date.second as u32,
+    )
+}
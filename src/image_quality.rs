@@ -0,0 +1,180 @@
+use image::{imageops::FilterType, DynamicImage, GenericImageView};
+use photon_rs::PhotonImage;
+
+use crate::config::QualityConfig;
+use crate::upload_image::to_photon_image;
+
+// Quality is measured on the image shrunk to fit in this many pixels wide and high,
+// so that sharpness and noise mean the same thing for large and small images
+static QUALITY_SIZE: u32 = 512;
+
+/// How usable a photo looks, measured from its pixels' luminance (0 to 255)
+pub struct ImageQuality {
+    // The variance of the Laplacian: low for blurry images, whose edges are soft
+    pub sharpness: f32,
+    // The mean luminance, from 0 (black) to 1 (white)
+    pub brightness: f32,
+    // The standard deviation of the luminance, from 0 (a single shade) to 0.5
+    pub contrast: f32,
+    // The estimated standard deviation of the noise, in luminance levels
+    pub noise: f32,
+}
+
+/// Measure the quality of an image (see ImageQuality).
+/// Large images are shrunk first (see QUALITY_SIZE), which still takes a while, so
+/// `post_image` measures them on a blocking thread along with their BlurHash.
+pub fn measure_quality(image: &DynamicImage) -> ImageQuality {
+    let image = if image.width().max(image.height()) > QUALITY_SIZE {
+        image.resize(QUALITY_SIZE, QUALITY_SIZE, FilterType::Triangle)
+    } else {
+        image.clone()
+    };
+    let image = to_photon_image(&image);
+    let (width, height) = (image.get_width() as usize, image.get_height() as usize);
+    let luminance = luminance(&image);
+
+    let mean = luminance.iter().sum::<f32>() / luminance.len() as f32;
+    let variance = luminance.iter().map(|value| (value - mean).powi(2)).sum::<f32>() / luminance.len() as f32;
+
+    // Both kernels need a pixel on every side, so images under 3x3 pixels (which
+    // aren't usable photos anyway) are treated as having no detail at all
+    let (mut laplacians, mut noise_sum) = (vec![], 0.0);
+    for y in 1..height.saturating_sub(1) {
+        for x in 1..width.saturating_sub(1) {
+            let at = |dx: isize, dy: isize| {
+                luminance[(y as isize + dy) as usize * width + (x as isize + dx) as usize]
+            };
+            let edges = at(0, -1) + at(-1, 0) + at(1, 0) + at(0, 1);
+            let corners = at(-1, -1) + at(1, -1) + at(-1, 1) + at(1, 1);
+            // [0 1 0; 1 -4 1; 0 1 0]
+            laplacians.push(edges - 4.0 * at(0, 0));
+            // [1 -2 1; -2 4 -2; 1 -2 1], which cancels out edges and leaves the noise
+            noise_sum += (corners - 2.0 * edges + 4.0 * at(0, 0)).abs();
+        }
+    }
+    let (sharpness, noise) = if laplacians.is_empty() {
+        (0.0, 0.0)
+    } else {
+        let count = laplacians.len() as f32;
+        let laplacian_mean = laplacians.iter().sum::<f32>() / count;
+        let sharpness = laplacians
+            .iter()
+            .map(|value| (value - laplacian_mean).powi(2))
+            .sum::<f32>()
+            / count;
+        // Immerkær's fast noise variance estimation
+        let noise = (std::f32::consts::PI / 2.0).sqrt() * noise_sum / (6.0 * count);
+        (sharpness, noise)
+    };
+
+    ImageQuality {
+        sharpness,
+        brightness: mean / 255.0,
+        contrast: variance.sqrt() / 255.0,
+        noise,
+    }
+}
+
+/// The luminance (Rec. 601) of each pixel in a photon image, from 0 to 255
+fn luminance(image: &PhotonImage) -> Vec<f32> {
+    image
+        .get_raw_pixels()
+        .chunks_exact(4)
+        .map(|pixel| 0.299 * pixel[0] as f32 + 0.587 * pixel[1] as f32 + 0.114 * pixel[2] as f32)
+        .collect()
+}
+
+/// The reasons an image counts as low quality according to the configured
+/// thresholds (e.g. `blurry`), or none if it is fine
+pub fn quality_issues(quality: &ImageQuality, config: &QualityConfig) -> Vec<&'static str> {
+    let mut issues = vec![];
+    if quality.sharpness < config.min_sharpness {
+        issues.push("blurry");
+    }
+    if quality.brightness < config.min_brightness {
+        issues.push("too_dark");
+    }
+    if quality.brightness > config.max_brightness {
+        issues.push("too_bright");
+    }
+    if quality.contrast < config.min_contrast {
+        issues.push("low_contrast");
+    }
+    if quality.noise > config.max_noise {
+        issues.push("noisy");
+    }
+    issues
+}
+
+#[cfg(test)]
+mod tests {
+    use image::{GrayImage, Luma};
+
+    use super::*;
+
+    fn gray(width: u32, height: u32, shade: impl Fn(u32, u32) -> u8) -> DynamicImage {
+        DynamicImage::ImageLuma8(GrayImage::from_fn(width, height, |x, y| Luma([shade(x, y)])))
+    }
+
+    #[test]
+    fn flat_images_have_no_detail() {
+        let quality = measure_quality(&gray(64, 64, |_, _| 51));
+        assert_eq!(quality.sharpness, 0.0);
+        assert_eq!(quality.contrast, 0.0);
+        assert_eq!(quality.noise, 0.0);
+        assert!((quality.brightness - 0.2).abs() < 0.01);
+    }
+
+    #[test]
+    fn sharp_images_are_sharper_than_blurry_ones() {
+        let checkerboard = gray(64, 64, |x, y| if (x / 4 + y / 4) % 2 == 0 { 0 } else { 255 });
+        let blurred = checkerboard.blur(4.0);
+        let (sharp, blurry) = (measure_quality(&checkerboard), measure_quality(&blurred));
+        assert!(sharp.sharpness > 10.0 * blurry.sharpness);
+        assert!((sharp.contrast - 0.5).abs() < 0.01);
+    }
+
+    #[test]
+    fn noise_is_measured_but_smooth_gradients_are_not_noise() {
+        let gradient = measure_quality(&gray(64, 64, |x, y| (x + y) as u8));
+        assert!(gradient.noise < 0.01);
+        // Neighbouring pixels that are 16 levels apart, which is as noisy as it gets
+        let noisy = measure_quality(&gray(64, 64, |x, y| 120 + 16 * ((x + y) % 2) as u8));
+        assert!(noisy.noise > 20.0);
+    }
+
+    #[test]
+    fn large_images_are_measured_at_the_same_scale() {
+        let quality = measure_quality(&gray(2048, 1024, |x, _| if x < 1024 { 0 } else { 255 }));
+        assert!((quality.brightness - 0.5).abs() < 0.01);
+    }
+
+    #[test]
+    fn tiny_images_have_no_detail() {
+        let quality = measure_quality(&gray(2, 2, |x, _| if x == 0 { 0 } else { 255 }));
+        assert_eq!(quality.sharpness, 0.0);
+        assert_eq!(quality.noise, 0.0);
+    }
+
+    #[test]
+    fn issues_follow_the_thresholds() {
+        let config = QualityConfig::default();
+        let quality = ImageQuality {
+            sharpness: 5.0,
+            brightness: 0.05,
+            contrast: 0.01,
+            noise: 20.0,
+        };
+        assert_eq!(
+            quality_issues(&quality, &config),
+            vec!["blurry", "too_dark", "low_contrast", "noisy"]
+        );
+        let quality = ImageQuality {
+            sharpness: 500.0,
+            brightness: 0.5,
+            contrast: 0.2,
+            noise: 2.0,
+        };
+        assert!(quality_issues(&quality, &config).is_empty());
+    }
+}
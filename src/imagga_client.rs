@@ -1,66 +1,289 @@
 use std::env::var;
+use std::sync::Arc;
+use std::time::Instant;
 
 use axum::http::StatusCode;
-use serde::{Deserialize, Serialize};
-use ureq::{get, post, Error};
+use serde::{de::{DeserializeOwned, IgnoredAny}, Deserialize, Serialize};
+use ureq::{delete, get, post, Error, Response};
 
 use crate::error::ServerError;
+use crate::tagger_usage::TaggerCall;
+use crate::validate_image::UploadedImage;
 
 /// Using the api key and secret found in environmental variables, construct the authorization.
 /// This authorization should be sent in the "Authorization header"
 /// We're using basic authentication, i.e. where the username and password are joined with a colon
 /// and then base64-encoded.
 /// Here the "username" is the API key and the "password" is the API secret.
-/// This function will panic if the environment variables are missing because our Imagga authorization
-/// is needed to make requests to Imagga, and it's better to correct the issue of these missing 
-/// environmental variables on startup than wait until a user makes a POST request (when we may
-/// no longer be monitoring the server).
-pub fn get_imagga_authorization() -> String {
+/// If Imagga is `required` (i.e. we aren't tagging images locally), this function will panic if
+/// the environment variables are missing because our Imagga authorization is needed to make requests
+/// to Imagga, and it's better to correct the issue of these missing environmental variables on startup
+/// than wait until a user makes a POST request (when we may no longer be monitoring the server).
+/// Otherwise, None is returned and the features that need Imagga are unavailable.
+pub fn get_imagga_authorization(required: bool) -> Option<String> {
     match (var("IMAGGA_API_KEY"), var("IMAGGA_API_SECRET")) {
         (Ok(key), Ok(secret)) => {
             let auth = base64::encode::<String>(format!("{key}:{secret}"));
-            format!("Basic {auth}")
+            Some(format!("Basic {auth}"))
         }
+        (_, _) if !required => None,
         (_, _) => {
             panic!("Missing API key/secret")
         }
     }
 }
 
-/// The structure of the body of the POST request we send to Imagga
-/// (when a POST request is used instead of a GET request)
-/// This will be x-www-form-urlencoded.
-#[derive(Serialize)]
-struct ImaggaPostRequest {
-    pub image_base64: String,
-}
-
 /// This enum allows the user of this Imagga client (i.e. our webserver)
-/// to specify either an image URL xor an image's base64-encoded data
+/// to specify either an image URL xor an image that was uploaded to us
+/// (which has already been validated, see validate_image.rs)
 #[derive(Clone)]
 pub enum ImageInput {
     ImageUrl(String),
-    ImageBase64(String),
+    ImageUpload(Arc<UploadedImage>),
+}
+
+/// How we refer to an image when making requests to Imagga: either by its URL,
+/// or by the id Imagga gave it when we uploaded it (see `with_imagga_image`).
+pub enum ImaggaImage {
+    Url(String),
+    UploadId(String),
+}
+
+/// Run `analyze` (which may make any number of requests to Imagga) on the given image.
+/// Uploaded images are also uploaded to Imagga once beforehand so that each request only
+/// needs to send the upload id rather than the whole image. The upload is deleted from
+/// Imagga afterwards, even if `analyze` fails. Every request made is recorded in `calls`.
+pub fn with_imagga_image<T>(
+    image_input: &ImageInput,
+    imagga_authorization: &str,
+    calls: &mut Vec<TaggerCall>,
+    analyze: impl FnOnce(&ImaggaImage, &mut Vec<TaggerCall>) -> Result<T, ServerError>,
+) -> Result<T, ServerError> {
+    match image_input {
+        ImageInput::ImageUrl(image_url) => analyze(&ImaggaImage::Url(image_url.to_owned()), calls),
+        ImageInput::ImageUpload(uploaded_image) => {
+            let image_base64 = base64::encode(&uploaded_image.bytes);
+            let upload_id = upload_image_to_imagga(&image_base64, imagga_authorization, calls)?;
+            let result = analyze(&ImaggaImage::UploadId(upload_id.clone()), calls);
+            // Failing to clean up isn't the user's problem (Imagga deletes uploads
+            // after 24 hours anyway), so it doesn't fail the request. The failed
+            // request is still recorded in `calls`, like every other request.
+            delete_imagga_upload(&upload_id, imagga_authorization, calls).ok();
+            result
+        }
+    }
 }
-/// Given an image (URL or base64-encoded data), use our Imagga authorization to ask
+
+/// Upload a base64-encoded image to Imagga and return the upload id, which
+/// can be used in place of an image URL in subsequent requests.
+fn upload_image_to_imagga(
+    image_base64: &str,
+    imagga_authorization: &str,
+    calls: &mut Vec<TaggerCall>,
+) -> Result<String, ServerError> {
+    // The body is x-www-form-urlencoded
+    let response = timed_call("POST /v2/uploads", calls, || {
+        post("https://api.imagga.com/v2/uploads")
+            .set("Authorization", imagga_authorization)
+            .send_form(&[("image_base64", image_base64)])
+    });
+    let result = parse_imagga_response::<ImaggaUploadResult>(response)?;
+    Ok(result.upload_id)
+}
+
+/// Delete an image we previously uploaded to Imagga
+fn delete_imagga_upload(
+    upload_id: &str,
+    imagga_authorization: &str,
+    calls: &mut Vec<TaggerCall>,
+) -> Result<(), ServerError> {
+    let response = timed_call("DELETE /v2/uploads", calls, || {
+        delete(&format!("https://api.imagga.com/v2/uploads/{upload_id}"))
+            .set("Authorization", imagga_authorization)
+            .call()
+    });
+    parse_imagga_response::<IgnoredAny>(response)?;
+    Ok(())
+}
+
+/// Given an image, use our Imagga authorization to ask
 /// Imagga to detect the objects in the image. Can return a 400-class ServerError (e.g.
 /// if provided a URL that points to nothing) or a 500-class ServerError (e.g. the client
 /// fails to deserialize a message).
-pub fn get_tags_for_image(image_input: ImageInput, imagga_authorization: String) -> Result<Vec<String>, ServerError> {
-    // Send the request to Imagga (pattern matching based on the type of input)
-    // and store the result (which could have been a success or a failure)
-    let response = match image_input {
-        ImageInput::ImageUrl(image_url) => get("https://api.imagga.com/v2/tags")
-            .set("Authorization", &imagga_authorization)
-            .query("image_url", &image_url)
-            .call(),
-        ImageInput::ImageBase64(image_base64) => {
-            post("https://api.imagga.com/v2/tags")
-                .set("Authorization", &imagga_authorization)
-                .send_form(&[("image_base64", &image_base64)])
-        }
+pub fn get_tags_for_image(
+    imagga_image: &ImaggaImage,
+    imagga_authorization: &str,
+    calls: &mut Vec<TaggerCall>,
+) -> Result<Vec<String>, ServerError> {
+    let response = send_imagga_request("/v2/tags", imagga_image, &[], imagga_authorization, calls);
+    let result = parse_imagga_response::<ImaggaTaggingResult>(response)?;
+    // If all goes well, we convert the deserialized response into a list of Strings
+    Ok(map_result_to_tags(result))
+}
+
+/// Given an image, ask Imagga to classify the image using
+/// the categorizer with the given id (e.g. `personal_photos`). Unlike tags, categories
+/// describe the scene as a whole and keep their confidence values. Errors are handled
+/// the same way as in `get_tags_for_image`.
+pub fn get_categories_for_image(
+    imagga_image: &ImaggaImage,
+    categorizer_id: &str,
+    imagga_authorization: &str,
+    calls: &mut Vec<TaggerCall>,
+) -> Result<Vec<ImageCategory>, ServerError> {
+    // Categorizer ids are interpolated into the URL, so only allow the characters
+    // Imagga actually uses in them (e.g. `general_v3`)
+    if categorizer_id.is_empty()
+        || !categorizer_id.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+    {
+        return Err(ServerError::new(
+            StatusCode::BAD_REQUEST,
+            format!("Invalid categorizer id: {categorizer_id:?}"),
+        ));
+    }
+    let endpoint = format!("/v2/categories/{categorizer_id}");
+    let response = send_imagga_request(&endpoint, imagga_image, &[], imagga_authorization, calls);
+    let result = parse_imagga_response::<ImaggaCategoriesResult>(response)?;
+    Ok(result
+        .categories
+        .into_iter()
+        .map(|category| ImageCategory {
+            name: normalize_category_name(&category.translations.english),
+            categorizer: categorizer_id.to_owned(),
+            confidence: category.confidence,
+        })
+        .collect())
+}
+
+/// Given an image, ask Imagga for the best crop of the
+/// image for each of the provided resolutions (width, height). Imagga picks crops that
+/// keep the subject of the image in frame. The returned croppings are in the coordinates
+/// of the original image.
+pub fn get_croppings_for_image(
+    imagga_image: &ImaggaImage,
+    resolutions: &[(u32, u32)],
+    imagga_authorization: &str,
+    calls: &mut Vec<TaggerCall>,
+) -> Result<Vec<ImageCropping>, ServerError> {
+    // Imagga expects the resolutions as a comma-separated list, e.g. "100x100,160x90"
+    let resolutions = resolutions
+        .iter()
+        .map(|(width, height)| format!("{width}x{height}"))
+        .collect::<Vec<_>>()
+        .join(",");
+    let response = send_imagga_request(
+        "/v2/croppings",
+        imagga_image,
+        &[("resolution", &resolutions)],
+        imagga_authorization,
+        calls,
+    );
+    let result = parse_imagga_response::<ImaggaCroppingsResult>(response)?;
+    Ok(result.croppings)
+}
+
+/// A suggested crop for one of the requested resolutions. (x1, y1) is the
+/// top-left corner of the crop and (x2, y2) is the bottom-right corner.
+#[derive(Deserialize, Clone)]
+pub struct ImageCropping {
+    pub target_width: u32,
+    pub target_height: u32,
+    pub x1: u32,
+    pub y1: u32,
+    pub x2: u32,
+    pub y2: u32,
+}
+
+/// A category assigned to an image by one of Imagga's categorizers
+#[derive(Clone)]
+pub struct ImageCategory {
+    pub name: String,
+    pub categorizer: String,
+    pub confidence: f32,
+}
+
+/// Imagga returns human readable category names such as "interior objects".
+/// We store them in a URL-friendly form (e.g. "interior_objects") so that they
+/// can be passed directly as a query parameter.
+fn normalize_category_name(name: &str) -> String {
+    name.trim().to_lowercase().replace(' ', "_")
+}
+
+/// Send a GET request to the given Imagga endpoint (e.g. `/v2/tags`) for the given
+/// image, recording it in `calls`. Any extra parameters are sent as query parameters.
+fn send_imagga_request(
+    endpoint: &str,
+    imagga_image: &ImaggaImage,
+    params: &[(&str, &str)],
+    imagga_authorization: &str,
+    calls: &mut Vec<TaggerCall>,
+) -> Result<Response, Error> {
+    let mut request = get(&format!("https://api.imagga.com{endpoint}"))
+        .set("Authorization", imagga_authorization);
+    request = match imagga_image {
+        ImaggaImage::Url(image_url) => request.query("image_url", image_url),
+        ImaggaImage::UploadId(upload_id) => request.query("image_upload_id", upload_id),
     };
+    for (name, value) in params {
+        request = request.query(name, value);
+    }
+    timed_call(&format!("GET {endpoint}"), calls, || request.call())
+}
+
+/// Make a request to Imagga using `send`, and record which endpoint was called,
+/// the status code we got back (if any) and how long it took in `calls`.
+fn timed_call(
+    endpoint: &str,
+    calls: &mut Vec<TaggerCall>,
+    send: impl FnOnce() -> Result<Response, Error>,
+) -> Result<Response, Error> {
+    let start = Instant::now();
+    let response = send();
+    let status = match &response {
+        Ok(response) => Some(response.status()),
+        Err(Error::Status(status, _)) => Some(*status),
+        // e.g. Imagga could not be reached at all
+        Err(_) => None,
+    };
+    calls.push(TaggerCall {
+        endpoint: endpoint.to_owned(),
+        status,
+        latency: start.elapsed(),
+    });
+    response
+}
+
+/// Ask Imagga how much of our quota we have used in the current billing period.
+/// This request does not count towards the quota, so it isn't recorded.
+pub fn get_imagga_usage(imagga_authorization: &str) -> Result<ImaggaUsage, ServerError> {
+    let response = get("https://api.imagga.com/v2/usage")
+        .set("Authorization", imagga_authorization)
+        .call();
+    parse_imagga_response::<ImaggaUsage>(response)
+}
 
+/// The part of Imagga's usage report we care about: how many requests
+/// we may make each month and how many we have made this month.
+#[derive(Deserialize, Serialize, Clone)]
+pub struct ImaggaUsage {
+    pub monthly_limit: i64,
+    pub monthly_processed: i64,
+    #[serde(default)]
+    pub billing_period_end: Option<String>,
+}
+
+impl ImaggaUsage {
+    /// How many more requests we can make this billing period
+    pub fn remaining(&self) -> i64 {
+        (self.monthly_limit - self.monthly_processed).max(0)
+    }
+}
+
+/// Convert the result of a request to Imagga (which could have been a success or a
+/// failure) into the `result` object of the response body, or a `ServerError`.
+fn parse_imagga_response<T: DeserializeOwned>(
+    response: Result<Response, Error>,
+) -> Result<T, ServerError> {
     // Exhaustively convert any errors to `ServerError`s
     let response = match response {
         Ok(response) => Ok(response),
@@ -68,7 +291,7 @@ pub fn get_tags_for_image(image_input: ImageInput, imagga_authorization: String)
         Err(Error::Status(error_code, response)) => {
             // Whenever we recieve a non-success error HTTP code (e.g. 400)
             // We can extract the error message and forward the error code
-            let error_msg = response.into_json::<ImaggaTaggingResponse>()?.status.error_text;
+            let error_msg = response.into_json::<ImaggaResponse<IgnoredAny>>()?.status.error_text;
             // Here we package the error code given to us by Imagga.
             // However, if Imagga gave us an invalid error code, then we
             // give a 500 error since something has gone totally wrong.
@@ -92,13 +315,12 @@ pub fn get_tags_for_image(image_input: ImageInput, imagga_authorization: String)
     }?; // ? operator will return from the function early with the ServerError if applicable
 
     // Now try to deserialize the response
-    match response.into_json::<ImaggaTaggingResponse>() {
+    match response.into_json::<ImaggaResponse<T>>() {
         Ok(response) => {
             // Because this a HTTP 200 result, it should have been successful.
             // Hence, we expect to see the `result` field in the JSON response.
             match response.result {
-                // If all goes well, we convert the deserialized response into a list of Strings
-                Some(result) => Ok(map_result_to_tags(result)),
+                Some(result) => Ok(result),
                 None => {
                     // Give a HTTP 500 error because this should not happen
                     // I.e., it would be weird to get a HTTP 200 response without a `result` field
@@ -137,9 +359,10 @@ fn map_result_to_tags(result: ImaggaTaggingResult) -> Vec<String> {
 /// The top-level schema for an Imagga response. The result field is optional
 /// because it can be omitted in an unsuccessful response, and we still want to
 /// be able to deserialize an unsuccessful response to get more useful error information.
+/// The type of the result depends on which endpoint was called.
 #[derive(Deserialize)]
-struct ImaggaTaggingResponse {
-    result: Option<ImaggaTaggingResult>,
+struct ImaggaResponse<T> {
+    result: Option<T>,
     status: ImaggaStatus,
 }
 /// Contains the result of a successful Imagga request, which in this case
@@ -148,18 +371,60 @@ struct ImaggaTaggingResponse {
 struct ImaggaTaggingResult {
     tags: Vec<ImaggaTag>,
 }
+/// Contains the result of a successful upload request, i.e. the id
+/// we can use to refer to the uploaded image.
+#[derive(Deserialize)]
+struct ImaggaUploadResult {
+    upload_id: String,
+}
+/// Contains the result of a successful croppings request, i.e. one
+/// suggested crop per requested resolution.
+#[derive(Deserialize)]
+struct ImaggaCroppingsResult {
+    croppings: Vec<ImageCropping>,
+}
+/// Contains the result of a successful categorization request, i.e. the list
+/// of categories the image belongs to.
+#[derive(Deserialize)]
+struct ImaggaCategoriesResult {
+    categories: Vec<ImaggaCategory>,
+}
+/// A single category along with how confident Imagga is that the image belongs to it.
+/// The name uses the same translation structure as tags.
+#[derive(Deserialize)]
+struct ImaggaCategory {
+    confidence: f32,
+    #[serde(rename = "name")]
+    translations: ImaggaTagTranslations,
+}
 /// Contians the tag as well as extra metadata we don't use (e.g. confidence).
 /// Imagga supports getting translations of tags in other languages, but we're 
 /// only interested in (and only request) the English translation.
+/// Our local tagger (see local_tagger.rs) produces tags in this same shape.
 #[derive(Deserialize)]
-struct ImaggaTag {
+pub struct ImaggaTag {
     #[allow(dead_code)]
     confidence: f32,
     #[serde(rename = "tag")]
     translations: ImaggaTagTranslations,
 }
-/// Contains the tag name in all requested languages. We only care about
-/// the English translation.
+
+impl ImaggaTag {
+    /// Construct a tag with an English name and a confidence from 0 to 100
+    pub fn new(english: String, confidence: f32) -> ImaggaTag {
+        ImaggaTag {
+            confidence,
+            translations: ImaggaTagTranslations { english },
+        }
+    }
+
+    /// The (English) name of the tag
+    pub fn name(&self) -> &str {
+        &self.translations.english
+    }
+}
+/// Contains the tag (or category) name in all requested languages. We only care
+/// about the English translation.
 #[derive(Deserialize)]
 struct ImaggaTagTranslations {
     #[serde(rename = "en")]
@@ -0,0 +1,170 @@
+use std::fs::read_to_string;
+use std::path::Path;
+use std::sync::Arc;
+
+use axum::http::StatusCode;
+use image::{imageops::FilterType, DynamicImage};
+use tract_onnx::prelude::*;
+
+use crate::config::LocalTaggerConfig;
+use crate::error::ServerError;
+use crate::imagga_client::ImaggaTag;
+
+// The size of the (square) images the model expects as input
+static INPUT_SIZE: usize = 224;
+// The mean and standard deviation of each (RGB) channel in the ImageNet
+// training data, which are used to normalize the input image
+static CHANNEL_MEAN: [f32; 3] = [0.485, 0.456, 0.406];
+static CHANNEL_STD: [f32; 3] = [0.229, 0.224, 0.225];
+// The maximum number of tags we give an image
+static MAX_TAGS: usize = 5;
+// How far the scores of a model that outputs probabilities can add up to something
+// other than 1 (because of rounding errors)
+static PROBABILITY_TOLERANCE: f32 = 0.01;
+
+/// An image classifier which runs entirely on our own machine (on the CPU),
+/// as an alternative to asking Imagga for tags. It uses an ImageNet
+/// classification model (e.g. MobileNet or ResNet) in the ONNX format.
+pub struct LocalTagger {
+    model: TypedRunnableModel<TypedModel>,
+    // The name of each class the model can predict, in the order of the model's output
+    labels: Vec<String>,
+    // Classes predicted with a lower confidence (0 to 100) are not used as tags
+    min_confidence: f32,
+}
+
+/// If a model is configured, load the local tagger using that model and its labels file.
+/// Like `get_imagga_authorization`, this panics if the configuration is invalid
+/// so that the problem is noticed on startup.
+pub fn load_local_tagger(config: &LocalTaggerConfig) -> Option<Arc<LocalTagger>> {
+    let model_path = config.model.as_ref()?;
+    let labels_path = config
+        .labels
+        .as_ref()
+        .expect("LOCAL_TAGGER_LABELS must be set when LOCAL_TAGGER_MODEL is set");
+    let tagger = LocalTagger::load(model_path, labels_path, config.min_confidence)
+        .unwrap_or_else(|err| panic!("Unable to load local tagger: {err}"));
+    Some(Arc::new(tagger))
+}
+
+impl LocalTagger {
+    /// Load and optimize the model, and read the labels file
+    pub fn load(model_path: &Path, labels_path: &Path, min_confidence: f32) -> TractResult<LocalTagger> {
+        let model = tract_onnx::onnx()
+            .model_for_path(model_path)?
+            .with_input_fact(0, f32::fact([1, 3, INPUT_SIZE, INPUT_SIZE]).into())?
+            .into_optimized()?
+            .into_runnable()?;
+        let labels = read_to_string(labels_path)?
+            .lines()
+            .map(parse_label)
+            .collect();
+        Ok(LocalTagger {
+            model,
+            labels,
+            min_confidence,
+        })
+    }
+
+    /// Tag a decoded image (e.g. an upload, or an image given by URL once it has been
+    /// downloaded and validated like an upload). The result has the same shape as the
+    /// tags Imagga gives us, with confidences from 0 to 100.
+    /// Running the model takes a while, so `tag_locally` (in routes.rs) calls this on a blocking thread.
+    pub fn tag_image(&self, image: &DynamicImage) -> Result<Vec<ImaggaTag>, ServerError> {
+        self.tag(image).map_err(|err| {
+            ServerError::new(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                format!("Error while running local tagger: {err}"),
+            )
+        })
+    }
+
+    /// Run the model on a decoded image and turn its most confident predictions into tags
+    fn tag(&self, image: &DynamicImage) -> TractResult<Vec<ImaggaTag>> {
+        // The model expects a normalized 1x3x224x224 (batch, channel, y, x) tensor
+        let size = INPUT_SIZE as u32;
+        let resized = image.resize_exact(size, size, FilterType::Triangle).to_rgb8();
+        let input: Tensor =
+            tract_ndarray::Array4::from_shape_fn((1, 3, INPUT_SIZE, INPUT_SIZE), |(_, c, y, x)| {
+                let value = resized[(x as u32, y as u32)][c] as f32 / 255.0;
+                (value - CHANNEL_MEAN[c]) / CHANNEL_STD[c]
+            })
+            .into();
+        let outputs = self.model.run(tvec!(input.into()))?;
+        let scores: Vec<f32> = outputs[0].to_array_view::<f32>()?.iter().copied().collect();
+        let mut predictions: Vec<(usize, f32)> = confidences(&scores).into_iter().enumerate().collect();
+        predictions.sort_by(|a, b| b.1.total_cmp(&a.1));
+
+        Ok(predictions
+            .into_iter()
+            .take(MAX_TAGS)
+            .filter(|(_, confidence)| *confidence >= self.min_confidence)
+            // Ignore predictions that the labels file has no name for
+            .filter_map(|(class, confidence)| {
+                self.labels
+                    .get(class)
+                    .map(|label| ImaggaTag::new(label.to_owned(), confidence))
+            })
+            .collect())
+    }
+}
+
+/// Turn the model's scores into confidences from 0 to 100 (like Imagga's). Some models
+/// already output probabilities (i.e. they end with a softmax), which are used as they
+/// are; the raw scores (logits) of other models are turned into probabilities first.
+fn confidences(scores: &[f32]) -> Vec<f32> {
+    let total: f32 = scores.iter().sum();
+    let probabilities = if scores.iter().all(|score| (0.0..=1.0).contains(score))
+        && (total - 1.0).abs() <= PROBABILITY_TOLERANCE
+    {
+        scores.to_vec()
+    } else {
+        let max_score = scores.iter().copied().fold(f32::NEG_INFINITY, f32::max);
+        let exponentials: Vec<f32> = scores.iter().map(|score| (score - max_score).exp()).collect();
+        let total: f32 = exponentials.iter().sum();
+        exponentials.iter().map(|exponential| exponential / total).collect()
+    };
+    probabilities.iter().map(|probability| probability * 100.0).collect()
+}
+
+/// Labels files for ImageNet models usually look like
+/// `n01440764 tench, Tinca tinca`, i.e. an optional WordNet id followed by
+/// a list of synonyms. We only keep the first synonym (e.g. `tench`).
+fn parse_label(line: &str) -> String {
+    let line = line.trim();
+    let line = match line.split_once(' ') {
+        Some((id, rest)) if id.len() == 9 && id.starts_with('n') && id[1..].chars().all(|c| c.is_ascii_digit()) => rest,
+        _ => line,
+    };
+    line.split(',').next().unwrap_or(line).trim().to_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn probabilities_are_used_as_they_are() {
+        let confidences = confidences(&[0.7, 0.2, 0.1]);
+        assert!((confidences[0] - 70.0).abs() < 1e-4);
+        assert!((confidences[1] - 20.0).abs() < 1e-4);
+        assert!((confidences[2] - 10.0).abs() < 1e-4);
+    }
+
+    #[test]
+    fn logits_are_turned_into_probabilities() {
+        let from_logits = confidences(&[2.0, 1.0, -3.5]);
+        let total: f32 = from_logits.iter().sum();
+        assert!((total - 100.0).abs() < 1e-3);
+        assert!(from_logits[0] > from_logits[1] && from_logits[1] > from_logits[2]);
+        // Scores that are all between 0 and 1 but don't add up to 1 aren't probabilities
+        let from_scores = confidences(&[0.5, 0.5, 0.5]);
+        assert!((from_scores[0] - 100.0 / 3.0).abs() < 1e-3);
+    }
+
+    #[test]
+    fn labels_keep_their_first_synonym() {
+        assert_eq!(parse_label("n01440764 tench, Tinca tinca"), "tench");
+        assert_eq!(parse_label("great white shark"), "great white shark");
+    }
+}
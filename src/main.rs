@@ -1,48 +1,139 @@
 use std::env;
 
 use axum::{
-    routing::{get, post},
+    routing::{get, post, put},
     Extension, Router,
 };
+use blurhash::backfill_blurhashes;
+use config::load_config;
 use imagga_client::get_imagga_authorization;
+use local_tagger::load_local_tagger;
 use migration::{Migrator, MigratorTrait};
-use routes::{get_image_by_id, get_images, post_image};
+use routes::{
+    get_file, get_image_by_id, get_image_duplicates, get_images, get_tagger_usage, post_image,
+    post_search_by_image, put_image_location,
+};
 use sea_orm::Database;
-use upload_image::{FILES_ROUTE, UPLOAD_DIR};
+use storage::get_storage;
+use tagger_usage::start_tagger_budget;
+use transform_image::CacheSize;
+use variants::backfill_variants;
+use watermark::load_watermark;
+mod auth;
+mod blurhash;
+mod config;
 mod create_image;
+mod crop_image;
 mod error;
+mod exif_metadata;
+mod image_quality;
 mod imagga_client;
+mod local_tagger;
+mod mirror_image;
+mod new_image;
+mod perceptual_hash;
 mod query_images;
 mod routes;
+mod sanitize_image;
+mod search_image;
+mod serve_file;
+mod storage;
+mod tagger_usage;
+mod temp_upload;
+mod transform_image;
 mod upload_image;
+mod url_policy;
+mod validate_image;
+mod variants;
+mod watermark;
 
 #[tokio::main]
 async fn main() {
+    // Where to listen, what our public URL is, etc. (from config.toml and/or the environment)
+    let config = load_config();
+
     // Database setup
-    let database_url = env::var("DATABASE_URL")
-        .expect("Missing DATABASE_URL environmental variable (see README.md)");
-    let database_connection = Database::connect(database_url)
+    let database_connection = Database::connect(config.connect_options())
         .await
         .expect("Unable to connect to database");
     // Here we run migrations only if they're needed. SeaORM has its own table in the
     // database which keeps track of which migrations have already been run.    
     Migrator::up(&database_connection, None).await.unwrap();
 
-    let imagga_auth = get_imagga_authorization();
+    // Where uploaded images are stored (a local directory by default)
+    let storage = get_storage(&config);
+
+    // `cargo run -- backfill-variants` generates the variants that are missing
+    // for existing images instead of starting the server
+    if env::args().nth(1).as_deref() == Some("backfill-variants") {
+        if let Err(err) = backfill_variants(&database_connection, storage.as_ref(), &config.variants, config.upload_limits).await {
+            panic!("Backfill failed: {}", err.msg());
+        }
+        return;
+    }
+    // `cargo run -- backfill-blurhashes` does the same for the BlurHash placeholders
+    if env::args().nth(1).as_deref() == Some("backfill-blurhashes") {
+        if let Err(err) = backfill_blurhashes(&database_connection, storage.as_ref(), &config.blurhash, config.upload_limits).await {
+            panic!("Backfill failed: {}", err.msg());
+        }
+        return;
+    }
+
+    // Images are tagged on our own machine instead of by Imagga if a local model is configured,
+    // in which case Imagga is optional (and only used for categorizers and smart crops)
+    let local_tagger = load_local_tagger(&config.local_tagger);
+    let imagga_auth = get_imagga_authorization(local_tagger.is_none());
+    // Keeps track of how much of our Imagga quota is left in the background
+    let tagger_budget = start_tagger_budget(imagga_auth.clone(), &config.imagga);
+    // The watermark (if any) drawn onto the files we serve
+    let watermark = load_watermark(&config.watermark);
 
     // Route and extension (i.e. for database) setup
     let app = Router::new()
         .route("/", get(|| async { "Hello, World!" }))
         .route("/images", post(post_image))
         .route("/images", get(get_images))
+        .route("/images/search-by-image", post(post_search_by_image))
         .route("/image/:image_id", get(get_image_by_id))
-        .merge(axum_extra::routing::SpaRouter::new(FILES_ROUTE, UPLOAD_DIR))
+        .route("/image/:image_id/duplicates", get(get_image_duplicates))
+        .route("/image/:image_id/location", put(put_image_location))
+        .route("/admin/tagger/usage", get(get_tagger_usage))
+        // Serve the files in storage, optionally resized (see transform_image.rs)
+        .route(&format!("{}/:key", config.files_route), get(get_file))
         // Provide our database connection to any route that wants it
         .layer(Extension(database_connection))
-        // Provide the Imagga authorization string to any route that wants it
-        .layer(Extension(imagga_auth));
+        // Provide the Imagga authorization string (if any) to any route that wants it
+        .layer(Extension(imagga_auth))
+        // Provide the local tagger (if any) to any route that wants it
+        .layer(Extension(local_tagger))
+        // Provide the Imagga budget (and latest usage report) to any route that wants it
+        .layer(Extension(tagger_budget))
+        // Provide the storage backend to any route that wants it
+        .layer(Extension(storage))
+        // Provide the limits on uploaded images to any route that wants it
+        .layer(Extension(config.upload_limits))
+        // Provide the settings for mirroring images to any route that wants it
+        .layer(Extension(config.mirror))
+        // Provide the policy for image URLs to any route that wants it
+        .layer(Extension(config.url_policy.clone()))
+        // Provide the sizes of variants to generate to any route that wants it
+        .layer(Extension(config.variants.clone()))
+        // Provide how detailed BlurHashes should be to any route that wants it
+        .layer(Extension(config.blurhash))
+        // Provide the thresholds for low quality images to any route that wants it
+        .layer(Extension(config.quality))
+        // Provide the limits on transforming files to any route that wants it
+        .layer(Extension(config.transforms.clone()))
+        // Provide how full the cache of transformed files is to any route that wants it
+        .layer(Extension(CacheSize::default()))
+        // Provide the watermark (if any) to any route that wants it
+        .layer(Extension(watermark))
+        // Provide what to do with the metadata of stored files to any route that wants it
+        .layer(Extension(config.metadata))
+        // Provide the internal users' token to any route that wants it
+        .layer(Extension(config.auth.clone()));
 
-    axum::Server::bind(&"0.0.0.0:3000".parse().unwrap())
+    axum::Server::bind(&config.bind_address)
         .serve(app.into_make_service())
         .await
         .unwrap();
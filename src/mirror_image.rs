@@ -0,0 +1,90 @@
+use std::io::Read;
+use std::time::Duration;
+
+use axum::http::StatusCode;
+use image::{GenericImageView, ImageFormat};
+use ureq::AgentBuilder;
+
+use crate::config::{MirrorConfig, UploadLimits, UrlPolicyConfig};
+use crate::error::ServerError;
+use crate::url_policy::resolve_netloc_allowed;
+use crate::validate_image::{too_large, validate_image, UploadedImage};
+
+/// What we know about the file of an image given by URL that we don't mirror
+pub struct RemoteFile {
+    pub format: ImageFormat,
+    // The width and height of the upright image, in pixels
+    pub width: u32,
+    pub height: u32,
+    pub byte_size: usize,
+    // The SHA-256 hash of the file (in hex). No file is stored under it.
+    pub content_hash: String,
+    // For finding near-duplicates and similar images (see perceptual_hash.rs and search_image.rs)
+    pub perceptual_hash: i64,
+    pub color_histogram: Vec<f32>,
+}
+
+/// What we record about the file of an image given by URL, once it has been downloaded
+/// and validated like a mirrored image (see `mirror_image`) without being stored
+pub fn remote_file(downloaded_image: &UploadedImage) -> RemoteFile {
+    RemoteFile {
+        format: downloaded_image.format,
+        width: downloaded_image.image.width(),
+        height: downloaded_image.image.height(),
+        byte_size: downloaded_image.bytes.len(),
+        content_hash: downloaded_image.content_hash.clone(),
+        perceptual_hash: downloaded_image.perceptual_hash,
+        color_histogram: downloaded_image.color_histogram.clone(),
+    }
+}
+
+/// Download the image at the given URL and validate it like an upload, so that we
+/// can store our own copy of it (i.e. a mirror) that keeps working if the source
+/// disappears. The download is done on a blocking thread since ureq is blocking.
+pub async fn mirror_image(
+    image_url: String,
+    limits: UploadLimits,
+    mirror_config: MirrorConfig,
+    url_policy: UrlPolicyConfig,
+) -> Result<UploadedImage, ServerError> {
+    tokio::task::spawn_blocking(move || {
+        let timeout = Duration::from_secs(mirror_config.timeout);
+        let bytes = download_image(&image_url, limits.max_bytes, timeout, &url_policy)?;
+        let mut uploaded_image = validate_image(bytes, &limits)?;
+        uploaded_image.source_url = Some(image_url);
+        Ok(uploaded_image)
+    })
+    .await?
+}
+
+/// Download the image at the given URL, giving up if it is larger than `max_bytes`
+/// (413) or if the whole download takes longer than `timeout` (400).
+/// Every host we connect to (including after redirects) must be allowed by the URL policy.
+pub fn download_image(
+    image_url: &str,
+    max_bytes: usize,
+    timeout: Duration,
+    url_policy: &UrlPolicyConfig,
+) -> Result<Vec<u8>, ServerError> {
+    let url_policy = url_policy.clone();
+    let agent = AgentBuilder::new()
+        .timeout(timeout)
+        .resolver(move |netloc: &str| resolve_netloc_allowed(netloc, &url_policy))
+        .build();
+    let response = agent.get(image_url).call().map_err(|err| {
+        ServerError::new(StatusCode::BAD_REQUEST, format!("Unable to download image: {err}"))
+    })?;
+    // Read one byte more than we allow, so that we can tell if the image was too large
+    let mut bytes = vec![];
+    response
+        .into_reader()
+        .take(max_bytes as u64 + 1)
+        .read_to_end(&mut bytes)
+        .map_err(|err| {
+            ServerError::new(StatusCode::BAD_REQUEST, format!("Unable to download image: {err}"))
+        })?;
+    if bytes.len() > max_bytes {
+        return Err(too_large(max_bytes));
+    }
+    Ok(bytes)
+}
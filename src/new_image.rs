@@ -0,0 +1,437 @@
+use async_trait::async_trait;
+use axum::{
+    body::{Body, HttpBody},
+    extract::{multipart::Field, FromRequest, Json, Multipart, Query, RequestParts},
+    http::{header::CONTENT_LENGTH, header::CONTENT_TYPE, StatusCode},
+    response::IntoResponse,
+    Extension,
+};
+use serde::{de::IntoDeserializer, Deserialize};
+
+use crate::config::UploadLimits;
+use crate::error::ServerError;
+use crate::temp_upload::{TempUpload, TempUploadWriter};
+use crate::validate_image::too_large;
+
+// The largest text part (i.e. anything but the image file) of a multipart form we read
+static MAX_TEXT_PART_BYTES: usize = 64 * 1024;
+// How much larger than the base64-encoded image a JSON body can be, for the other fields
+static MAX_JSON_OVERHEAD_BYTES: usize = 64 * 1024;
+
+/// This struct is deserialized from the JSON body
+/// of a `POST /images` request. It specifies whether the user
+/// wants object detection or not, as well as gives the user
+/// the option to specify the image's label (one will be
+/// generated automatically otherwise).
+/// The user has the option of specifying an image URL or
+/// an image's base64-encoded data; however, the user should
+/// do only one of these things. A HTTP 400 error will be given
+/// if the user tries to give both or neither of the `image_url`
+/// and `image_base64` fields.
+#[derive(Deserialize)]
+pub struct NewImageRequest {
+    image_url: Option<String>,
+    image_base64: Option<String>,
+    #[serde(flatten)]
+    options: NewImageOptions,
+}
+
+/// What the user wants done with a new image, however it was sent to us.
+/// `categorizers` optionally lists the ids of Imagga categorizers
+/// (e.g. `personal_photos`) that should classify the image.
+/// `mirror` says whether we should store our own copy of an image specified
+/// by URL (the configured default is used if it isn't given).
+/// `on_duplicate` says what to do if we already have an image with the same content.
+/// `strip_metadata` says whether to remove the metadata (e.g. EXIF) from the file we
+/// store (the configured default is used if it isn't given).
+/// `latitude` and `longitude` say where the image was taken, for images whose
+/// metadata doesn't say (they take precedence over the metadata if both are given).
+#[derive(Deserialize)]
+pub struct NewImageOptions {
+    pub label: Option<String>,
+    pub object_detection: bool,
+    #[serde(default)]
+    pub categorizers: Vec<String>,
+    #[serde(default)]
+    pub mirror: Option<bool>,
+    #[serde(default)]
+    pub on_duplicate: OnDuplicate,
+    #[serde(default)]
+    pub strip_metadata: Option<bool>,
+    #[serde(default)]
+    pub latitude: Option<f64>,
+    #[serde(default)]
+    pub longitude: Option<f64>,
+}
+
+/// What to do when an uploaded (or mirrored) image has exactly the same content as
+/// an image we already have (i.e. the same SHA-256 hash)
+#[derive(Deserialize, Clone, Copy, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum OnDuplicate {
+    // Respond with the existing image, without storing anything
+    Return,
+    // Create a new image that shares the existing image's file, crops and variants (the default)
+    #[default]
+    Link,
+    // Respond with a HTTP 409 error
+    Reject,
+}
+
+/// The query parameters of a `POST /images` request whose body is the image
+/// itself (e.g. `Content-Type: image/jpeg`), which take the place of NewImageOptions.
+/// `categorizers` is a comma-separated list.
+#[derive(Deserialize)]
+struct RawImageQueryParams {
+    label: Option<String>,
+    #[serde(default)]
+    object_detection: bool,
+    categorizers: Option<String>,
+    #[serde(default)]
+    on_duplicate: OnDuplicate,
+    strip_metadata: Option<bool>,
+    latitude: Option<f64>,
+    longitude: Option<f64>,
+}
+
+/// Where the image in a `POST /images` request comes from
+pub enum NewImageSource {
+    Url(String),
+    Base64(String),
+    // The image file itself, from a multipart form or a raw request body
+    File(TempUpload),
+}
+
+/// The body of a `POST /images` request, which can be sent in one of three ways
+/// depending on its `Content-Type`:
+/// - `multipart/form-data`, with the image file in an `image` part and the options
+///   in `label`, `object_detection`, `categorizers`, `on_duplicate`, `strip_metadata`,
+///   `latitude` and `longitude` parts
+/// - `image/*`, with the image file as the whole body and the options in the
+///   query parameters (see RawImageQueryParams)
+/// - anything else is treated as JSON (see NewImageRequest)
+///
+/// The image file is written to a temporary file as it arrives (see temp_upload.rs),
+/// and we stop reading as soon as it is too large.
+pub struct NewImage {
+    pub source: NewImageSource,
+    pub options: NewImageOptions,
+}
+
+#[async_trait]
+impl FromRequest<Body> for NewImage {
+    type Rejection = ServerError;
+
+    async fn from_request(req: &mut RequestParts<Body>) -> Result<NewImage, ServerError> {
+        let (content_type, limits) = content_type_and_limits(req).await?;
+
+        if content_type.starts_with("multipart/form-data") {
+            let multipart = Multipart::from_request(req)
+                .await
+                .map_err(|rejection| rejection_error(rejection.to_string(), rejection))?;
+            read_multipart(multipart, &limits).await
+        } else if content_type.starts_with("image/") {
+            let Query(params) = Query::<RawImageQueryParams>::from_request(req)
+                .await
+                .map_err(|rejection| rejection_error(rejection.to_string(), rejection))?;
+            let file = read_raw_body(req, &limits).await?;
+            Ok(NewImage {
+                source: NewImageSource::File(file),
+                options: NewImageOptions {
+                    label: params.label,
+                    object_detection: params.object_detection,
+                    categorizers: params
+                        .categorizers
+                        .map(|categorizers| split_list(&categorizers))
+                        .unwrap_or_default(),
+                    mirror: None,
+                    on_duplicate: params.on_duplicate,
+                    strip_metadata: params.strip_metadata,
+                    latitude: params.latitude,
+                    longitude: params.longitude,
+                },
+            })
+        } else {
+            read_json_body(req, &limits).await?;
+            let Json(request) = Json::<NewImageRequest>::from_request(req)
+                .await
+                .map_err(|rejection| rejection_error(rejection.to_string(), rejection))?;
+            Ok(NewImage {
+                source: image_source(request.image_url, request.image_base64)?,
+                options: request.options,
+            })
+        }
+    }
+}
+
+/// The JSON body of a `POST /images/search-by-image` request, which gives the
+/// image to search for in the same way as NewImageRequest
+#[derive(Deserialize)]
+struct SearchImageRequest {
+    image_url: Option<String>,
+    image_base64: Option<String>,
+}
+
+/// The body of a `POST /images/search-by-image` request, which can be sent in the
+/// same ways as NewImage. Options that only make sense for new images are ignored.
+pub struct SearchImage {
+    pub source: NewImageSource,
+}
+
+#[async_trait]
+impl FromRequest<Body> for SearchImage {
+    type Rejection = ServerError;
+
+    async fn from_request(req: &mut RequestParts<Body>) -> Result<SearchImage, ServerError> {
+        let (content_type, limits) = content_type_and_limits(req).await?;
+        let source = if content_type.starts_with("multipart/form-data") {
+            let multipart = Multipart::from_request(req)
+                .await
+                .map_err(|rejection| rejection_error(rejection.to_string(), rejection))?;
+            read_multipart(multipart, &limits).await?.source
+        } else if content_type.starts_with("image/") {
+            NewImageSource::File(read_raw_body(req, &limits).await?)
+        } else {
+            read_json_body(req, &limits).await?;
+            let Json(request) = Json::<SearchImageRequest>::from_request(req)
+                .await
+                .map_err(|rejection| rejection_error(rejection.to_string(), rejection))?;
+            image_source(request.image_url, request.image_base64)?
+        };
+        Ok(SearchImage { source })
+    }
+}
+
+/// The (lowercase) content type of the request, which decides how we read the
+/// body, and the limits on the size of the image in it
+async fn content_type_and_limits(req: &mut RequestParts<Body>) -> Result<(String, UploadLimits), ServerError> {
+    let content_type = req
+        .headers()
+        .get(CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .unwrap_or_default()
+        .to_ascii_lowercase();
+    let Extension(limits) = Extension::<UploadLimits>::from_request(req)
+        .await
+        .map_err(|rejection| rejection_error(rejection.to_string(), rejection))?;
+    Ok((content_type, limits))
+}
+
+/// The image given in a JSON body. A HTTP 400 error is given if the body has
+/// both or neither of `image_url` and `image_base64`.
+fn image_source(image_url: Option<String>, image_base64: Option<String>) -> Result<NewImageSource, ServerError> {
+    match (image_url, image_base64) {
+        (Some(url), None) => Ok(NewImageSource::Url(url)),
+        (None, Some(base64)) => Ok(NewImageSource::Base64(base64)),
+        (_, _) => Err(ServerError::new(
+            StatusCode::BAD_REQUEST,
+            "Expected an image URL or base64 encoded image (not both)".into(),
+        )),
+    }
+}
+
+/// Read the parts of a multipart form. Unknown parts are ignored.
+/// Text parts over MAX_TEXT_PART_BYTES are rejected with a 413 error.
+async fn read_multipart(mut multipart: Multipart, limits: &UploadLimits) -> Result<NewImage, ServerError> {
+    let mut file = None;
+    let mut label = None;
+    let mut object_detection = false;
+    let mut categorizers = vec![];
+    let mut on_duplicate = OnDuplicate::default();
+    let mut strip_metadata = None;
+    let mut latitude = None;
+    let mut longitude = None;
+    while let Some(mut field) = multipart.next_field().await.map_err(bad_form)? {
+        let name = field.name().unwrap_or_default().to_owned();
+        match name.as_str() {
+            "image" => {
+                let mut writer = TempUploadWriter::new(limits).await?;
+                while let Some(chunk) = field.chunk().await.map_err(bad_form)? {
+                    writer.write(&chunk).await?;
+                }
+                file = Some(writer.finish().await?);
+            }
+            "label" => label = Some(read_text(&mut field).await?),
+            "object_detection" => {
+                object_detection = parse_bool(&name, &read_text(&mut field).await?)?;
+            }
+            "strip_metadata" => {
+                strip_metadata = Some(parse_bool(&name, &read_text(&mut field).await?)?);
+            }
+            "latitude" => latitude = Some(parse_number(&name, &read_text(&mut field).await?)?),
+            "longitude" => longitude = Some(parse_number(&name, &read_text(&mut field).await?)?),
+            // Categorizers can be given as several parts, or as a comma-separated list
+            "categorizers" => categorizers.extend(split_list(&read_text(&mut field).await?)),
+            "on_duplicate" => {
+                let value = read_text(&mut field).await?;
+                on_duplicate = OnDuplicate::deserialize(value.trim().into_deserializer()).map_err(
+                    |_: serde::de::value::Error| {
+                        ServerError::new(
+                            StatusCode::BAD_REQUEST,
+                            format!("on_duplicate should be return, link or reject, not {value:?}"),
+                        )
+                    },
+                )?;
+            }
+            _ => {}
+        }
+    }
+    let file = file.ok_or_else(|| {
+        ServerError::new(
+            StatusCode::BAD_REQUEST,
+            "Expected the image file in an `image` part of the form".to_owned(),
+        )
+    })?;
+    Ok(NewImage {
+        source: NewImageSource::File(file),
+        options: NewImageOptions {
+            label,
+            object_detection,
+            categorizers,
+            mirror: None,
+            on_duplicate,
+            strip_metadata,
+            latitude,
+            longitude,
+        },
+    })
+}
+
+/// Read a text part of a multipart form, giving up as soon as it goes over
+/// MAX_TEXT_PART_BYTES
+async fn read_text(field: &mut Field<'_>) -> Result<String, ServerError> {
+    let mut bytes = vec![];
+    while let Some(chunk) = field.chunk().await.map_err(bad_form)? {
+        if bytes.len() + chunk.len() > MAX_TEXT_PART_BYTES {
+            return Err(ServerError::new(
+                StatusCode::PAYLOAD_TOO_LARGE,
+                format!(
+                    "The {:?} part of the form can be at most {MAX_TEXT_PART_BYTES} bytes",
+                    field.name().unwrap_or_default()
+                ),
+            ));
+        }
+        bytes.extend_from_slice(&chunk);
+    }
+    String::from_utf8(bytes).map_err(|_| {
+        ServerError::new(
+            StatusCode::BAD_REQUEST,
+            format!("The {:?} part of the form should be UTF-8 text", field.name().unwrap_or_default()),
+        )
+    })
+}
+
+/// Write the whole request body to a temporary file as it arrives, giving up as soon
+/// as it goes over the upload limit
+async fn read_raw_body(req: &mut RequestParts<Body>, limits: &UploadLimits) -> Result<TempUpload, ServerError> {
+    // Reject bodies we know are too large without reading them
+    if content_length(req).unwrap_or_default() > limits.max_bytes {
+        return Err(too_large(limits.max_bytes));
+    }
+    let mut body = take_body(req)?;
+    let mut writer = TempUploadWriter::new(limits).await?;
+    while let Some(chunk) = body.data().await {
+        writer.write(&chunk.map_err(unreadable_body)?).await?;
+    }
+    writer.finish().await
+}
+
+/// Read a JSON body into memory (we need all of it to deserialize it), and put it back
+/// for the Json extractor. It can be a bit over a third larger than the upload limit,
+/// since that is how much larger base64 makes the image.
+async fn read_json_body(req: &mut RequestParts<Body>, limits: &UploadLimits) -> Result<(), ServerError> {
+    let max_bytes = limits.max_bytes / 3 * 4 + 4 + MAX_JSON_OVERHEAD_BYTES;
+    let bytes = read_body(req, max_bytes).await?;
+    *req.body_mut() = Some(Body::from(bytes));
+    Ok(())
+}
+
+/// Read the whole request body into memory as it arrives, giving up as soon as it
+/// goes over `max_bytes` (so that we never hold more than that in memory)
+async fn read_body(req: &mut RequestParts<Body>, max_bytes: usize) -> Result<Vec<u8>, ServerError> {
+    // Reject bodies we know are too large without reading them
+    let content_length = content_length(req);
+    if content_length.unwrap_or_default() > max_bytes {
+        return Err(json_too_large(max_bytes));
+    }
+    let mut body = take_body(req)?;
+    let mut bytes = Vec::with_capacity(content_length.unwrap_or_default());
+    while let Some(chunk) = body.data().await {
+        let chunk = chunk.map_err(unreadable_body)?;
+        if bytes.len() + chunk.len() > max_bytes {
+            return Err(json_too_large(max_bytes));
+        }
+        bytes.extend_from_slice(&chunk);
+    }
+    Ok(bytes)
+}
+
+/// The `Content-Length` of the request, if it has one
+fn content_length(req: &RequestParts<Body>) -> Option<usize> {
+    req.headers()
+        .get(CONTENT_LENGTH)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.parse().ok())
+}
+
+/// Take the body out of the request, so that we can read it chunk by chunk
+fn take_body(req: &mut RequestParts<Body>) -> Result<Body, ServerError> {
+    req.take_body().ok_or_else(|| {
+        ServerError::new(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "The request body has already been read".to_owned(),
+        )
+    })
+}
+
+/// The error for request bodies we couldn't read (e.g. because the client went away)
+fn unreadable_body(err: impl std::fmt::Display) -> ServerError {
+    ServerError::new(StatusCode::BAD_REQUEST, format!("Unable to read the request body: {err}"))
+}
+
+/// The error for JSON bodies that are larger than read_json_body allows
+fn json_too_large(max_bytes: usize) -> ServerError {
+    ServerError::new(
+        StatusCode::PAYLOAD_TOO_LARGE,
+        format!("JSON bodies can be at most {max_bytes} bytes"),
+    )
+}
+
+/// Split a comma-separated list, ignoring empty entries
+fn split_list(list: &str) -> Vec<String> {
+    list.split(',')
+        .map(|item| item.trim())
+        .filter(|item| !item.is_empty())
+        .map(|item| item.to_owned())
+        .collect()
+}
+
+/// Parse a form part that should be `true` or `false`
+fn parse_bool(name: &str, value: &str) -> Result<bool, ServerError> {
+    value.trim().parse().map_err(|_| {
+        ServerError::new(
+            StatusCode::BAD_REQUEST,
+            format!("{name} should be true or false, not {value:?}"),
+        )
+    })
+}
+
+/// Parse a form part that should be a number
+fn parse_number(name: &str, value: &str) -> Result<f64, ServerError> {
+    value.trim().parse().map_err(|_| {
+        ServerError::new(
+            StatusCode::BAD_REQUEST,
+            format!("{name} should be a number, not {value:?}"),
+        )
+    })
+}
+
+/// The error for multipart forms that we couldn't read
+fn bad_form(err: axum::extract::multipart::MultipartError) -> ServerError {
+    ServerError::new(StatusCode::BAD_REQUEST, format!("Invalid multipart form: {err}"))
+}
+
+/// Turn one of axum's rejections into a ServerError, keeping its status code
+fn rejection_error(msg: String, rejection: impl IntoResponse) -> ServerError {
+    ServerError::new(rejection.into_response().status(), msg)
+}
@@ -0,0 +1,132 @@
+use axum::http::StatusCode;
+use entity::image;
+use entity::prelude::*;
+use ::image::{imageops::FilterType, DynamicImage};
+use migration::{Expr, Order};
+use sea_orm::{ColumnTrait, DatabaseConnection, EntityTrait, FromQueryResult, QueryFilter, QueryOrder, QuerySelect};
+use serde::Serialize;
+
+use crate::error::ServerError;
+
+// Hashes are made from the image shrunk down to this many pixels wide (and one less high)
+static HASH_WIDTH: u32 = 9;
+static HASH_HEIGHT: u32 = 8;
+// Images whose hashes differ by at most this many bits are almost certainly the same picture
+pub static DUPLICATE_WARNING_DISTANCE: u32 = 8;
+
+/// A stored image whose perceptual hash is close to the one we searched for
+#[derive(Serialize, FromQueryResult)]
+pub struct SimilarImage {
+    pub id: i32,
+    // The number of bits that differ between the hashes (0 to 64), where 0 is most similar
+    pub distance: i32,
+}
+
+/// Compute the difference hash (dHash) of an image: the image is shrunk to 9x8 pixels
+/// in grayscale, and each bit of the hash says whether a pixel is brighter than the one
+/// to its right. Resizing, recompressing or slightly recoloring an image barely changes
+/// its hash. The hash is stored as an i64, since that is what Postgres has.
+/// Shrinking a large image takes a while, so it is computed while validating an image
+/// (see validate_image.rs), on the same blocking thread.
+pub fn perceptual_hash(image: &DynamicImage) -> i64 {
+    let pixels = image
+        .resize_exact(HASH_WIDTH, HASH_HEIGHT, FilterType::Triangle)
+        .to_luma8();
+    let mut hash: u64 = 0;
+    for y in 0..HASH_HEIGHT {
+        for x in 0..HASH_WIDTH - 1 {
+            let brighter = pixels.get_pixel(x, y)[0] > pixels.get_pixel(x + 1, y)[0];
+            hash = (hash << 1) | brighter as u64;
+        }
+    }
+    hash as i64
+}
+
+/// Find the stored images whose perceptual hash is within `max_distance` bits of
+/// `hash` (excluding `exclude_id`, e.g. the image we are finding duplicates of),
+/// most similar first. Images without a perceptual hash are never returned.
+pub async fn find_similar_images(
+    hash: i64,
+    max_distance: u32,
+    exclude_id: Option<i32>,
+    db: &DatabaseConnection,
+) -> Result<Vec<SimilarImage>, ServerError> {
+    // The Hamming distance between the hashes, i.e. the number of 1s in their XOR
+    // (Postgres only has bit_count from version 14, so we count the 1s as text)
+    let distance = Expr::cust_with_values(
+        "length(replace((perceptual_hash # ?)::bit(64)::text, '0', ''))",
+        vec![hash],
+    );
+    let mut query = Image::find()
+        .select_only()
+        .column(image::Column::Id)
+        .column_as(distance.clone(), "distance")
+        .filter(image::Column::PerceptualHash.is_not_null())
+        .filter(Expr::expr(distance.clone()).lte(max_distance as i32));
+    if let Some(exclude_id) = exclude_id {
+        query = query.filter(image::Column::Id.ne(exclude_id));
+    }
+    let similar_images = query
+        .order_by(distance, Order::Asc)
+        .order_by_asc(image::Column::Id)
+        .into_model::<SimilarImage>()
+        .all(db)
+        .await?;
+    Ok(similar_images)
+}
+
+/// The perceptual hash of a stored image, or a 404 if there is no such image.
+/// Images without a perceptual hash (e.g. ones given by URL) give a 400, since
+/// we have nothing to compare them by.
+pub async fn get_perceptual_hash(id: i32, db: &DatabaseConnection) -> Result<i64, ServerError> {
+    let image = Image::find_by_id(id).one(db).await?.ok_or_else(|| {
+        ServerError::new(
+            StatusCode::NOT_FOUND,
+            format!("No image found with id {id}"),
+        )
+    })?;
+    image.perceptual_hash.ok_or_else(|| {
+        ServerError::new(
+            StatusCode::BAD_REQUEST,
+            format!("Image {id} has no perceptual hash (only images we store have one)"),
+        )
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use ::image::{GrayImage, Luma};
+
+    use super::*;
+
+    /// An image that gets darker from left to right
+    fn gradient(width: u32, height: u32) -> DynamicImage {
+        DynamicImage::ImageLuma8(GrayImage::from_fn(width, height, |x, _| {
+            Luma([255 - (x * 255 / width) as u8])
+        }))
+    }
+
+    #[test]
+    fn flat_images_have_an_empty_hash() {
+        let flat = DynamicImage::ImageLuma8(GrayImage::from_pixel(64, 64, Luma([128])));
+        assert_eq!(perceptual_hash(&flat), 0);
+    }
+
+    #[test]
+    fn every_bit_is_set_when_each_pixel_is_brighter_than_the_next() {
+        assert_eq!(perceptual_hash(&gradient(90, 80)), -1);
+    }
+
+    #[test]
+    fn resized_images_have_the_same_hash() {
+        let image = gradient(180, 160);
+        let resized = image.resize_exact(45, 40, FilterType::Triangle);
+        assert_eq!(perceptual_hash(&image), perceptual_hash(&resized));
+    }
+
+    #[test]
+    fn mirrored_gradients_have_the_opposite_hash() {
+        let image = gradient(90, 80);
+        assert_eq!(perceptual_hash(&image.fliph()), 0);
+    }
+}
@@ -1,7 +1,12 @@
+use std::collections::HashMap;
 use std::convert::TryInto;
 
 use axum::http::StatusCode;
+use entity::category;
 use entity::image;
+use entity::image_category;
+use entity::image_crop;
+use entity::image_variant;
 use entity::prelude::*;
 use entity::tag;
 use migration::Expr;
@@ -13,11 +18,25 @@ use sea_orm::ConnectionTrait;
 use sea_orm::DatabaseConnection;
 use sea_orm::EntityTrait;
 use sea_orm::FromQueryResult;
+use sea_orm::Condition;
 use sea_orm::QueryFilter;
+use sea_orm::QueryOrder;
 use sea_orm::Value::Int;
-use serde::Serialize;
+use serde::{Deserialize, Serialize};
 
+use crate::config::QualityConfig;
 use crate::error::ServerError;
+use crate::image_quality::{quality_issues, ImageQuality};
+use crate::perceptual_hash::SimilarImage;
+use crate::storage::Storage;
+
+// The mean radius of the Earth, in kilometers
+static EARTH_RADIUS_KM: f64 = 6371.0;
+// The number of kilometers in a degree of latitude (or of longitude at the equator)
+static KM_PER_DEGREE: f64 = 111.195;
+// How far (relatively) an image's aspect ratio may be from the requested one, since
+// dimensions are whole pixels (e.g. 1366x768 is close enough to 16:9)
+static ASPECT_RATIO_TOLERANCE: f64 = 0.01;
 
 /// This struct (which gets serialized to JSON) is how we
 /// represent images to the client. It contains a vector of 
@@ -27,9 +46,104 @@ use crate::error::ServerError;
 #[derive(Serialize)]
 pub struct ImageResult {
     url: String,
+    // Where the image came from, for images specified by URL (whether or not we mirrored them)
+    source_url: Option<String>,
+    // The format of the image's file (None for images stored before we recorded it)
+    mime_type: Option<String>,
+    // The size of the upright image in pixels, and of its file in bytes
+    // (None for images stored before we recorded them)
+    width: Option<i32>,
+    height: Option<i32>,
+    byte_size: Option<i64>,
+    // A placeholder to show while the image loads (see blurhash.rs)
+    blurhash: Option<String>,
     tags: Vec<String>,
+    categories: Vec<CategoryResult>,
+    crops: Vec<CropResult>,
+    variants: Vec<VariantResult>,
+    // The EXIF metadata of a stored image (None if it has none, or for external users)
+    exif: Option<ExifResult>,
+    // Where the image was taken (None if we don't know)
+    location: Option<LocationResult>,
+    // How usable an image looks (None for images stored before we measured it)
+    quality: Option<QualityResult>,
     label: String,
-    id: i32,
+    pub id: i32,
+    // Stored images that look almost the same as a newly stored image (only
+    // included in the response to `POST /images`, see perceptual_hash.rs)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub possible_duplicates: Option<Vec<SimilarImage>>,
+}
+
+impl ImageResult {
+    /// Remove the metadata that only our internal users may see (see auth.rs),
+    /// i.e. where a photo was taken and the EXIF metadata (which can reveal it, along
+    /// with e.g. the camera's serial number)
+    pub fn hide_private_metadata(&mut self) {
+        self.location = None;
+        self.exif = None;
+    }
+}
+
+/// How we represent a category (from one of Imagga's categorizers) to the client.
+#[derive(Serialize)]
+pub struct CategoryResult {
+    name: String,
+    categorizer: String,
+    confidence: f32,
+}
+
+/// How we represent a cropped version of an image (e.g. the square crop) to the client.
+#[derive(Serialize)]
+pub struct CropResult {
+    name: String,
+    url: String,
+    width: i32,
+    height: i32,
+}
+
+/// How we represent a resized version of an image (e.g. a thumbnail) to the client.
+#[derive(Serialize)]
+pub struct VariantResult {
+    name: String,
+    url: String,
+    mime_type: String,
+    width: i32,
+    height: i32,
+}
+
+/// How we represent the EXIF metadata of an image (see exif_metadata.rs) to the client.
+#[derive(Serialize)]
+pub struct ExifResult {
+    camera_make: Option<String>,
+    camera_model: Option<String>,
+    lens_model: Option<String>,
+    // e.g. 2022-10-18T07:28:00, in the camera's local time
+    taken_at: Option<String>,
+    orientation: Option<i32>,
+    // Every field we could read, by tag name
+    fields: Json,
+}
+
+/// How we represent where an image was taken to the client, in degrees
+/// (negative for south and west)
+#[derive(Serialize)]
+pub struct LocationResult {
+    latitude: f64,
+    longitude: f64,
+}
+
+/// How we represent the quality of an image (see image_quality.rs) to the client.
+/// `issues` lists why the image is `low_quality` (e.g. `blurry`), according to
+/// the thresholds in our configuration.
+#[derive(Serialize)]
+pub struct QualityResult {
+    sharpness: f32,
+    brightness: f32,
+    contrast: f32,
+    noise: f32,
+    low_quality: bool,
+    issues: Vec<&'static str>,
 }
 
 /// Query an image (and associated tags) by its ID.
@@ -37,6 +151,8 @@ pub struct ImageResult {
 pub async fn query_image_by_id(
     id: i32,
     db: &DatabaseConnection,
+    storage: &dyn Storage,
+    quality_config: &QualityConfig,
 ) -> Result<ImageResult, ServerError> {
     let image: Option<image::Model> = Image::find()
         .filter(image::Column::Id.eq(id))
@@ -57,11 +173,40 @@ pub async fn query_image_by_id(
             let tags: Vec<tag::Model> = image.find_related(Tag).all(db).await?;
             // Now extract names as strings from Tags (shadowing old value)
             let tags: Vec<String> = tags.iter().map(|tag| tag.name.clone()).collect();
+            let categories = get_categories_for_images(vec![image.id], db)
+                .await?
+                .remove(&image.id)
+                .unwrap_or_default();
+            let crops = get_crops_for_images(vec![image.id], db, storage)
+                .await?
+                .remove(&image.id)
+                .unwrap_or_default();
+            let variants = get_variants_for_images(vec![image.id], db, storage)
+                .await?
+                .remove(&image.id)
+                .unwrap_or_default();
+            // These borrow the image, so they come before its fields are moved into the result
+            let exif = get_exif_result(&image);
+            let location = get_location_result(&image);
+            let quality = get_quality_result(&image, quality_config);
             Ok(ImageResult {
-                url: image.url,
+                url: get_image_url(&image, storage),
+                source_url: image.source_url,
+                mime_type: image.mime_type,
+                width: image.width,
+                height: image.height,
+                byte_size: image.byte_size,
+                blurhash: image.blurhash,
                 id: image.id,
                 label: image.label,
                 tags,
+                categories,
+                crops,
+                variants,
+                exif,
+                location,
+                quality,
+                possible_duplicates: None,
             })
         }
     }
@@ -77,17 +222,87 @@ pub enum TagFilter {
     ContainsSomeTags(Vec<String>),
     ContainsAllTags(Vec<String>),
 }
+/// Filters that are applied on top of the TagFilter. Each field
+/// that is set narrows down the returned images further.
+/// `category` only keeps images that were assigned that category
+/// by any of the categorizers. `ids` only keeps the images with those ids.
+/// `taken_after` and `taken_before` only keep images whose EXIF metadata says
+/// they were taken in that time (inclusive), and `camera_model` only keeps
+/// images taken with that camera model.
+/// `bbox` only keeps images taken inside a bounding box, and `near`
+/// only keeps images taken within a distance of a point (see below).
+/// `min_width` and `min_height` only keep images at least that large (in pixels),
+/// `shape` only keeps landscape, portrait or square images, and `aspect_ratio`
+/// only keeps images whose width divided by their height is about that.
+/// Images whose dimensions we don't know are left out by these.
+/// `min_sharpness` only keeps images at least that sharp (see image_quality.rs).
+/// `sort` is the order to return the images in (by id if it isn't given).
+#[derive(Default)]
+pub struct ImageFilters {
+    pub category: Option<String>,
+    pub ids: Option<Vec<i32>>,
+    pub taken_after: Option<DateTime>,
+    pub taken_before: Option<DateTime>,
+    pub camera_model: Option<String>,
+    pub bbox: Option<BoundingBox>,
+    pub near: Option<Near>,
+    pub min_width: Option<i32>,
+    pub min_height: Option<i32>,
+    pub shape: Option<Shape>,
+    pub aspect_ratio: Option<f64>,
+    pub min_sharpness: Option<f32>,
+    pub sort: Option<ImageSort>,
+}
+/// The orders images can be returned in, other than by id
+#[derive(Deserialize, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum ImageSort {
+    // Sharpest first, followed by the images we haven't measured
+    Sharpness,
+}
+/// Whether an image is wider than it is high (landscape), higher than it is wide
+/// (portrait) or neither (square)
+#[derive(Deserialize, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum Shape {
+    Landscape,
+    Portrait,
+    Square,
+}
+/// An area on the map, in degrees. If `min_longitude` is greater than
+/// `max_longitude`, the box crosses the antimeridian (e.g. from 170 to -170).
+pub struct BoundingBox {
+    pub min_longitude: f64,
+    pub min_latitude: f64,
+    pub max_longitude: f64,
+    pub max_latitude: f64,
+}
+/// A circle on the map: a point in degrees and a radius in kilometers
+pub struct Near {
+    pub latitude: f64,
+    pub longitude: f64,
+    pub radius_km: f64,
+}
 /// Return all images (and their tags), or all images that match
-/// a certain filter (see above TagFilter struct).
+/// a certain filter (see above TagFilter and ImageFilters structs).
 pub async fn query_images(
     tag_filter: TagFilter,
+    filters: ImageFilters,
     db: &DatabaseConnection,
+    storage: &dyn Storage,
+    quality_config: &QualityConfig,
 ) -> Result<Vec<ImageResult>, ServerError> {
-    let images_with_tags: Vec<(image::Model, Vec<tag::Model>)> = match tag_filter {
+    let sort = filters.sort;
+    let condition = get_filters_condition(filters);
+    let mut images_with_tags: Vec<(image::Model, Vec<tag::Model>)> = match tag_filter {
         TagFilter::None => {
             // Simplest case: select all images and join them
             // with their tags
-            Image::find().find_with_related(Tag).all(db).await?
+            Image::find()
+                .find_with_related(Tag)
+                .filter(condition)
+                .all(db)
+                .await?
         }
         TagFilter::ContainsSomeTags(tags) => {
             // Slightly more complicated: filter the images
@@ -95,6 +310,7 @@ pub async fn query_images(
             Image::find()
                 .find_with_related(Tag)
                 .filter(tag::Column::Name.is_in(tags))
+                .filter(condition)
                 .all(db)
                 .await?
         }
@@ -107,21 +323,50 @@ pub async fn query_images(
             Image::find()
                 .find_with_related(Tag)
                 .filter(image::Column::Id.is_in(image_ids))
+                .filter(condition)
                 .all(db)
                 .await?
         }
     };
 
+    // The images come back ordered by id (which is how SeaORM groups their tags),
+    // so any other order is applied here
+    if let Some(ImageSort::Sharpness) = sort {
+        images_with_tags.sort_by(|(a, _), (b, _)| match (a.sharpness, b.sharpness) {
+            (Some(a), Some(b)) => b.total_cmp(&a),
+            (a, b) => b.is_some().cmp(&a.is_some()),
+        });
+    }
+
+    // Categories, crops and variants are each fetched in a single extra query for all the images
+    let image_ids: Vec<i32> = images_with_tags.iter().map(|(image, _)| image.id).collect();
+    let mut categories = get_categories_for_images(image_ids.clone(), db).await?;
+    let mut crops = get_crops_for_images(image_ids.clone(), db, storage).await?;
+    let mut variants = get_variants_for_images(image_ids, db, storage).await?;
+
     let result_images: Vec<ImageResult> = images_with_tags
         .iter()
         .map(|(image, tags)| {
             // Extract names from Tags (shadowing old value)
             let tags: Vec<String> = tags.iter().map(|tag| tag.name.clone()).collect();
             ImageResult {
-                url: image.url.clone(),
+                url: get_image_url(image, storage),
+                source_url: image.source_url.clone(),
+                mime_type: image.mime_type.clone(),
+                width: image.width,
+                height: image.height,
+                byte_size: image.byte_size,
+                blurhash: image.blurhash.clone(),
                 id: image.id,
                 label: image.label.clone(),
                 tags,
+                categories: categories.remove(&image.id).unwrap_or_default(),
+                crops: crops.remove(&image.id).unwrap_or_default(),
+                variants: variants.remove(&image.id).unwrap_or_default(),
+                exif: get_exif_result(image),
+                location: get_location_result(image),
+                quality: get_quality_result(image, quality_config),
+                possible_duplicates: None,
             }
         })
         .collect();
@@ -129,6 +374,300 @@ pub async fn query_images(
     Ok(result_images)
 }
 
+/// The URL clients should use to access an image: where we stored it if it was
+/// uploaded (or mirrored), or the URL it was given by otherwise
+fn get_image_url(image: &image::Model, storage: &dyn Storage) -> String {
+    match (&image.storage_key, &image.source_url) {
+        (Some(storage_key), _) => storage.url(storage_key),
+        (None, Some(url)) => url.clone(),
+        (None, None) => String::new(),
+    }
+}
+
+/// The EXIF metadata of an image, or None if we didn't find any
+fn get_exif_result(image: &image::Model) -> Option<ExifResult> {
+    Some(ExifResult {
+        camera_make: image.camera_make.clone(),
+        camera_model: image.camera_model.clone(),
+        lens_model: image.lens_model.clone(),
+        taken_at: image
+            .taken_at
+            .map(|taken_at| taken_at.format("%Y-%m-%dT%H:%M:%S").to_string()),
+        orientation: image.orientation,
+        fields: image.exif.clone()?,
+    })
+}
+
+/// Where an image was taken, or None if we don't know
+fn get_location_result(image: &image::Model) -> Option<LocationResult> {
+    Some(LocationResult {
+        latitude: image.latitude?,
+        longitude: image.longitude?,
+    })
+}
+
+/// The quality of an image, flagged against our thresholds, or None if we didn't measure it
+fn get_quality_result(image: &image::Model, quality_config: &QualityConfig) -> Option<QualityResult> {
+    let quality = ImageQuality {
+        sharpness: image.sharpness?,
+        brightness: image.brightness?,
+        contrast: image.contrast?,
+        noise: image.noise?,
+    };
+    let issues = quality_issues(&quality, quality_config);
+    Some(QualityResult {
+        sharpness: quality.sharpness,
+        brightness: quality.brightness,
+        contrast: quality.contrast,
+        noise: quality.noise,
+        low_quality: !issues.is_empty(),
+        issues,
+    })
+}
+
+/// The condition for images taken inside a bounding box
+fn bounding_box_condition(bbox: &BoundingBox) -> Condition {
+    let longitude = if bbox.min_longitude <= bbox.max_longitude {
+        Condition::all()
+            .add(image::Column::Longitude.gte(bbox.min_longitude))
+            .add(image::Column::Longitude.lte(bbox.max_longitude))
+    } else {
+        // The box crosses the antimeridian, so it is made of the two sides of it
+        Condition::any()
+            .add(image::Column::Longitude.gte(bbox.min_longitude))
+            .add(image::Column::Longitude.lte(bbox.max_longitude))
+    };
+    Condition::all()
+        .add(image::Column::Latitude.gte(bbox.min_latitude))
+        .add(image::Column::Latitude.lte(bbox.max_latitude))
+        .add(longitude)
+}
+
+/// The condition for images taken within a distance of a point (as the crow flies).
+/// Images are first narrowed down to the bounding box around the circle (which can
+/// use the location index), and then by their exact distance (the haversine formula).
+fn near_condition(near: &Near) -> Condition {
+    let latitude_delta = near.radius_km / KM_PER_DEGREE;
+    let min_latitude = near.latitude - latitude_delta;
+    let max_latitude = near.latitude + latitude_delta;
+    // Degrees of longitude get shorter towards the poles, and near a pole
+    // (or for a huge radius) the circle covers every longitude
+    let widest_latitude = min_latitude.abs().max(max_latitude.abs());
+    let longitude_delta = if widest_latitude < 90.0 {
+        latitude_delta / widest_latitude.to_radians().cos()
+    } else {
+        180.0
+    };
+    let bbox = if longitude_delta >= 180.0 {
+        BoundingBox {
+            min_longitude: -180.0,
+            min_latitude,
+            max_longitude: 180.0,
+            max_latitude,
+        }
+    } else {
+        BoundingBox {
+            min_longitude: wrap_longitude(near.longitude - longitude_delta),
+            min_latitude,
+            max_longitude: wrap_longitude(near.longitude + longitude_delta),
+            max_latitude,
+        }
+    };
+    // i.e. 2 * R * asin(sqrt(sin²(Δlat / 2) + cos(lat1) * cos(lat2) * sin²(Δlon / 2))) <= radius
+    let distance = Expr::cust_with_values(
+        &format!(
+            "2 * ? * asin(least(1, sqrt(\
+                power(sin(radians({latitude} - ?) / 2), 2) + \
+                cos(radians(?)) * cos(radians({latitude})) * power(sin(radians({longitude} - ?) / 2), 2)\
+            ))) <= ?",
+            latitude = image_column(image::Column::Latitude),
+            longitude = image_column(image::Column::Longitude),
+        ),
+        vec![
+            EARTH_RADIUS_KM,
+            near.latitude,
+            near.latitude,
+            near.longitude,
+            near.radius_km,
+        ],
+    );
+    bounding_box_condition(&bbox).add(distance)
+}
+
+/// A column of the Image table, qualified with the table name, for conditions that are
+/// written in SQL (so that they stay unambiguous when the query joins other tables)
+fn image_column(column: image::Column) -> String {
+    format!("\"{}\".\"{}\"", image::Entity.table_name(), column.as_str())
+}
+
+/// Bring a longitude that went past the antimeridian back into -180 to 180
+fn wrap_longitude(longitude: f64) -> f64 {
+    if longitude > 180.0 {
+        longitude - 360.0
+    } else if longitude < -180.0 {
+        longitude + 360.0
+    } else {
+        longitude
+    }
+}
+
+/// Turn the ImageFilters into a condition on the Image table
+/// which can be added to any query that selects images.
+fn get_filters_condition(filters: ImageFilters) -> Condition {
+    let mut condition = Condition::all();
+    if let Some(category) = filters.category {
+        // i.e.
+        //   image.id IN (SELECT image_category.image_id FROM image_category
+        //     JOIN category ON image_category.category_id = category.id
+        //     WHERE category.name = 'interior_objects')
+        let image_ids_query = Query::select()
+            .column((migration::ImageCategory::Table, migration::ImageCategory::ImageId))
+            .from(migration::ImageCategory::Table)
+            .join(
+                migration::JoinType::InnerJoin,
+                migration::Category::Table,
+                Expr::tbl(migration::ImageCategory::Table, migration::ImageCategory::CategoryId)
+                    .equals(migration::Category::Table, migration::Category::Id),
+            )
+            .and_where(Expr::tbl(migration::Category::Table, migration::Category::Name).eq(category))
+            .to_owned();
+        condition = condition.add(image::Column::Id.in_subquery(image_ids_query));
+    }
+    if let Some(ids) = filters.ids {
+        condition = condition.add(image::Column::Id.is_in(ids));
+    }
+    if let Some(taken_after) = filters.taken_after {
+        condition = condition.add(image::Column::TakenAt.gte(taken_after));
+    }
+    if let Some(taken_before) = filters.taken_before {
+        condition = condition.add(image::Column::TakenAt.lte(taken_before));
+    }
+    if let Some(camera_model) = filters.camera_model {
+        condition = condition.add(image::Column::CameraModel.eq(camera_model));
+    }
+    if let Some(bbox) = filters.bbox {
+        condition = condition.add(bounding_box_condition(&bbox));
+    }
+    if let Some(near) = filters.near {
+        condition = condition.add(near_condition(&near));
+    }
+    if let Some(min_width) = filters.min_width {
+        condition = condition.add(image::Column::Width.gte(min_width));
+    }
+    if let Some(min_height) = filters.min_height {
+        condition = condition.add(image::Column::Height.gte(min_height));
+    }
+    if let Some(shape) = filters.shape {
+        condition = condition.add(match shape {
+            Shape::Landscape => Expr::tbl(image::Entity, image::Column::Width)
+                .greater_than(Expr::tbl(image::Entity, image::Column::Height)),
+            Shape::Portrait => Expr::tbl(image::Entity, image::Column::Width)
+                .less_than(Expr::tbl(image::Entity, image::Column::Height)),
+            Shape::Square => Expr::tbl(image::Entity, image::Column::Width)
+                .equals(image::Entity, image::Column::Height),
+        });
+    }
+    if let Some(min_sharpness) = filters.min_sharpness {
+        condition = condition.add(image::Column::Sharpness.gte(min_sharpness));
+    }
+    if let Some(aspect_ratio) = filters.aspect_ratio {
+        condition = condition.add(Expr::cust_with_values(
+            &format!(
+                "{}::float8 / nullif({}, 0) BETWEEN ? AND ?",
+                image_column(image::Column::Width),
+                image_column(image::Column::Height),
+            ),
+            vec![
+                aspect_ratio * (1.0 - ASPECT_RATIO_TOLERANCE),
+                aspect_ratio * (1.0 + ASPECT_RATIO_TOLERANCE),
+            ],
+        ));
+    }
+    condition
+}
+
+/// Fetch the categories of the provided images, grouped by image id.
+/// Images without any categories will not have an entry in the map.
+async fn get_categories_for_images(
+    image_ids: Vec<i32>,
+    db: &DatabaseConnection,
+) -> Result<HashMap<i32, Vec<CategoryResult>>, ServerError> {
+    let image_categories: Vec<(image_category::Model, Option<category::Model>)> =
+        ImageCategory::find()
+            .find_also_related(Category)
+            .filter(image_category::Column::ImageId.is_in(image_ids))
+            .all(db)
+            .await?;
+
+    let mut categories: HashMap<i32, Vec<CategoryResult>> = HashMap::new();
+    for (image_category, category) in image_categories {
+        // The foreign key guarantees the category exists
+        if let Some(category) = category {
+            categories
+                .entry(image_category.image_id)
+                .or_default()
+                .push(CategoryResult {
+                    name: category.name,
+                    categorizer: category.categorizer,
+                    confidence: image_category.confidence,
+                });
+        }
+    }
+    Ok(categories)
+}
+
+/// Fetch the crops of the provided images, grouped by image id.
+/// Images without any crops (e.g. ones specified by URL) will not
+/// have an entry in the map.
+async fn get_crops_for_images(
+    image_ids: Vec<i32>,
+    db: &DatabaseConnection,
+    storage: &dyn Storage,
+) -> Result<HashMap<i32, Vec<CropResult>>, ServerError> {
+    let image_crops: Vec<image_crop::Model> = ImageCrop::find()
+        .filter(image_crop::Column::ImageId.is_in(image_ids))
+        .all(db)
+        .await?;
+
+    let mut crops: HashMap<i32, Vec<CropResult>> = HashMap::new();
+    for crop in image_crops {
+        crops.entry(crop.image_id).or_default().push(CropResult {
+            name: crop.name,
+            url: storage.url(&crop.storage_key),
+            width: crop.width,
+            height: crop.height,
+        });
+    }
+    Ok(crops)
+}
+
+/// Fetch the variants of the provided images, grouped by image id
+/// and ordered from smallest to largest. Images without any variants
+/// (e.g. ones specified by URL) will not have an entry in the map.
+async fn get_variants_for_images(
+    image_ids: Vec<i32>,
+    db: &DatabaseConnection,
+    storage: &dyn Storage,
+) -> Result<HashMap<i32, Vec<VariantResult>>, ServerError> {
+    let image_variants: Vec<image_variant::Model> = ImageVariant::find()
+        .filter(image_variant::Column::ImageId.is_in(image_ids))
+        .order_by_asc(image_variant::Column::Width)
+        .all(db)
+        .await?;
+
+    let mut variants: HashMap<i32, Vec<VariantResult>> = HashMap::new();
+    for variant in image_variants {
+        variants.entry(variant.image_id).or_default().push(VariantResult {
+            name: variant.name,
+            url: storage.url(&variant.storage_key),
+            mime_type: variant.mime_type,
+            width: variant.width,
+            height: variant.height,
+        });
+    }
+    Ok(variants)
+}
+
 /// Fetch the ids of the images that have all the tags
 /// in the provided string vector.
 async fn get_image_ids_that_have_all_tags(
@@ -1,84 +1,527 @@
+use std::sync::Arc;
+
 use axum::{
     extract::{Json, Path, Query},
-    http::StatusCode,
+    http::{HeaderMap, StatusCode},
+    response::Response,
     Extension,
 };
-use sea_orm::DatabaseConnection;
+use chrono::{NaiveDate, NaiveDateTime};
+use entity::image;
+use entity::prelude::*;
+use sea_orm::{ActiveModelTrait, DatabaseConnection, EntityTrait, Set};
 use serde::Deserialize;
 
 use crate::{
-    create_image::execute_insert_image,
+    auth::InternalUser,
+    blurhash::image_blurhash,
+    config::{
+        BlurhashConfig, MetadataConfig, MirrorConfig, QualityConfig, TransformConfig, UploadLimits,
+        UrlPolicyConfig, VariantConfig,
+    },
+    create_image::{execute_insert_image, find_duplicate, ImageAnalysis, ImageDetails},
+    crop_image::crop_resolutions,
     error::ServerError,
-    imagga_client::{get_tags_for_image, ImageInput},
-    query_images::{query_image_by_id, query_images, ImageResult, TagFilter},
+    image_quality::measure_quality,
+    imagga_client::{
+        get_categories_for_image, get_croppings_for_image, get_tags_for_image, with_imagga_image,
+        ImageInput, ImaggaImage,
+    },
+    local_tagger::LocalTagger,
+    mirror_image::{mirror_image, remote_file},
+    new_image::{NewImage, NewImageSource, OnDuplicate, SearchImage},
+    sanitize_image::sanitize_upload,
+    perceptual_hash::{find_similar_images, get_perceptual_hash, SimilarImage, DUPLICATE_WARNING_DISTANCE},
+    storage::Storage,
+    query_images::{
+        query_image_by_id, query_images, BoundingBox, ImageFilters, ImageResult, ImageSort, Near,
+        Shape, TagFilter,
+    },
+    tagger_usage::{
+        check_tagger_budget, get_tagger_usage_report, record_tagger_calls, TaggerBudget,
+        TaggerCall, TaggerUsageReport,
+    },
+    search_image::{search_by_image, SearchResult},
+    serve_file::file_response,
+    transform_image::{get_transformed_file, CacheSize, TransformParams},
+    url_policy::check_image_url,
+    watermark::Watermark,
+    validate_image::{validate_base64_image, validate_image_file, UploadedImage},
 };
 
-/// This struct is deserialized from the JSON body
-/// of a `POST /images` request. It specifies whether the user
-/// wants object detection or not, as well as gives the user 
-/// the option to specify the image's label (one will be 
-/// generated automatically otherwise). 
-/// The user has the option of specifying an image URL or 
-/// an image's base64-encoded data; however, the user should
-/// do only one of these things. A HTTP 400 error will be given
-/// if the user tries to give both or neither of the `image_url`
-/// and `image_base64` fields.
-#[derive(Deserialize)]
-pub struct NewImageRequest {
-    image_url: Option<String>,
-    image_base64: Option<String>,
-    label: Option<String>,
-    object_detection: bool,
-}
-
-/// The route handler for the `POST /images` endpoint. The JSON
-/// body is deserialized into the NewImageRequest struct. A 400 or 500
+/// The route handler for the `POST /images` endpoint. The body is either
+/// JSON, a multipart form or the image itself (see NewImage). A 400 or 500
 /// class error can be returned depending on whether the user was at fault.
 /// If no errors occur, the image is inserted into the database and the
 /// resulting inserted images is serialized and sent back to the user.
 /// If the insert fails mid-request, its changes to the database will
 /// be rolled back (see execute_insert_image implementation.)
+/// Image URLs must be allowed by our URL policy (see url_policy.rs), and uploaded (and
+/// mirrored) images are validated before anything else is done with them. Invalid images
+/// are rejected with a 400, 413 or 415 error (see validate_image.rs).
+/// Uploads with the same content as an image we already have are returned, linked
+/// or rejected with a 409 error, depending on the request's `on_duplicate`.
+/// Uploads that look almost the same as images we already have are still stored,
+/// but those images are listed in the response's `possible_duplicates`.
+/// Uploads are turned upright and (by default) have their metadata removed before
+/// they are stored (see sanitize_image.rs). Private metadata is only included in the
+/// response for internal users (see auth.rs).
+/// Every request made to Imagga along the way is recorded (see tagger_usage.rs),
+/// and auto-tagging is refused once our Imagga budget has been used up.
+/// If a local tagger is configured, it is used for object detection instead of Imagga.
 pub async fn post_image(
-    Json(request): Json<NewImageRequest>,
     Extension(ref db): Extension<DatabaseConnection>,
-    Extension(imagga_authorization): Extension<String>,
+    Extension(imagga_authorization): Extension<Option<String>>,
+    Extension(ref tagger_budget): Extension<TaggerBudget>,
+    Extension(local_tagger): Extension<Option<Arc<LocalTagger>>>,
+    Extension(storage): Extension<Arc<dyn Storage>>,
+    Extension(upload_limits): Extension<UploadLimits>,
+    Extension(mirror_config): Extension<MirrorConfig>,
+    Extension(url_policy): Extension<UrlPolicyConfig>,
+    Extension(ref variant_config): Extension<VariantConfig>,
+    Extension(blurhash_config): Extension<BlurhashConfig>,
+    Extension(ref quality_config): Extension<QualityConfig>,
+    Extension(metadata_config): Extension<MetadataConfig>,
+    InternalUser(internal_user): InternalUser,
+    NewImage {
+        source,
+        options: request,
+    }: NewImage,
 ) -> Result<Json<ImageResult>, ServerError> {
-    // Pattern match on the input to make sure that the user has provided an image
-    // URL or base64-encoded data but not both/neither.
-    let image_input = match (request.image_url, request.image_base64) {
-        (Some(url), None) => Ok(ImageInput::ImageUrl(url)),
-        (None, Some(base64)) => Ok(ImageInput::ImageBase64(base64)),
-        (_, _) => Err(ServerError::new(
-            StatusCode::BAD_REQUEST,
-            "Expected an image URL or base64 encoded image (not both)".into(),
-        )),
-    }?;
+    // URLs are checked before we (or Imagga) fetch anything from them
+    if let NewImageSource::Url(url) = &source {
+        check_image_url(url, &url_policy).await?;
+    }
+    let location = match (request.latitude, request.longitude) {
+        (Some(latitude), Some(longitude)) => Some(check_location(latitude, longitude)?),
+        (None, None) => None,
+        _ => {
+            return Err(ServerError::new(
+                StatusCode::BAD_REQUEST,
+                "latitude and longitude must be given together".to_owned(),
+            ))
+        }
+    };
+    let strip_metadata = request.strip_metadata.unwrap_or(metadata_config.strip_by_default);
+    let image_input = match source {
+        // Mirrored images are downloaded now and then treated like uploads
+        NewImageSource::Url(url) if request.mirror.unwrap_or(mirror_config.by_default) => {
+            let uploaded_image =
+                mirror_image(url, upload_limits, mirror_config, url_policy.clone()).await?;
+            ImageInput::ImageUpload(Arc::new(sanitize(uploaded_image, strip_metadata).await?))
+        }
+        NewImageSource::Url(url) => ImageInput::ImageUrl(url),
+        NewImageSource::Base64(base64) => {
+            let uploaded_image = validate_base64_image(base64, upload_limits).await?;
+            ImageInput::ImageUpload(Arc::new(sanitize(uploaded_image, strip_metadata).await?))
+        }
+        NewImageSource::File(upload) => {
+            let uploaded_image = validate_image_file(upload, upload_limits).await?;
+            ImageInput::ImageUpload(Arc::new(sanitize(uploaded_image, strip_metadata).await?))
+        }
+    };
+
+    // Duplicates are handled before any tagging, so that returning or rejecting
+    // them doesn't cost us anything
+    let mut linked_image = None;
+    if let ImageInput::ImageUpload(uploaded_image) = &image_input {
+        if let Some(duplicate) = find_duplicate(&uploaded_image.content_hash, db).await? {
+            match request.on_duplicate {
+                OnDuplicate::Return => {
+                    let mut image =
+                        query_image_by_id(duplicate.id, db, storage.as_ref(), quality_config).await?;
+                    if !internal_user {
+                        image.hide_private_metadata();
+                    }
+                    return Ok(Json(image));
+                }
+                OnDuplicate::Reject => {
+                    return Err(ServerError::new(
+                        StatusCode::CONFLICT,
+                        format!("This image has already been stored as image {}", duplicate.id),
+                    ))
+                }
+                OnDuplicate::Link => linked_image = Some(duplicate),
+            }
+        }
+    }
+
+    // We don't store images given by URL, but we download (and validate) them once,
+    // like mirrored images, so that we can look at them like uploads: record their
+    // dimensions, tag them locally, and make their placeholder and measure their quality
+    let (decoded_image, remote_file) = match &image_input {
+        ImageInput::ImageUpload(uploaded_image) => (uploaded_image.clone(), None),
+        ImageInput::ImageUrl(url) => {
+            let downloaded_image =
+                mirror_image(url.clone(), upload_limits, mirror_config, url_policy.clone()).await?;
+            let file = remote_file(&downloaded_image);
+            (Arc::new(downloaded_image), Some(file))
+        }
+    };
+    // Clients get a placeholder to show while the image loads, and its quality is measured
+    let (blurhash, quality) = {
+        let decoded_image = decoded_image.clone();
+        tokio::task::spawn_blocking(move || {
+            (
+                image_blurhash(&decoded_image.image, &blurhash_config),
+                measure_quality(&decoded_image.image),
+            )
+        })
+        .await?
+    };
+
+    // Objects are detected by the local tagger if we have one, and by Imagga otherwise
+    let imagga_object_detection = request.object_detection && local_tagger.is_none();
+    let auto_tagging = imagga_object_detection || !request.categorizers.is_empty();
+    // Uploaded images get cropped versions, so we also want Imagga for those.
+    // However, since we can crop them without Imagga, running out of budget
+    // (or not having Imagga at all) just means we skip asking Imagga.
+    // Linked duplicates share the crops of the image they duplicate.
+    let is_upload = matches!(image_input, ImageInput::ImageUpload(_));
+    let perceptual_hash = decoded_image.perceptual_hash;
+    let wants_smart_crop = is_upload && linked_image.is_none();
+    // The budget is only checked (once) if we would ask Imagga for anything
+    let within_budget = if imagga_authorization.is_some() && (auto_tagging || wants_smart_crop) {
+        check_tagger_budget(tagger_budget, db).await
+    } else {
+        Ok(())
+    };
+    let smart_crop = wants_smart_crop && imagga_authorization.is_some() && within_budget.is_ok();
+    if auto_tagging {
+        if imagga_authorization.is_none() {
+            let unavailable = match (imagga_object_detection, request.categorizers.is_empty()) {
+                (true, true) => "Object detection is",
+                (true, false) => "Object detection and categorizers are",
+                (false, _) => "Categorizers are",
+            };
+            return Err(ServerError::new(
+                StatusCode::BAD_REQUEST,
+                format!("{unavailable} unavailable because Imagga is not configured"),
+            ));
+        }
+        within_budget?;
+    }
+
+    let (analysis, tagger_calls) = match imagga_authorization {
+        Some(imagga_authorization) if auto_tagging || smart_crop => {
+            // All the analyses share a single upload of the image to Imagga.
+            // ureq is blocking, so we make the requests on a separate thread.
+            let input = image_input.clone();
+            let categorizers = request.categorizers;
+            tokio::task::spawn_blocking(move || {
+                let mut calls: Vec<TaggerCall> = vec![];
+                let analysis = with_imagga_image(
+                    &input,
+                    &imagga_authorization,
+                    &mut calls,
+                    |imagga_image, calls| {
+                        analyze_image(
+                            imagga_image,
+                            imagga_object_detection,
+                            categorizers,
+                            smart_crop,
+                            &imagga_authorization,
+                            calls,
+                        )
+                    },
+                );
+                (analysis, calls)
+            })
+            .await?
+        }
+        _ => (Ok(ImageAnalysis::default()), vec![]),
+    };
+    // Now run the local tagger (if it is used). The model runs on the CPU,
+    // so we move it to a separate thread to avoid blocking other requests.
+    let analysis = match (analysis, local_tagger) {
+        (Ok(mut analysis), Some(local_tagger)) if request.object_detection => {
+            match tag_locally(local_tagger, decoded_image).await {
+                Ok(tags) => {
+                    analysis.tags = tags;
+                    Ok(analysis)
+                }
+                Err(err) => Err(err),
+            }
+        }
+        (analysis, _) => analysis,
+    };
+    let inserted = match analysis {
+        Ok(mut analysis) => {
+            analysis.remote_file = remote_file;
+            analysis.blurhash = Some(blurhash);
+            analysis.quality = Some(quality);
+            execute_insert_image(
+                image_input,
+                analysis,
+                ImageDetails {
+                    label: request.label,
+                    location,
+                },
+                db,
+                storage.as_ref(),
+                variant_config,
+                linked_image,
+            )
+            .await
+        }
+        Err(err) => Err(err),
+    };
+    // The calls are recorded whether or not the image made it into the database,
+    // since they count towards our quota either way
+    record_tagger_calls(tagger_calls, inserted.as_ref().ok().copied(), db).await?;
+    let image_id = inserted?;
+
+    let mut image = query_image_by_id(image_id, db, storage.as_ref(), quality_config).await?;
+    image.possible_duplicates =
+        Some(find_similar_images(perceptual_hash, DUPLICATE_WARNING_DISTANCE, Some(image_id), db).await?);
+    if !internal_user {
+        image.hide_private_metadata();
+    }
+    Ok(Json(image))
+}
+
+/// Prepare an uploaded image's file for storage (see `sanitize_upload`) on a blocking thread
+async fn sanitize(mut uploaded_image: UploadedImage, strip_metadata: bool) -> Result<UploadedImage, ServerError> {
+    tokio::task::spawn_blocking(move || {
+        sanitize_upload(&mut uploaded_image, strip_metadata)?;
+        Ok(uploaded_image)
+    })
+    .await?
+}
 
-    let tags = if request.object_detection {
-        get_tags_for_image(image_input.clone(), imagga_authorization)?
+/// Tag an image with the local tagger on a blocking thread
+async fn tag_locally(
+    local_tagger: Arc<LocalTagger>,
+    decoded_image: Arc<UploadedImage>,
+) -> Result<Vec<String>, ServerError> {
+    let tags = tokio::task::spawn_blocking(move || local_tagger.tag_image(&decoded_image.image)).await??;
+    Ok(tags.iter().map(|tag| tag.name().to_owned()).collect())
+}
+
+/// Make the requests to Imagga needed for the analyses the user asked for.
+/// Any analysis that wasn't requested is left empty.
+fn analyze_image(
+    imagga_image: &ImaggaImage,
+    object_detection: bool,
+    mut categorizers: Vec<String>,
+    smart_crop: bool,
+    imagga_authorization: &str,
+    calls: &mut Vec<TaggerCall>,
+) -> Result<ImageAnalysis, ServerError> {
+    let tags = if object_detection {
+        get_tags_for_image(imagga_image, imagga_authorization, calls)?
     } else {
         // If no tags were requested, we use an empty tag list
         vec![]
     };
 
-    let image_id = execute_insert_image(image_input, tags, request.label, db).await?;
+    // Each requested categorizer is a separate call to Imagga. Duplicate
+    // categorizer ids are skipped since they would give the same categories.
+    categorizers.sort();
+    categorizers.dedup();
+    let mut categories = vec![];
+    for categorizer_id in categorizers {
+        categories.extend(get_categories_for_image(
+            imagga_image,
+            &categorizer_id,
+            imagga_authorization,
+            calls,
+        )?);
+    }
 
-    Ok(Json(query_image_by_id(image_id, db).await?))
+    // We ask Imagga where to crop so that the subject stays in frame, but we
+    // can still crop around the center ourselves if Imagga can't help us, so an
+    // error here shouldn't fail the whole request (the failed request is still
+    // recorded in `calls`).
+    let croppings = if smart_crop {
+        get_croppings_for_image(imagga_image, &crop_resolutions(), imagga_authorization, calls)
+            .unwrap_or_default()
+    } else {
+        // Images specified by URL are not stored by us, so we don't crop them
+        // (and uploads that we crop ourselves don't need any suggestions)
+        vec![]
+    };
+
+    Ok(ImageAnalysis {
+        tags,
+        categories,
+        croppings,
+        remote_file: None,
+        blurhash: None,
+        quality: None,
+    })
 }
 
 /// The route handler for the `GET /image/{imageId}` endpoint. Fetches the image and
 /// returns it as JSON, unless it doesn't exist, in which case it returns a 404.
+/// Private metadata is only included for internal users (see auth.rs).
 pub async fn get_image_by_id(
     Path(image_id): Path<i32>,
     Extension(ref db): Extension<DatabaseConnection>,
+    Extension(storage): Extension<Arc<dyn Storage>>,
+    Extension(ref quality_config): Extension<QualityConfig>,
+    InternalUser(internal_user): InternalUser,
 ) -> Result<axum::Json<ImageResult>, ServerError> {
-    Ok(Json(query_image_by_id(image_id, db).await?))
+    let mut image = query_image_by_id(image_id, db, storage.as_ref(), quality_config).await?;
+    if !internal_user {
+        image.hide_private_metadata();
+    }
+    Ok(Json(image))
+}
+
+/// The body of the `PUT /image/{imageId}/location` endpoint, in degrees
+/// (negative for south and west)
+#[derive(Deserialize)]
+pub struct LocationBody {
+    latitude: f64,
+    longitude: f64,
+}
+
+/// The route handler for the `PUT /image/{imageId}/location` endpoint, which sets where
+/// an image was taken (e.g. for images without GPS metadata, or with the wrong location).
+/// Returns the updated image, or a 404 if it doesn't exist.
+/// Like the rest of the private metadata, the location can only be set by internal users
+/// (anyone else gets a 403).
+pub async fn put_image_location(
+    Path(image_id): Path<i32>,
+    Extension(ref db): Extension<DatabaseConnection>,
+    Extension(storage): Extension<Arc<dyn Storage>>,
+    Extension(ref quality_config): Extension<QualityConfig>,
+    InternalUser(internal_user): InternalUser,
+    Json(body): Json<LocationBody>,
+) -> Result<Json<ImageResult>, ServerError> {
+    if !internal_user {
+        return Err(ServerError::new(
+            StatusCode::FORBIDDEN,
+            "Only internal users can set the location of an image".to_owned(),
+        ));
+    }
+    let (latitude, longitude) = check_location(body.latitude, body.longitude)?;
+    let image: image::ActiveModel = Image::find_by_id(image_id)
+        .one(db)
+        .await?
+        .ok_or_else(|| {
+            ServerError::new(
+                StatusCode::NOT_FOUND,
+                format!("No image found with id {image_id}"),
+            )
+        })?
+        .into();
+    image::ActiveModel {
+        latitude: Set(Some(latitude)),
+        longitude: Set(Some(longitude)),
+        ..image
+    }
+    .update(db)
+    .await?;
+
+    let image = query_image_by_id(image_id, db, storage.as_ref(), quality_config).await?;
+    Ok(Json(image))
+}
+
+/// Check that a latitude and longitude (in degrees) are on the map, or give a HTTP 400 error
+fn check_location(latitude: f64, longitude: f64) -> Result<(f64, f64), ServerError> {
+    if !(-90.0..=90.0).contains(&latitude) || !(-180.0..=180.0).contains(&longitude) {
+        return Err(ServerError::new(
+            StatusCode::BAD_REQUEST,
+            format!(
+                "The latitude must be between -90 and 90 and the longitude between -180 and 180, \
+                not {latitude} and {longitude}"
+            ),
+        ));
+    }
+    Ok((latitude, longitude))
+}
+
+/// The query parameters for the `GET /image/{imageId}/duplicates` endpoint.
+/// `max_distance` is how many bits of the perceptual hashes may differ (8 by default).
+#[derive(Deserialize)]
+pub struct DuplicatesQueryParams {
+    max_distance: Option<u32>,
+}
+
+/// The route handler for the `GET /image/{imageId}/duplicates` endpoint. Returns the
+/// ids of the images that look almost the same as the image (i.e. whose perceptual
+/// hashes are within `max_distance` bits of its hash), most similar first.
+pub async fn get_image_duplicates(
+    Path(image_id): Path<i32>,
+    Query(params): Query<DuplicatesQueryParams>,
+    Extension(ref db): Extension<DatabaseConnection>,
+) -> Result<Json<Vec<SimilarImage>>, ServerError> {
+    let max_distance = params.max_distance.unwrap_or(DUPLICATE_WARNING_DISTANCE);
+    if max_distance > 64 {
+        return Err(ServerError::new(
+            StatusCode::BAD_REQUEST,
+            "max_distance can be at most 64".to_owned(),
+        ));
+    }
+    let perceptual_hash = get_perceptual_hash(image_id, db).await?;
+    Ok(Json(
+        find_similar_images(perceptual_hash, max_distance, Some(image_id), db).await?,
+    ))
+}
+
+/// The query parameters for the `POST /images/search-by-image` endpoint.
+/// `limit` is the most results to return (10 by default, and at most 100).
+#[derive(Deserialize)]
+pub struct SearchQueryParams {
+    limit: Option<usize>,
+}
+
+/// The route handler for the `POST /images/search-by-image` endpoint. The body gives
+/// an image in any of the ways `POST /images` accepts, and the stored images that
+/// look most like it are returned, best match first (see search_image.rs).
+/// The image is validated (and downloaded, if it is given by URL) like a new image,
+/// but it isn't stored.
+pub async fn post_search_by_image(
+    Query(params): Query<SearchQueryParams>,
+    Extension(ref db): Extension<DatabaseConnection>,
+    Extension(storage): Extension<Arc<dyn Storage>>,
+    Extension(ref quality_config): Extension<QualityConfig>,
+    Extension(upload_limits): Extension<UploadLimits>,
+    Extension(mirror_config): Extension<MirrorConfig>,
+    Extension(url_policy): Extension<UrlPolicyConfig>,
+    InternalUser(internal_user): InternalUser,
+    SearchImage { source }: SearchImage,
+) -> Result<Json<Vec<SearchResult>>, ServerError> {
+    let limit = params.limit.unwrap_or(10);
+    if limit == 0 || limit > 100 {
+        return Err(ServerError::new(
+            StatusCode::BAD_REQUEST,
+            "limit must be between 1 and 100".to_owned(),
+        ));
+    }
+    let searched_image = match source {
+        // We need the image itself to compare it, so images given by URL are downloaded
+        NewImageSource::Url(url) => {
+            check_image_url(&url, &url_policy).await?;
+            mirror_image(url, upload_limits, mirror_config, url_policy).await?
+        }
+        NewImageSource::Base64(base64) => validate_base64_image(base64, upload_limits).await?,
+        NewImageSource::File(upload) => validate_image_file(upload, upload_limits).await?,
+    };
+    let mut results = search_by_image(&searched_image, limit, db, storage.as_ref(), quality_config).await?;
+    if !internal_user {
+        results.iter_mut().for_each(|result| result.hide_private_metadata());
+    }
+    Ok(Json(results))
 }
 
 /// The query parameters for the `GET /images` endpoint.
 /// `objects` is used for requesting images that contain all specified objects.
 /// `some_objects` is used for requesting images that contain some of the
 /// specified objects.
+/// `category` is used for requesting images that were assigned the given
+/// category (e.g. `interior_objects`) and can be combined with either of the above.
+/// `taken_after`, `taken_before` (dates like `2022-10-18` or `2022-10-18T07:28:00`) and
+/// `camera_model` filter images by their EXIF metadata, and can also be combined with the above.
+/// `bbox` (`minLon,minLat,maxLon,maxLat`) filters images by where they were taken, as does
+/// `near` (`lat,lon`) with `radius_km` (5 by default). Since locations are private metadata,
+/// these are only available to internal users (see auth.rs).
+/// `min_width`, `min_height`, `orientation` (`landscape`, `portrait` or `square`) and
+/// `aspect_ratio` (e.g. `16:9` or `1.5`) filter images by their dimensions.
+/// `min_sharpness` filters images by how sharp they are, and `sort=sharpness`
+/// returns the sharpest images first (see image_quality.rs).
 /// Neither query parameter is necessary, and if neither are provided, all
 /// images will be returned.
 /// However, passing both `objects` and `some_objects` query parameters is not
@@ -86,14 +529,31 @@ pub async fn get_image_by_id(
 #[derive(Deserialize)]
 pub struct GetImagesQueryParams {
     objects: Option<String>, // request images containing all objects in a comma-separated list
-    some_objects: Option<String> // request images containing 1+ objects in a comma separated list
+    some_objects: Option<String>, // request images containing 1+ objects in a comma separated list
+    category: Option<String>, // request images that belong to a category
+    taken_after: Option<String>, // request images taken on or after a date
+    taken_before: Option<String>, // request images taken on or before a date
+    camera_model: Option<String>, // request images taken with a camera model
+    bbox: Option<String>, // request images taken inside a bounding box
+    near: Option<String>, // request images taken near a point
+    radius_km: Option<f64>, // how near to the point (in kilometers)
+    min_width: Option<i32>, // request images at least this wide (in pixels)
+    min_height: Option<i32>, // request images at least this high (in pixels)
+    orientation: Option<Shape>, // request landscape, portrait or square images
+    aspect_ratio: Option<String>, // request images with an aspect ratio
+    min_sharpness: Option<f32>, // request images that are at least this sharp
+    sort: Option<ImageSort>, // the order to return the images in
 }
-/// The endpoint for the `GET /images` route (as well as with the `objects` and `some_objects`
+/// The endpoint for the `GET /images` route (as well as with the `objects`, `some_objects` and `category`
 /// query parameters, as per the GetImagesQueryParameters struct). Returns a JSON array of images
-/// that include a list of their associated tags.
+/// that include a list of their associated tags. Private metadata is only included for internal
+/// users (see auth.rs).
 pub async fn get_images(
     query_params: Query<GetImagesQueryParams>,
     Extension(ref db): Extension<DatabaseConnection>,
+    Extension(storage): Extension<Arc<dyn Storage>>,
+    Extension(ref quality_config): Extension<QualityConfig>,
+    InternalUser(internal_user): InternalUser,
 ) -> Result<axum::Json<Vec<ImageResult>>, ServerError> {
     let tag_filter = match (&query_params.objects, &query_params.some_objects) {
         (Some(objects_list), None) => {
@@ -108,5 +568,213 @@ pub async fn get_images(
         (Some(_), Some(_)) => Err(ServerError::new(StatusCode::BAD_REQUEST, 
             "Cannot specify both an objects list and a some_objects list".to_owned())),
     }?;
-    Ok(Json(query_images(tag_filter, db).await?))
+    let uses_location = query_params.bbox.is_some() || query_params.near.is_some();
+    if uses_location && !internal_user {
+        return Err(ServerError::new(
+            StatusCode::FORBIDDEN,
+            "Only internal users can filter images by location".to_owned(),
+        ));
+    }
+    let bbox = match &query_params.bbox {
+        Some(bbox) => {
+            let [min_longitude, min_latitude, max_longitude, max_latitude] =
+                parse_numbers_param("bbox", bbox)?;
+            check_location(min_latitude, min_longitude)?;
+            check_location(max_latitude, max_longitude)?;
+            if min_latitude > max_latitude {
+                return Err(ServerError::new(
+                    StatusCode::BAD_REQUEST,
+                    "The bbox's minimum latitude can't be above its maximum latitude".to_owned(),
+                ));
+            }
+            Some(BoundingBox {
+                min_longitude,
+                min_latitude,
+                max_longitude,
+                max_latitude,
+            })
+        }
+        None => None,
+    };
+    let near = match &query_params.near {
+        Some(near) => {
+            let [latitude, longitude] = parse_numbers_param("near", near)?;
+            let (latitude, longitude) = check_location(latitude, longitude)?;
+            let radius_km = query_params.radius_km.unwrap_or(5.0);
+            if !(radius_km.is_finite() && radius_km > 0.0) {
+                return Err(ServerError::new(
+                    StatusCode::BAD_REQUEST,
+                    "radius_km must be a positive number".to_owned(),
+                ));
+            }
+            Some(Near {
+                latitude,
+                longitude,
+                radius_km,
+            })
+        }
+        None => None,
+    };
+    let filters = ImageFilters {
+        category: query_params.category.clone(),
+        taken_after: parse_date_param("taken_after", &query_params.taken_after, false)?,
+        taken_before: parse_date_param("taken_before", &query_params.taken_before, true)?,
+        camera_model: query_params.camera_model.clone(),
+        bbox,
+        near,
+        min_width: query_params.min_width,
+        min_height: query_params.min_height,
+        shape: query_params.orientation,
+        aspect_ratio: parse_aspect_ratio(&query_params.aspect_ratio)?,
+        min_sharpness: query_params.min_sharpness,
+        sort: query_params.sort,
+        ..ImageFilters::default()
+    };
+    let mut images = query_images(tag_filter, filters, db, storage.as_ref(), quality_config).await?;
+    if !internal_user {
+        images.iter_mut().for_each(|image| image.hide_private_metadata());
+    }
+    Ok(Json(images))
+}
+
+/// Parse a date query parameter, which is either a date and time (e.g. `2022-10-18T07:28:00`)
+/// or just a date, in which case it means the start of the day (or the end of the day if
+/// `end_of_day` is true, so that e.g. `taken_before=2022-10-18` includes that whole day).
+/// Invalid dates give a HTTP 400 error.
+fn parse_date_param(
+    name: &str,
+    value: &Option<String>,
+    end_of_day: bool,
+) -> Result<Option<NaiveDateTime>, ServerError> {
+    let value = match value {
+        Some(value) => value,
+        None => return Ok(None),
+    };
+    if let Ok(date_time) = NaiveDateTime::parse_from_str(value, "%Y-%m-%dT%H:%M:%S") {
+        return Ok(Some(date_time));
+    }
+    let date = NaiveDate::parse_from_str(value, "%Y-%m-%d").map_err(|_| {
+        ServerError::new(
+            StatusCode::BAD_REQUEST,
+            format!("{name} should be a date like 2022-10-18 or 2022-10-18T07:28:00, not {value:?}"),
+        )
+    })?;
+    Ok(Some(if end_of_day {
+        date.and_hms(23, 59, 59)
+    } else {
+        date.and_hms(0, 0, 0)
+    }))
+}
+
+/// Parse a query parameter that is a comma-separated list of N numbers (e.g. `near=52.37,4.89`).
+/// Anything else gives a HTTP 400 error.
+fn parse_numbers_param<const N: usize>(name: &str, value: &str) -> Result<[f64; N], ServerError> {
+    let invalid = || {
+        ServerError::new(
+            StatusCode::BAD_REQUEST,
+            format!("{name} should be {N} comma-separated numbers, not {value:?}"),
+        )
+    };
+    let numbers: Vec<f64> = value
+        .split(',')
+        .map(|number| number.trim().parse().map_err(|_| invalid()))
+        .collect::<Result<_, _>>()?;
+    numbers.try_into().map_err(|_| invalid())
+}
+
+/// Parse an aspect ratio query parameter, which is either a ratio of two numbers
+/// (e.g. `16:9`) or a single number (e.g. `1.5`). Anything that isn't a positive
+/// ratio gives a HTTP 400 error.
+fn parse_aspect_ratio(value: &Option<String>) -> Result<Option<f64>, ServerError> {
+    let value = match value {
+        Some(value) => value,
+        None => return Ok(None),
+    };
+    let ratio = match value.split_once(':') {
+        Some((width, height)) => width
+            .trim()
+            .parse::<f64>()
+            .and_then(|width| Ok(width / height.trim().parse::<f64>()?)),
+        None => value.trim().parse(),
+    };
+    match ratio {
+        Ok(ratio) if ratio.is_finite() && ratio > 0.0 => Ok(Some(ratio)),
+        _ => Err(ServerError::new(
+            StatusCode::BAD_REQUEST,
+            format!("aspect_ratio should be like 16:9 or 1.5, not {value:?}"),
+        )),
+    }
+}
+
+/// The route handler for the `GET /admin/tagger/usage` endpoint. Returns how many
+/// requests we have made to Imagga per day and per month, along with our monthly
+/// budget and the remaining quota reported by Imagga.
+/// Only internal users can see this (anyone else gets a 403).
+pub async fn get_tagger_usage(
+    Extension(ref db): Extension<DatabaseConnection>,
+    Extension(ref tagger_budget): Extension<TaggerBudget>,
+    InternalUser(internal_user): InternalUser,
+) -> Result<Json<TaggerUsageReport>, ServerError> {
+    if !internal_user {
+        return Err(ServerError::new(
+            StatusCode::FORBIDDEN,
+            "Only internal users can see the tagger usage".to_owned(),
+        ));
+    }
+    Ok(Json(get_tagger_usage_report(tagger_budget, db).await?))
+}
+
+/// The route handler for the `GET /files/{key}` endpoint, which serves the files we
+/// store (e.g. uploaded images and their crops), optionally transformed according to
+/// the query parameters (e.g. `?w=400&h=300&fit=cover&format=webp&q=80`, see
+/// TransformParams). Returns a 404 if there is no such file.
+/// If a watermark is configured, it is drawn onto every file we serve (see watermark.rs),
+/// except for internal users who ask for `watermark=false` (anyone else gets a 403).
+/// Supports caching and range requests (see serve_file.rs).
+pub async fn get_file(
+    Path(key): Path<String>,
+    Query(params): Query<TransformParams>,
+    headers: HeaderMap,
+    Extension(storage): Extension<Arc<dyn Storage>>,
+    Extension(ref transform_config): Extension<TransformConfig>,
+    Extension(watermark): Extension<Option<Arc<Watermark>>>,
+    Extension(ref cache_size): Extension<CacheSize>,
+    InternalUser(internal_user): InternalUser,
+) -> Result<Response, ServerError> {
+    let skip_watermark = params.watermark == Some(false) && watermark.is_some();
+    if skip_watermark && !internal_user {
+        return Err(ServerError::new(
+            StatusCode::FORBIDDEN,
+            "Only internal users can fetch files without the watermark".to_owned(),
+        ));
+    }
+    let watermark = if skip_watermark { None } else { watermark };
+    let watermarked = watermark.is_some();
+    let file = get_transformed_file(&key, params, watermark, storage.as_ref(), transform_config, cache_size).await?;
+    Ok(file_response(&key, file, &headers, skip_watermark, watermarked))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn aspect_ratio(value: &str) -> Option<f64> {
+        parse_aspect_ratio(&Some(value.to_owned())).ok().flatten()
+    }
+
+    #[test]
+    fn aspect_ratios_can_be_ratios_or_numbers() {
+        assert_eq!(aspect_ratio("16:9"), Some(16.0 / 9.0));
+        assert_eq!(aspect_ratio(" 4 : 3 "), Some(4.0 / 3.0));
+        assert_eq!(aspect_ratio("1.5"), Some(1.5));
+        assert_eq!(aspect_ratio("1"), Some(1.0));
+        assert_eq!(parse_aspect_ratio(&None).ok(), Some(None));
+    }
+
+    #[test]
+    fn aspect_ratios_must_be_positive_and_finite() {
+        for value in ["0", "-1.5", "16:0", "0:9", "-16:9", "inf", "NaN", "", "16:9:1", "wide"] {
+            assert!(parse_aspect_ratio(&Some(value.to_owned())).is_err(), "{value:?} was accepted");
+        }
+    }
 }
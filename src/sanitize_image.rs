@@ -0,0 +1,382 @@
+use image::{DynamicImage, ImageFormat};
+use sha2::{Digest, Sha256};
+
+use crate::error::ServerError;
+use crate::upload_image::encode_in_format;
+use crate::validate_image::UploadedImage;
+
+// The JPEG segments and PNG and WebP chunks that hold metadata (EXIF, XMP and IPTC)
+static JPEG_METADATA_PREFIXES: [&[u8]; 4] = [
+    b"Exif\0",
+    b"http://ns.adobe.com/xap/1.0/\0",
+    b"http://ns.adobe.com/xmp/extension/\0",
+    b"Photoshop 3.0\0",
+];
+static PNG_METADATA_CHUNKS: [&[u8; 4]; 4] = [b"eXIf", b"tEXt", b"zTXt", b"iTXt"];
+static WEBP_METADATA_CHUNKS: [&[u8; 4]; 2] = [b"EXIF", b"XMP "];
+// The block size (11) and identifier of the GIF application extension that holds XMP
+static GIF_XMP_IDENTIFIER: &[u8; 12] = b"\x0BXMP DataXMP";
+
+/// Rotate and/or flip a decoded image according to its EXIF orientation (1 to 8),
+/// so that its pixels are upright. Phones usually store photos sideways and rely
+/// on this tag to display them, which we would otherwise ignore when cropping,
+/// resizing and hashing.
+pub fn apply_orientation(image: DynamicImage, orientation: Option<u32>) -> DynamicImage {
+    match orientation {
+        Some(2) => image.fliph(),
+        Some(3) => image.rotate180(),
+        Some(4) => image.flipv(),
+        Some(5) => image.rotate90().fliph(),
+        Some(6) => image.rotate90(),
+        Some(7) => image.rotate270().fliph(),
+        Some(8) => image.rotate270(),
+        _ => image,
+    }
+}
+
+/// Prepare the file of an uploaded image for storage, where it is served publicly:
+/// - images that have to be rotated to be upright (see `apply_orientation`) are
+///   re-encoded from their upright pixels, since their orientation tag would be lost
+///   along with the rest of their metadata
+/// - if `strip_metadata` is true, EXIF, XMP and IPTC metadata (e.g. where a photo was
+///   taken) are removed from the file, without re-encoding it where possible
+///
+/// The metadata we read from the original file (see exif_metadata.rs) is kept either way.
+/// The content hash is updated to match the file that will be stored.
+/// Re-encoding a file takes about as long as decoding it, so `sanitize` (in routes.rs)
+/// calls this on a blocking thread.
+pub fn sanitize_upload(uploaded_image: &mut UploadedImage, strip_metadata: bool) -> Result<(), ServerError> {
+    let rotated = matches!(
+        uploaded_image.exif.as_ref().and_then(|exif| exif.orientation),
+        Some(2..=8)
+    );
+    let bytes = if rotated {
+        Some(encode_in_format(&uploaded_image.image, uploaded_image.format)?)
+    } else if strip_metadata {
+        match remove_metadata(&uploaded_image.bytes, uploaded_image.format) {
+            Some(bytes) => Some(bytes),
+            // If we can't remove the metadata from the file, we make a new file without it
+            None => Some(encode_in_format(&uploaded_image.image, uploaded_image.format)?),
+        }
+    } else {
+        None
+    };
+    if let Some(bytes) = bytes {
+        uploaded_image.content_hash = format!("{:x}", Sha256::digest(&bytes));
+        uploaded_image.bytes = bytes;
+        // The uploaded file is no longer the file we will store
+        uploaded_image.temp_upload = None;
+    }
+    Ok(())
+}
+
+/// Remove the metadata from an image file without re-encoding it, or None if we
+/// can't (e.g. the file is malformed, or it is a TIFF, where the EXIF metadata
+/// is part of the file's structure). Formats that can't hold metadata are returned as-is.
+fn remove_metadata(bytes: &[u8], format: ImageFormat) -> Option<Vec<u8>> {
+    match format {
+        ImageFormat::Jpeg => remove_jpeg_metadata(bytes),
+        ImageFormat::Png => remove_png_metadata(bytes),
+        ImageFormat::WebP => remove_webp_metadata(bytes),
+        ImageFormat::Gif => remove_gif_metadata(bytes),
+        ImageFormat::Tiff => None,
+        _ => Some(bytes.to_vec()),
+    }
+}
+
+/// JPEGs are a list of segments, each starting with a 0xFF marker byte, its type and
+/// (for most types) its length. Metadata is stored in APP1 (EXIF and XMP) and APP13
+/// (IPTC) segments. The segments end with the compressed image data, which we copy as-is.
+/// Markers can be padded with any number of 0xFF fill bytes, which we drop. Gives None
+/// for anything unexpected (e.g. the end of the image before any image data).
+fn remove_jpeg_metadata(bytes: &[u8]) -> Option<Vec<u8>> {
+    if bytes.get(..2)? != [0xFF, 0xD8] {
+        return None;
+    }
+    let mut output = bytes[..2].to_vec(); // The start of image marker
+    let mut position = 2;
+    loop {
+        if *bytes.get(position)? != 0xFF {
+            return None;
+        }
+        while *bytes.get(position + 1)? == 0xFF {
+            position += 1;
+        }
+        let marker = bytes[position + 1];
+        match marker {
+            // Start of scan: the rest of the file is the image data
+            0xDA => {
+                output.extend_from_slice(&bytes[position..]);
+                return Some(output);
+            }
+            // TEM and RST0 to RST7 stand alone, without a length
+            0x01 | 0xD0..=0xD7 => {
+                output.extend_from_slice(&bytes[position..position + 2]);
+                position += 2;
+                continue;
+            }
+            // A stuffed 0x00 (which only belongs in image data), or another start or end of image
+            0x00 | 0xD8 | 0xD9 => return None,
+            _ => {}
+        }
+        let length = u16::from_be_bytes([*bytes.get(position + 2)?, *bytes.get(position + 3)?]) as usize;
+        if length < 2 {
+            return None;
+        }
+        let segment = bytes.get(position..position + 2 + length)?;
+        let payload = &segment[4..];
+        let is_metadata = matches!(marker, 0xE1 | 0xED)
+            && JPEG_METADATA_PREFIXES
+                .iter()
+                .any(|prefix| payload.starts_with(prefix));
+        if !is_metadata {
+            output.extend_from_slice(segment);
+        }
+        position += 2 + length;
+    }
+}
+
+/// PNGs are a signature followed by a list of chunks, each with its length, type,
+/// data and CRC. Metadata is stored in eXIf chunks and text chunks (which is where XMP goes).
+fn remove_png_metadata(bytes: &[u8]) -> Option<Vec<u8>> {
+    let mut output = bytes.get(..8)?.to_vec(); // The signature
+    let mut position = 8;
+    while position < bytes.len() {
+        let length = u32::from_be_bytes(bytes.get(position..position + 4)?.try_into().ok()?) as usize;
+        let chunk = bytes.get(position..position + 12 + length)?;
+        if !PNG_METADATA_CHUNKS.iter().any(|chunk_type| &chunk[4..8] == *chunk_type) {
+            output.extend_from_slice(chunk);
+        }
+        position += 12 + length;
+    }
+    Some(output)
+}
+
+/// GIFs are a header, a logical screen descriptor (optionally followed by a global color
+/// table), and then a list of blocks up to a trailer byte (0x3B). Each image is a block
+/// starting with 0x2C, and everything else (e.g. animation timing) is an extension block
+/// starting with 0x21. XMP metadata is stored in an application extension block. Both
+/// end with a list of sub-blocks. Gives None for anything unexpected.
+fn remove_gif_metadata(bytes: &[u8]) -> Option<Vec<u8>> {
+    // The header (e.g. "GIF89a") and the logical screen descriptor
+    let flags = *bytes.get(10)?;
+    let mut position = 13 + color_table_length(flags);
+    let mut output = bytes.get(..position)?.to_vec();
+    loop {
+        let start = position;
+        match *bytes.get(position)? {
+            // The trailer, after which there is nothing that we need
+            0x3B => {
+                output.push(0x3B);
+                return Some(output);
+            }
+            // An image descriptor, its local color table and its LZW minimum code size
+            0x2C => {
+                let flags = *bytes.get(position + 9)?;
+                position = skip_gif_sub_blocks(bytes, position + 11 + color_table_length(flags))?;
+                output.extend_from_slice(&bytes[start..position]);
+            }
+            0x21 => {
+                let label = *bytes.get(position + 1)?;
+                let is_xmp = label == 0xFF && bytes.get(position + 2..position + 14)? == GIF_XMP_IDENTIFIER;
+                position = skip_gif_sub_blocks(bytes, position + 2)?;
+                if !is_xmp {
+                    output.extend_from_slice(&bytes[start..position]);
+                }
+            }
+            _ => return None,
+        }
+    }
+}
+
+/// The length of the color table that follows a GIF descriptor with the given flags
+pub fn color_table_length(flags: u8) -> usize {
+    if flags & 0x80 != 0 {
+        3 << ((flags & 0x07) + 1)
+    } else {
+        0
+    }
+}
+
+/// Find the end of the list of sub-blocks starting at `position`. Each sub-block is its
+/// length (1 to 255) followed by its data, and the list ends with a sub-block of length 0.
+pub fn skip_gif_sub_blocks(bytes: &[u8], mut position: usize) -> Option<usize> {
+    loop {
+        let length = *bytes.get(position)? as usize;
+        position += 1 + length;
+        if length == 0 {
+            return Some(position);
+        }
+    }
+}
+
+/// WebPs are a RIFF file: a header with the size of the file, and then a list of chunks,
+/// each with its type, length and data (padded to an even length). Metadata is stored in
+/// EXIF and XMP chunks, whose presence is also flagged in the VP8X chunk.
+fn remove_webp_metadata(bytes: &[u8]) -> Option<Vec<u8>> {
+    let mut chunks = vec![];
+    let mut position = 12;
+    while position < bytes.len() {
+        let length = u32::from_le_bytes(bytes.get(position + 4..position + 8)?.try_into().ok()?) as usize;
+        let padded_length = length + length % 2;
+        let chunk = bytes.get(position..(position + 8 + padded_length).min(bytes.len()))?;
+        if !WEBP_METADATA_CHUNKS.iter().any(|chunk_type| &chunk[..4] == *chunk_type) {
+            let mut chunk = chunk.to_vec();
+            if &chunk[..4] == b"VP8X" && chunk.len() > 8 {
+                // Clear the EXIF (0x08) and XMP (0x04) flags
+                chunk[8] &= !(0x08 | 0x04);
+            }
+            chunks.extend(chunk);
+        }
+        position += 8 + padded_length;
+    }
+    let mut output = bytes.get(..12)?.to_vec(); // "RIFF", the size and "WEBP"
+    output[4..8].copy_from_slice(&(chunks.len() as u32 + 4).to_le_bytes());
+    output.extend(chunks);
+    Some(output)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use exif::experimental::Writer;
+    use exif::{Field, In, Tag, Value};
+    use image::{GenericImageView, ImageOutputFormat, RgbImage};
+
+    use super::*;
+    use crate::exif_metadata::read_exif;
+
+    fn encode(format: ImageOutputFormat) -> Vec<u8> {
+        let image = DynamicImage::ImageRgb8(RgbImage::from_fn(32, 24, |x, y| image::Rgb([x as u8 * 8, y as u8 * 8, 128])));
+        let mut bytes = vec![];
+        image.write_to(&mut bytes, format).unwrap();
+        bytes
+    }
+
+    /// EXIF metadata (a TIFF structure) saying where a photo was taken
+    fn gps_exif() -> Vec<u8> {
+        let fields = [
+            Field {
+                tag: Tag::GPSLatitudeRef,
+                ifd_num: In::PRIMARY,
+                value: Value::Ascii(vec![b"N".to_vec()]),
+            },
+            Field {
+                tag: Tag::GPSLatitude,
+                ifd_num: In::PRIMARY,
+                value: Value::Rational(vec![(52, 1).into(), (22, 1).into(), (23, 1).into()]),
+            },
+            Field {
+                tag: Tag::GPSLongitudeRef,
+                ifd_num: In::PRIMARY,
+                value: Value::Ascii(vec![b"E".to_vec()]),
+            },
+            Field {
+                tag: Tag::GPSLongitude,
+                ifd_num: In::PRIMARY,
+                value: Value::Rational(vec![(4, 1).into(), (53, 1).into(), (32, 1).into()]),
+            },
+        ];
+        let mut writer = Writer::new();
+        for field in &fields {
+            writer.push_field(field);
+        }
+        let mut tiff = Cursor::new(vec![]);
+        writer.write(&mut tiff, false).unwrap();
+        tiff.into_inner()
+    }
+
+    /// A JPEG with the given segments right after its start of image marker
+    fn jpeg_with(segments: &[u8]) -> Vec<u8> {
+        let jpeg = encode(ImageOutputFormat::Jpeg(90));
+        [&jpeg[..2], segments, &jpeg[2..]].concat()
+    }
+
+    fn exif_segment() -> Vec<u8> {
+        let payload = [b"Exif\0\0".as_slice(), &gps_exif()].concat();
+        let length = (payload.len() as u16 + 2).to_be_bytes();
+        [&[0xFF, 0xE1], length.as_slice(), &payload].concat()
+    }
+
+    fn crc32(bytes: &[u8]) -> u32 {
+        let mut crc = !0u32;
+        for byte in bytes {
+            crc ^= *byte as u32;
+            for _ in 0..8 {
+                crc = if crc & 1 != 0 { (crc >> 1) ^ 0xEDB8_8320 } else { crc >> 1 };
+            }
+        }
+        !crc
+    }
+
+    fn assert_decodes(bytes: &[u8]) {
+        let image = image::load_from_memory(bytes).unwrap();
+        assert_eq!((image.width(), image.height()), (32, 24));
+    }
+
+    #[test]
+    fn jpeg_gps_metadata_is_removed() {
+        let jpeg = jpeg_with(&exif_segment());
+        assert!(read_exif(&jpeg).and_then(|exif| exif.latitude).is_some());
+        let stripped = remove_metadata(&jpeg, ImageFormat::Jpeg).unwrap();
+        assert!(read_exif(&stripped).is_none());
+        assert_eq!(stripped.len(), jpeg.len() - exif_segment().len());
+        assert_decodes(&stripped);
+    }
+
+    #[test]
+    fn jpeg_fill_bytes_and_standalone_markers() {
+        // Fill bytes before the EXIF segment, and a TEM marker
+        let jpeg = jpeg_with(&[&[0xFF, 0xFF], exif_segment().as_slice(), &[0xFF, 0x01]].concat());
+        let stripped = remove_metadata(&jpeg, ImageFormat::Jpeg).unwrap();
+        assert!(read_exif(&stripped).is_none());
+        assert_eq!(&stripped[2..4], [0xFF, 0x01]);
+    }
+
+    #[test]
+    fn unexpected_jpeg_markers() {
+        let jpeg = encode(ImageOutputFormat::Jpeg(90));
+        assert!(remove_metadata(&jpeg_with(&[0xFF, 0x00]), ImageFormat::Jpeg).is_none());
+        assert!(remove_metadata(&jpeg_with(&[0xFF, 0xD9]), ImageFormat::Jpeg).is_none());
+        assert!(remove_metadata(&jpeg_with(&[0xFF, 0xE0, 0x00, 0x01]), ImageFormat::Jpeg).is_none());
+        assert!(remove_metadata(&jpeg[2..], ImageFormat::Jpeg).is_none());
+        assert!(remove_metadata(&jpeg[..100], ImageFormat::Jpeg).is_none());
+    }
+
+    #[test]
+    fn png_gps_metadata_is_removed() {
+        let png = encode(ImageOutputFormat::Png);
+        let data = [b"eXIf".as_slice(), &gps_exif()].concat();
+        let chunk = [
+            (data.len() as u32 - 4).to_be_bytes().as_slice(),
+            &data,
+            &crc32(&data).to_be_bytes(),
+        ]
+        .concat();
+        // After the signature (8 bytes) and the IHDR chunk (25 bytes)
+        let png = [&png[..33], chunk.as_slice(), &png[33..]].concat();
+        assert!(read_exif(&png).and_then(|exif| exif.latitude).is_some());
+        let stripped = remove_metadata(&png, ImageFormat::Png).unwrap();
+        assert!(read_exif(&stripped).is_none());
+        assert_eq!(stripped.len(), png.len() - chunk.len());
+        assert_decodes(&stripped);
+    }
+
+    #[test]
+    fn gif_xmp_metadata_is_removed() {
+        let gif = encode(ImageOutputFormat::Gif);
+        assert_eq!(remove_metadata(&gif, ImageFormat::Gif).unwrap(), gif);
+        // An XMP packet, followed by the "magic trailer" that XMP adds to GIFs
+        let mut extension = [&[0x21, 0xFF], GIF_XMP_IDENTIFIER.as_slice(), b"<x:xmpmeta xmlns:x=\"adobe:ns:meta/\"/>"].concat();
+        extension.push(0x01);
+        extension.extend((0..=255).rev());
+        extension.push(0x00);
+        let with_xmp = [&gif[..gif.len() - 1], extension.as_slice(), &[0x3B]].concat();
+        assert_decodes(&with_xmp);
+        let stripped = remove_metadata(&with_xmp, ImageFormat::Gif).unwrap();
+        assert_eq!(stripped, gif);
+        assert_decodes(&stripped);
+        assert!(remove_metadata(&gif[..gif.len() - 1], ImageFormat::Gif).is_none());
+    }
+}
@@ -0,0 +1,149 @@
+use std::cmp::Ordering;
+use std::collections::HashMap;
+
+use entity::image;
+use entity::prelude::*;
+use ::image::{imageops::FilterType, DynamicImage};
+use sea_orm::{ColumnTrait, DatabaseConnection, EntityTrait, QueryFilter};
+use serde::Serialize;
+
+use crate::config::QualityConfig;
+use crate::error::ServerError;
+use crate::perceptual_hash::find_similar_images;
+use crate::query_images::{query_images, ImageFilters, ImageResult, TagFilter};
+use crate::storage::Storage;
+use crate::validate_image::UploadedImage;
+
+// Each color channel is split into this many bins, for BINS_PER_CHANNEL^3 bins in total
+static BINS_PER_CHANNEL: usize = 4;
+// Histograms are computed on the image shrunk to fit in this many pixels wide and high,
+// which is plenty for counting colors
+static HISTOGRAM_SIZE: u32 = 64;
+// Only images whose perceptual hashes are within this many bits of the searched
+// image's hash are considered at all. Unrelated images are usually around 32 bits apart.
+static SEARCH_MAX_DISTANCE: u32 = 24;
+
+/// A stored image that looks like the image that was searched for
+#[derive(Serialize)]
+pub struct SearchResult {
+    // How similar the images are overall, from 0 to 1 (the average of the two scores below)
+    score: f32,
+    // The number of bits that differ between the perceptual hashes (0 to 64)
+    distance: i32,
+    // How much the colors of the images overlap, from 0 to 1
+    color_similarity: f32,
+    image: ImageResult,
+}
+
+impl SearchResult {
+    /// See `ImageResult::hide_private_metadata`
+    pub fn hide_private_metadata(&mut self) {
+        self.image.hide_private_metadata();
+    }
+}
+
+/// Compute the color histogram of an image: the share of its (non-transparent) pixels
+/// that fall into each color bin, so that the bins add up to 1. Images of different
+/// sizes can be compared by their histograms.
+/// Every pixel is looked at, so like the perceptual hash, it is computed while
+/// validating an image (see validate_image.rs).
+pub fn color_histogram(image: &DynamicImage) -> Vec<f32> {
+    let pixels = image
+        .resize(HISTOGRAM_SIZE, HISTOGRAM_SIZE, FilterType::Triangle)
+        .to_rgba8();
+    let mut histogram = vec![0.0; BINS_PER_CHANNEL.pow(3)];
+    let bin = |value: u8| value as usize * BINS_PER_CHANNEL / 256;
+    let mut counted = 0;
+    for pixel in pixels.pixels().filter(|pixel| pixel[3] > 0) {
+        let [red, green, blue, _] = pixel.0;
+        histogram[(bin(red) * BINS_PER_CHANNEL + bin(green)) * BINS_PER_CHANNEL + bin(blue)] += 1.0;
+        counted += 1;
+    }
+    if counted > 0 {
+        for share in histogram.iter_mut() {
+            *share /= counted as f32;
+        }
+    }
+    histogram
+}
+
+/// How much two color histograms overlap (i.e. their intersection), from 0 for
+/// images with no colors in common to 1 for images with the same colors
+fn color_similarity(histogram: &[f32], other: &[f32]) -> f32 {
+    histogram
+        .iter()
+        .zip(other)
+        .map(|(share, other_share)| share.min(*other_share))
+        .sum()
+}
+
+/// Find the stored images that look most like the given image (which isn't stored),
+/// best match first. Images are first narrowed down by their perceptual hash, and then
+/// ranked by the average of how close their hashes are and how similar their colors are.
+/// Images without a color histogram are ranked by their hash alone.
+pub async fn search_by_image(
+    searched_image: &UploadedImage,
+    limit: usize,
+    db: &DatabaseConnection,
+    storage: &dyn Storage,
+    quality_config: &QualityConfig,
+) -> Result<Vec<SearchResult>, ServerError> {
+    let candidates = find_similar_images(
+        searched_image.perceptual_hash,
+        SEARCH_MAX_DISTANCE,
+        None,
+        db,
+    )
+    .await?;
+    let candidate_ids: Vec<i32> = candidates.iter().map(|candidate| candidate.id).collect();
+    let histograms: HashMap<i32, Vec<f32>> = Image::find()
+        .filter(image::Column::Id.is_in(candidate_ids))
+        .all(db)
+        .await?
+        .into_iter()
+        .filter_map(|image| {
+            let histogram = serde_json::from_value(image.color_histogram?).ok()?;
+            Some((image.id, histogram))
+        })
+        .collect();
+
+    // (id, distance, color similarity, score)
+    let mut ranked: Vec<(i32, i32, f32, f32)> = candidates
+        .iter()
+        .map(|candidate| {
+            let hash_similarity = 1.0 - candidate.distance as f32 / 64.0;
+            match histograms.get(&candidate.id) {
+                Some(histogram) => {
+                    let color_similarity = color_similarity(&searched_image.color_histogram, histogram);
+                    let score = (hash_similarity + color_similarity) / 2.0;
+                    (candidate.id, candidate.distance, color_similarity, score)
+                }
+                None => (candidate.id, candidate.distance, 0.0, hash_similarity),
+            }
+        })
+        .collect();
+    ranked.sort_by(|a, b| b.3.partial_cmp(&a.3).unwrap_or(Ordering::Equal));
+    ranked.truncate(limit);
+
+    // Fetch the ranked images all at once and put them back in order
+    let filters = ImageFilters {
+        ids: Some(ranked.iter().map(|(id, ..)| *id).collect()),
+        ..ImageFilters::default()
+    };
+    let mut images: HashMap<i32, ImageResult> = query_images(TagFilter::None, filters, db, storage, quality_config)
+        .await?
+        .into_iter()
+        .map(|image| (image.id, image))
+        .collect();
+    Ok(ranked
+        .into_iter()
+        .filter_map(|(id, distance, color_similarity, score)| {
+            Some(SearchResult {
+                score,
+                distance,
+                color_similarity,
+                image: images.remove(&id)?,
+            })
+        })
+        .collect())
+}
@@ -0,0 +1,267 @@
+use axum::{
+    http::{
+        header::{
+            ACCEPT_RANGES, CACHE_CONTROL, CONTENT_RANGE, CONTENT_TYPE, ETAG, IF_MODIFIED_SINCE,
+            IF_NONE_MATCH, IF_RANGE, LAST_MODIFIED, RANGE,
+        },
+        HeaderMap, HeaderValue, StatusCode,
+    },
+    response::{IntoResponse, Response},
+};
+use chrono::{DateTime, Utc};
+use sha2::{Digest, Sha256};
+
+use crate::transform_image::ServedFile;
+
+// Files whose key is the hash of their content never change, so they can be cached forever
+static IMMUTABLE_CACHE_CONTROL: &str = "public, max-age=31536000, immutable";
+// Other files can be cached, but clients should check that they are still current (which
+// is cheap thanks to the ETag)
+static REVALIDATE_CACHE_CONTROL: &str = "public, no-cache";
+// Files only some clients may have (e.g. without our watermark) mustn't be kept in shared caches
+static PRIVATE_CACHE_CONTROL: &str = "private, no-cache";
+
+/// Build the response for a file served from `GET /files/{key}`, with the headers
+/// that let clients and proxies cache it (`ETag`, `Last-Modified` and `Cache-Control`).
+/// Conditional requests (`If-None-Match` and `If-Modified-Since`) for a file the
+/// client already has get a 304 with no body, and `Range` requests get just the
+/// bytes they asked for (a 206, or a 416 if the range is outside of the file).
+/// `private` files may only be cached by the client that asked for them, and
+/// `watermarked` files change along with the watermark, even if their key doesn't.
+pub fn file_response(
+    key: &str,
+    file: ServedFile,
+    request_headers: &HeaderMap,
+    private: bool,
+    watermarked: bool,
+) -> Response {
+    // A strong ETag, since the same bytes always give the same hash
+    let etag = format!("\"{:x}\"", Sha256::digest(&file.bytes));
+    let last_modified = file.last_modified.map(http_date);
+
+    let mut headers = HeaderMap::new();
+    headers.insert(ETAG, header_value(&etag));
+    if let Some(last_modified) = &last_modified {
+        headers.insert(LAST_MODIFIED, header_value(last_modified));
+    }
+    let cache_control = if private {
+        PRIVATE_CACHE_CONTROL
+    } else if is_content_addressed(key) && !watermarked {
+        IMMUTABLE_CACHE_CONTROL
+    } else {
+        REVALIDATE_CACHE_CONTROL
+    };
+    headers.insert(CACHE_CONTROL, header_value(cache_control));
+    headers.insert(ACCEPT_RANGES, header_value("bytes"));
+
+    if is_not_modified(request_headers, &etag, file.last_modified) {
+        return (StatusCode::NOT_MODIFIED, headers).into_response();
+    }
+
+    headers.insert(CONTENT_TYPE, header_value(&file.content_type));
+    let length = file.bytes.len();
+    let range = header_str(request_headers, RANGE.as_str())
+        .filter(|_| if_range_matches(request_headers, &etag, last_modified.as_deref()));
+    match range.map(|range| parse_range(range, length)) {
+        Some(Ok(Some((start, end)))) => {
+            headers.insert(
+                CONTENT_RANGE,
+                header_value(&format!("bytes {start}-{end}/{length}")),
+            );
+            (StatusCode::PARTIAL_CONTENT, headers, file.bytes[start..=end].to_vec()).into_response()
+        }
+        Some(Err(())) => {
+            headers.insert(CONTENT_RANGE, header_value(&format!("bytes */{length}")));
+            (StatusCode::RANGE_NOT_SATISFIABLE, headers).into_response()
+        }
+        // No range, or one we don't support (e.g. several ranges), so send the whole file
+        Some(Ok(None)) | None => (StatusCode::OK, headers, file.bytes).into_response(),
+    }
+}
+
+/// Whether the key is named after the SHA-256 hash of the file's content
+/// (e.g. `<64 hex digits>.png`, or `<64 hex digits>_square.png` for files derived from it)
+fn is_content_addressed(key: &str) -> bool {
+    let stem = key.split(['.', '_']).next().unwrap_or_default();
+    stem.len() == 64 && stem.bytes().all(|byte| byte.is_ascii_hexdigit())
+}
+
+/// Whether the client already has the current version of the file. `If-None-Match`
+/// takes precedence over `If-Modified-Since` when both are given.
+fn is_not_modified(request_headers: &HeaderMap, etag: &str, last_modified: Option<DateTime<Utc>>) -> bool {
+    if let Some(if_none_match) = header_str(request_headers, IF_NONE_MATCH.as_str()) {
+        // A list of ETags, which (unlike If-Range) are compared weakly
+        return if_none_match
+            .split(',')
+            .map(|tag| tag.trim())
+            .any(|tag| tag == "*" || tag.trim_start_matches("W/") == etag);
+    }
+    match (header_str(request_headers, IF_MODIFIED_SINCE.as_str()), last_modified) {
+        (Some(if_modified_since), Some(last_modified)) => match parse_http_date(if_modified_since) {
+            // HTTP dates don't have fractions of a second
+            Some(since) => last_modified.timestamp() <= since.timestamp(),
+            None => false,
+        },
+        _ => false,
+    }
+}
+
+/// Whether a `Range` request should be honored: if it has an `If-Range` header, that must
+/// exactly match the current ETag or Last-Modified date, otherwise the whole file is sent
+fn if_range_matches(request_headers: &HeaderMap, etag: &str, last_modified: Option<&str>) -> bool {
+    match header_str(request_headers, IF_RANGE.as_str()) {
+        Some(if_range) => if_range == etag || Some(if_range) == last_modified,
+        None => true,
+    }
+}
+
+/// Parse a `Range` header with a single range of bytes (e.g. `bytes=0-99`, `bytes=100-`
+/// or `bytes=-100` for the last 100 bytes) into the first and last byte it covers.
+/// Gives None for ranges we don't support, and an error if the range is outside of the file.
+fn parse_range(range: &str, length: usize) -> Result<Option<(usize, usize)>, ()> {
+    let spec = match range.trim().strip_prefix("bytes=") {
+        Some(spec) if !spec.contains(',') => spec.trim(),
+        _ => return Ok(None),
+    };
+    let (start, end) = match spec.split_once('-') {
+        Some(bounds) => bounds,
+        None => return Ok(None),
+    };
+    let (start, end) = match (start.parse::<usize>(), end.parse::<usize>()) {
+        (Ok(start), Ok(end)) if start <= end => (start, end.min(length.saturating_sub(1))),
+        (Ok(start), Err(_)) if end.is_empty() => (start, length.saturating_sub(1)),
+        (Err(_), Ok(suffix)) if start.is_empty() && suffix > 0 => {
+            (length.saturating_sub(suffix), length.saturating_sub(1))
+        }
+        _ => return Ok(None),
+    };
+    if start >= length {
+        return Err(());
+    }
+    Ok(Some((start, end)))
+}
+
+/// Format a date the way HTTP headers expect (e.g. `Tue, 18 Oct 2022 07:28:00 GMT`)
+fn http_date(date: DateTime<Utc>) -> String {
+    date.format("%a, %d %b %Y %H:%M:%S GMT").to_string()
+}
+
+fn parse_http_date(date: &str) -> Option<DateTime<Utc>> {
+    DateTime::parse_from_rfc2822(date)
+        .ok()
+        .map(|date| date.with_timezone(&Utc))
+}
+
+fn header_str<'a>(headers: &'a HeaderMap, name: &str) -> Option<&'a str> {
+    headers.get(name).and_then(|value| value.to_str().ok())
+}
+
+/// All of the header values we send are ASCII that we generate ourselves, so they are always valid
+fn header_value(value: &str) -> HeaderValue {
+    HeaderValue::from_str(value).expect("Invalid header value")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    static ETAG: &str = "\"abc123\"";
+
+    fn date(date: &str) -> DateTime<Utc> {
+        DateTime::parse_from_rfc3339(date).unwrap().with_timezone(&Utc)
+    }
+
+    fn headers(pairs: &[(&'static str, &str)]) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        for (name, value) in pairs {
+            headers.insert(*name, header_value(value));
+        }
+        headers
+    }
+
+    #[test]
+    fn closed_ranges_are_clamped_to_the_file() {
+        assert_eq!(parse_range("bytes=0-99", 1000), Ok(Some((0, 99))));
+        assert_eq!(parse_range("bytes=900-1999", 1000), Ok(Some((900, 999))));
+        assert_eq!(parse_range("bytes=5-5", 1000), Ok(Some((5, 5))));
+    }
+
+    #[test]
+    fn open_ranges_run_to_the_end_of_the_file() {
+        assert_eq!(parse_range("bytes=100-", 1000), Ok(Some((100, 999))));
+        assert_eq!(parse_range("bytes=0-", 1), Ok(Some((0, 0))));
+    }
+
+    #[test]
+    fn suffix_ranges_are_the_last_bytes_of_the_file() {
+        assert_eq!(parse_range("bytes=-100", 1000), Ok(Some((900, 999))));
+        // A suffix longer than the file is the whole file
+        assert_eq!(parse_range("bytes=-5000", 1000), Ok(Some((0, 999))));
+    }
+
+    #[test]
+    fn ranges_outside_of_the_file_are_unsatisfiable() {
+        assert_eq!(parse_range("bytes=1000-", 1000), Err(()));
+        assert_eq!(parse_range("bytes=1000-1099", 1000), Err(()));
+        assert_eq!(parse_range("bytes=0-", 0), Err(()));
+    }
+
+    #[test]
+    fn unsupported_ranges_are_ignored() {
+        assert_eq!(parse_range("bytes=0-99,200-299", 1000), Ok(None));
+        assert_eq!(parse_range("items=0-9", 1000), Ok(None));
+        assert_eq!(parse_range("bytes=99-0", 1000), Ok(None));
+        assert_eq!(parse_range("bytes=abc", 1000), Ok(None));
+        assert_eq!(parse_range("bytes=-", 1000), Ok(None));
+    }
+
+    #[test]
+    fn matching_etags_are_not_modified() {
+        assert!(is_not_modified(&headers(&[("if-none-match", ETAG)]), ETAG, None));
+        assert!(is_not_modified(&headers(&[("if-none-match", "*")]), ETAG, None));
+        // ETags in If-None-Match are compared weakly
+        assert!(is_not_modified(&headers(&[("if-none-match", "W/\"abc123\"")]), ETAG, None));
+        assert!(!is_not_modified(&headers(&[("if-none-match", "\"other\"")]), ETAG, None));
+    }
+
+    #[test]
+    fn any_etag_in_an_if_none_match_list_matches() {
+        let list = headers(&[("if-none-match", "\"first\", \"abc123\" ,W/\"third\"")]);
+        assert!(is_not_modified(&list, ETAG, None));
+        let list = headers(&[("if-none-match", "\"first\", \"second\"")]);
+        assert!(!is_not_modified(&list, ETAG, None));
+    }
+
+    #[test]
+    fn if_modified_since_compares_whole_seconds() {
+        let last_modified = date("2022-10-18T07:28:00.500Z");
+        let since = |date: &str| headers(&[("if-modified-since", date)]);
+        assert!(is_not_modified(&since("Tue, 18 Oct 2022 07:28:00 GMT"), ETAG, Some(last_modified)));
+        assert!(is_not_modified(&since("Wed, 19 Oct 2022 00:00:00 GMT"), ETAG, Some(last_modified)));
+        assert!(!is_not_modified(&since("Tue, 18 Oct 2022 07:27:59 GMT"), ETAG, Some(last_modified)));
+        assert!(!is_not_modified(&since("not a date"), ETAG, Some(last_modified)));
+        assert!(!is_not_modified(&since("Tue, 18 Oct 2022 07:28:00 GMT"), ETAG, None));
+    }
+
+    #[test]
+    fn if_none_match_takes_precedence_over_if_modified_since() {
+        let last_modified = date("2022-10-18T07:28:00Z");
+        let both = headers(&[
+            ("if-none-match", "\"other\""),
+            ("if-modified-since", "Wed, 19 Oct 2022 00:00:00 GMT"),
+        ]);
+        assert!(!is_not_modified(&both, ETAG, Some(last_modified)));
+    }
+
+    #[test]
+    fn if_range_must_match_exactly() {
+        let last_modified = "Tue, 18 Oct 2022 07:28:00 GMT";
+        assert!(if_range_matches(&headers(&[]), ETAG, Some(last_modified)));
+        assert!(if_range_matches(&headers(&[("if-range", ETAG)]), ETAG, Some(last_modified)));
+        assert!(if_range_matches(&headers(&[("if-range", last_modified)]), ETAG, Some(last_modified)));
+        // Weak ETags never match If-Range
+        assert!(!if_range_matches(&headers(&[("if-range", "W/\"abc123\"")]), ETAG, Some(last_modified)));
+        assert!(!if_range_matches(&headers(&[("if-range", "\"other\"")]), ETAG, Some(last_modified)));
+        assert!(!if_range_matches(&headers(&[("if-range", last_modified)]), ETAG, None));
+    }
+}
@@ -0,0 +1,224 @@
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use axum::http::StatusCode;
+use chrono::{DateTime, Utc};
+use s3::{bucket::Bucket, creds::Credentials, region::Region};
+
+use crate::config::{Config, StorageBackend, StorageConfig};
+use crate::error::ServerError;
+
+/// Somewhere we can store uploaded files (and files derived from them, such as
+/// crops). Files are identified by a key, which is just a filename (e.g. `1.png`).
+/// Only keys are stored in the database, so that the URLs we give out can change
+/// (e.g. when moving to a new domain).
+/// Multiple replicas of the API can share images by sharing a storage backend.
+#[async_trait]
+pub trait Storage: Send + Sync {
+    /// Store the file, replacing any existing file with the same key
+    async fn put(&self, key: &str, bytes: Vec<u8>, content_type: &str) -> Result<(), ServerError>;
+    /// Store the contents of a local file in the same way as `put`, without reading
+    /// all of it into memory
+    async fn put_file(&self, key: &str, path: &Path, content_type: &str) -> Result<(), ServerError>;
+    /// Fetch the file, or None if there is no file with that key
+    async fn get(&self, key: &str) -> Result<Option<Vec<u8>>, ServerError>;
+    /// Delete the file. Deleting a file that doesn't exist is not an error.
+    async fn delete(&self, key: &str) -> Result<(), ServerError>;
+    /// When the file was last stored, or None if there is no file with that key
+    async fn last_modified(&self, key: &str) -> Result<Option<DateTime<Utc>>, ServerError>;
+    /// The URL clients can use to access the file
+    fn url(&self, key: &str) -> String;
+}
+
+/// Choose the storage backend from the config, which can be `local` (the default)
+/// or `s3`. See README.md for the settings each backend needs. Local storage uses
+/// the upload directory from the config.
+/// Like `get_imagga_authorization`, this panics if the configuration is invalid
+/// so that the problem is noticed on startup.
+pub fn get_storage(config: &Config) -> Arc<dyn Storage> {
+    match config.storage.backend {
+        StorageBackend::Local => Arc::new(LocalStorage {
+            dir: config.upload_dir.clone(),
+            base_url: config.files_url(),
+        }),
+        StorageBackend::S3 => Arc::new(S3Storage::from_config(&config.storage, config.files_url())),
+    }
+}
+
+/// Only allow keys that are plain filenames, so that a key can't be used to
+/// reach files outside of the storage (e.g. `../secrets`)
+pub fn check_key(key: &str) -> Result<(), ServerError> {
+    if key.is_empty() || key.starts_with('.') || key.contains(['/', '\\']) {
+        return Err(ServerError::new(
+            StatusCode::BAD_REQUEST,
+            format!("Invalid file name: {key:?}"),
+        ));
+    }
+    Ok(())
+}
+
+/// Stores files in a directory on this machine, which are served by us under `base_url`.
+pub struct LocalStorage {
+    dir: PathBuf,
+    base_url: String,
+}
+
+#[async_trait]
+impl Storage for LocalStorage {
+    async fn put(&self, key: &str, bytes: Vec<u8>, _content_type: &str) -> Result<(), ServerError> {
+        check_key(key)?;
+        tokio::fs::create_dir_all(&self.dir).await?;
+        tokio::fs::write(self.dir.join(key), bytes).await?;
+        Ok(())
+    }
+
+    async fn put_file(&self, key: &str, path: &Path, _content_type: &str) -> Result<(), ServerError> {
+        check_key(key)?;
+        tokio::fs::create_dir_all(&self.dir).await?;
+        tokio::fs::copy(path, self.dir.join(key)).await?;
+        Ok(())
+    }
+
+    async fn get(&self, key: &str) -> Result<Option<Vec<u8>>, ServerError> {
+        check_key(key)?;
+        match tokio::fs::read(self.dir.join(key)).await {
+            Ok(bytes) => Ok(Some(bytes)),
+            Err(err) if err.kind() == ErrorKind::NotFound => Ok(None),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    async fn delete(&self, key: &str) -> Result<(), ServerError> {
+        check_key(key)?;
+        match tokio::fs::remove_file(self.dir.join(key)).await {
+            Err(err) if err.kind() != ErrorKind::NotFound => Err(err.into()),
+            _ => Ok(()),
+        }
+    }
+
+    async fn last_modified(&self, key: &str) -> Result<Option<DateTime<Utc>>, ServerError> {
+        check_key(key)?;
+        match tokio::fs::metadata(self.dir.join(key)).await {
+            Ok(metadata) => Ok(Some(metadata.modified()?.into())),
+            Err(err) if err.kind() == ErrorKind::NotFound => Ok(None),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    fn url(&self, key: &str) -> String {
+        format!("{}/{key}", self.base_url)
+    }
+}
+
+/// Stores files in a bucket of an S3-compatible service (e.g. AWS S3 or MinIO).
+/// Like local files, they are served by us (under `base_url`) rather than by that
+/// service, so that they get our watermark and caching headers (see `get_file`).
+pub struct S3Storage {
+    bucket: Bucket,
+    base_url: String,
+}
+
+impl S3Storage {
+    /// Configure the bucket using the S3 settings in the storage config
+    fn from_config(config: &StorageConfig, base_url: String) -> S3Storage {
+        let bucket_name = config.bucket.clone().expect("Missing S3_BUCKET setting (see README.md)");
+        let region_name = config.region.clone().unwrap_or_else(|| "us-east-1".to_owned());
+        // A custom endpoint is needed for services other than AWS (e.g. http://localhost:9000 for MinIO)
+        let region = match &config.endpoint {
+            Some(endpoint) => Region::Custom {
+                region: region_name,
+                endpoint: endpoint.clone(),
+            },
+            None => region_name.parse().expect("Invalid S3_REGION"),
+        };
+        let credentials = Credentials::new(
+            config.access_key.as_deref(),
+            config.secret_key.as_deref(),
+            None,
+            None,
+            None,
+        )
+        .expect("Invalid S3 credentials");
+        // Path-style URLs (e.g. http://localhost:9000/bucket/key) work with
+        // every S3-compatible service, unlike bucket subdomains
+        let bucket = Bucket::new(&bucket_name, region, credentials)
+            .expect("Invalid S3 bucket configuration")
+            .with_path_style();
+        S3Storage { bucket, base_url }
+    }
+}
+
+/// Turn an unsuccessful response from the S3-compatible service into a ServerError
+fn s3_error(action: &str, key: &str, status: u16) -> ServerError {
+    ServerError::new(
+        StatusCode::INTERNAL_SERVER_ERROR,
+        format!("Unable to {action} {key}: storage responded with status {status}"),
+    )
+}
+
+#[async_trait]
+impl Storage for S3Storage {
+    async fn put(&self, key: &str, bytes: Vec<u8>, content_type: &str) -> Result<(), ServerError> {
+        check_key(key)?;
+        let response = self
+            .bucket
+            .put_object_with_content_type(key, &bytes, content_type)
+            .await?;
+        match response.status_code() {
+            200..=299 => Ok(()),
+            status => Err(s3_error("store", key, status)),
+        }
+    }
+
+    async fn put_file(&self, key: &str, path: &Path, content_type: &str) -> Result<(), ServerError> {
+        check_key(key)?;
+        let mut file = tokio::fs::File::open(path).await?;
+        let status = self
+            .bucket
+            .put_object_stream_with_content_type(&mut file, key, content_type)
+            .await?;
+        match status {
+            200..=299 => Ok(()),
+            status => Err(s3_error("store", key, status)),
+        }
+    }
+
+    async fn get(&self, key: &str) -> Result<Option<Vec<u8>>, ServerError> {
+        check_key(key)?;
+        let response = self.bucket.get_object(key).await?;
+        match response.status_code() {
+            200..=299 => Ok(Some(response.bytes().to_vec())),
+            404 => Ok(None),
+            status => Err(s3_error("fetch", key, status)),
+        }
+    }
+
+    async fn delete(&self, key: &str) -> Result<(), ServerError> {
+        check_key(key)?;
+        let response = self.bucket.delete_object(key).await?;
+        match response.status_code() {
+            200..=299 | 404 => Ok(()),
+            status => Err(s3_error("delete", key, status)),
+        }
+    }
+
+    async fn last_modified(&self, key: &str) -> Result<Option<DateTime<Utc>>, ServerError> {
+        check_key(key)?;
+        let (head, status) = self.bucket.head_object(key).await?;
+        match status {
+            // S3 gives the date in the same format as HTTP headers (e.g. `Tue, 18 Oct 2022 07:28:00 GMT`)
+            200..=299 => Ok(head
+                .last_modified
+                .and_then(|date| DateTime::parse_from_rfc2822(&date).ok())
+                .map(|date| date.with_timezone(&Utc))),
+            404 => Ok(None),
+            status => Err(s3_error("check", key, status)),
+        }
+    }
+
+    fn url(&self, key: &str) -> String {
+        format!("{}/{key}", self.base_url)
+    }
+}
@@ -0,0 +1,253 @@
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+
+use axum::http::StatusCode;
+use chrono::{Datelike, TimeZone, Utc};
+use entity::tagger_call;
+use migration::{Alias, Expr, Order, Query};
+use sea_orm::{
+    ActiveValue::NotSet, ColumnTrait, ConnectionTrait, DatabaseConnection, EntityTrait,
+    FromQueryResult, PaginatorTrait, QueryFilter, Set,
+};
+use serde::Serialize;
+
+use crate::config::ImaggaConfig;
+use crate::error::ServerError;
+use crate::imagga_client::{get_imagga_usage, ImaggaUsage};
+
+// How often we ask Imagga how much of our quota is left
+static USAGE_POLL_INTERVAL: Duration = Duration::from_secs(15 * 60);
+
+/// A request we made to Imagga, which will be stored in the TaggerCall table.
+/// `status` is the HTTP status code of the response (None if we got no response).
+pub struct TaggerCall {
+    pub endpoint: String,
+    pub status: Option<u16>,
+    pub latency: Duration,
+}
+
+/// The limits on how many requests we can make to Imagga. This is provided
+/// to the routes as an extension.
+#[derive(Clone)]
+pub struct TaggerBudget {
+    // The maximum number of Imagga requests we allow ourselves to make per
+    // calendar month. This lets us stop well before Imagga's own limit is reached.
+    pub monthly_budget: Option<i64>,
+    // The latest usage report we got from Imagga, shared with the background
+    // task that fetches it. None until the first report has been fetched
+    // (or if fetching it keeps failing).
+    pub imagga_usage: Arc<RwLock<Option<ImaggaUsage>>>,
+    // Why the latest attempt to fetch the usage report failed (None if it succeeded)
+    pub imagga_usage_error: Arc<RwLock<Option<String>>>,
+}
+
+/// Take our monthly budget (if any) from the config and start a background task that
+/// periodically fetches our usage from Imagga (if Imagga is configured).
+pub fn start_tagger_budget(imagga_authorization: Option<String>, config: &ImaggaConfig) -> TaggerBudget {
+    let monthly_budget = config.monthly_budget;
+    let imagga_usage = Arc::new(RwLock::new(None));
+    let imagga_usage_error = Arc::new(RwLock::new(None));
+    if let Some(imagga_authorization) = imagga_authorization {
+        spawn_usage_poller(imagga_authorization, imagga_usage.clone(), imagga_usage_error.clone());
+    }
+    TaggerBudget {
+        monthly_budget,
+        imagga_usage,
+        imagga_usage_error,
+    }
+}
+
+/// Start a background task that periodically fetches our usage from Imagga
+/// and stores it in `shared_usage`. Since there is no request to fail when
+/// fetching it fails, the error is stored in `shared_error` for the usage report.
+fn spawn_usage_poller(
+    imagga_authorization: String,
+    shared_usage: Arc<RwLock<Option<ImaggaUsage>>>,
+    shared_error: Arc<RwLock<Option<String>>>,
+) {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(USAGE_POLL_INTERVAL);
+        loop {
+            interval.tick().await;
+            // ureq is blocking, so we make the request on a separate thread
+            let authorization = imagga_authorization.clone();
+            let latest = match tokio::task::spawn_blocking(move || get_imagga_usage(&authorization)).await {
+                Ok(latest) => latest,
+                Err(err) => Err(err.into()),
+            };
+            match latest {
+                Ok(latest) => {
+                    *shared_usage.write().unwrap() = Some(latest);
+                    *shared_error.write().unwrap() = None;
+                }
+                Err(err) => *shared_error.write().unwrap() = Some(err.msg().to_owned()),
+            }
+        }
+    });
+}
+
+/// Store the calls made to Imagga (e.g. while handling a `POST /images` request).
+/// `image_id` is the image the calls were made for, if it ended up being inserted.
+pub async fn record_tagger_calls(
+    calls: Vec<TaggerCall>,
+    image_id: Option<i32>,
+    db: &DatabaseConnection,
+) -> Result<(), ServerError> {
+    let now = Utc::now();
+    let models = calls
+        .into_iter()
+        .map(|call| tagger_call::ActiveModel {
+            id: NotSet,
+            endpoint: Set(call.endpoint),
+            image_id: Set(image_id),
+            status: Set(call.status.map(i32::from)),
+            latency_ms: Set(call.latency.as_millis().try_into().unwrap_or(i32::MAX)),
+            created_at: Set(now.into()),
+        })
+        .collect::<Vec<_>>();
+    if models.len() > 0 {
+        tagger_call::Entity::insert_many(models).exec(db).await?;
+    }
+    Ok(())
+}
+
+/// Make sure we are allowed to make more requests to Imagga, i.e. that neither
+/// our own monthly budget nor Imagga's quota has been used up.
+/// Gives a HTTP 429 error explaining which limit was reached otherwise.
+pub async fn check_tagger_budget(
+    budget: &TaggerBudget,
+    db: &DatabaseConnection,
+) -> Result<(), ServerError> {
+    if let Some(monthly_budget) = budget.monthly_budget {
+        let calls_this_month = count_calls_this_month(db).await?;
+        if calls_this_month >= monthly_budget {
+            return Err(ServerError::new(
+                StatusCode::TOO_MANY_REQUESTS,
+                format!(
+                    "Auto-tagging is unavailable: the monthly budget of {monthly_budget} Imagga requests has been used up"
+                ),
+            ));
+        }
+    }
+    if let Some(usage) = budget.imagga_usage.read().unwrap().as_ref() {
+        if usage.remaining() == 0 {
+            return Err(ServerError::new(
+                StatusCode::TOO_MANY_REQUESTS,
+                format!(
+                    "Auto-tagging is unavailable: the Imagga quota of {} requests has been used up",
+                    usage.monthly_limit
+                ),
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// Count the calls made to Imagga since the start of the current (UTC) month
+async fn count_calls_this_month(db: &DatabaseConnection) -> Result<i64, ServerError> {
+    let now = Utc::now();
+    let start_of_month = Utc.ymd(now.year(), now.month(), 1).and_hms(0, 0, 0);
+    let count = tagger_call::Entity::find()
+        .filter(tagger_call::Column::CreatedAt.gte(start_of_month))
+        .count(db)
+        .await?;
+    Ok(count as i64)
+}
+
+/// The response body of `GET /admin/tagger/usage`
+#[derive(Serialize)]
+pub struct TaggerUsageReport {
+    // Totals for each of the last 31 days, most recent first
+    daily: Vec<UsageTotal>,
+    // Totals for each of the last 12 months, most recent first
+    monthly: Vec<UsageTotal>,
+    monthly_budget: Option<i64>,
+    // What Imagga itself reports (None if it hasn't been fetched yet)
+    imagga: Option<ImaggaQuotaReport>,
+    // Why we couldn't fetch Imagga's report the last time we tried (None if we could)
+    imagga_error: Option<String>,
+}
+
+/// The number of calls made to Imagga in a day or month
+#[derive(Serialize, FromQueryResult)]
+pub struct UsageTotal {
+    period: String,
+    calls: i64,
+    failed_calls: i64,
+    average_latency_ms: f64,
+}
+
+/// Imagga's usage report, along with how many requests are left
+#[derive(Serialize)]
+pub struct ImaggaQuotaReport {
+    #[serde(flatten)]
+    usage: ImaggaUsage,
+    remaining: i64,
+}
+
+/// Put together the usage report for `GET /admin/tagger/usage`
+pub async fn get_tagger_usage_report(
+    budget: &TaggerBudget,
+    db: &DatabaseConnection,
+) -> Result<TaggerUsageReport, ServerError> {
+    let daily = get_usage_totals("day", "YYYY-MM-DD", "31 days", db).await?;
+    let monthly = get_usage_totals("month", "YYYY-MM", "12 months", db).await?;
+    let imagga = budget
+        .imagga_usage
+        .read()
+        .unwrap()
+        .clone()
+        .map(|usage| ImaggaQuotaReport {
+            remaining: usage.remaining(),
+            usage,
+        });
+    Ok(TaggerUsageReport {
+        daily,
+        monthly,
+        monthly_budget: budget.monthly_budget,
+        imagga,
+        imagga_error: budget.imagga_usage_error.read().unwrap().clone(),
+    })
+}
+
+/// Total up the calls made to Imagga per `unit` (a Postgres date_trunc unit such as "day")
+/// over the last `interval`, labelling each period using the Postgres date `format`.
+/// i.e.
+///   SELECT to_char(date_trunc('day', created_at), 'YYYY-MM-DD') AS period,
+///     COUNT(*) AS calls, ...
+///   FROM tagger_call
+///   WHERE created_at >= now() - interval '31 days'
+///   GROUP BY period
+///   ORDER BY period DESC
+async fn get_usage_totals(
+    unit: &str,
+    format: &str,
+    interval: &str,
+    db: &DatabaseConnection,
+) -> Result<Vec<UsageTotal>, ServerError> {
+    let query = Query::select()
+        .expr_as(
+            Expr::cust(&format!("to_char(date_trunc('{unit}', created_at), '{format}')")),
+            Alias::new("period"),
+        )
+        .expr_as(Expr::asterisk().count(), Alias::new("calls"))
+        .expr_as(
+            // Calls without a status code never got a response
+            Expr::cust("COUNT(*) FILTER (WHERE status IS NULL OR status >= 400)"),
+            Alias::new("failed_calls"),
+        )
+        .expr_as(
+            Expr::cust("AVG(latency_ms)::float8"),
+            Alias::new("average_latency_ms"),
+        )
+        .from(migration::TaggerCall::Table)
+        .and_where(Expr::cust(&format!("created_at >= now() - interval '{interval}'")))
+        .group_by_col(Alias::new("period"))
+        .order_by(Alias::new("period"), Order::Desc)
+        .to_owned();
+    Ok(
+        UsageTotal::find_by_statement(db.get_database_backend().build(&query))
+            .all(db)
+            .await?,
+    )
+}
@@ -0,0 +1,81 @@
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use sha2::{Digest, Sha256};
+use tokio::fs::{File, OpenOptions};
+use tokio::io::AsyncWriteExt;
+
+use crate::config::UploadLimits;
+use crate::error::ServerError;
+use crate::validate_image::too_large;
+
+// Numbers the temporary files of this process, so that concurrent uploads get different files
+static NEXT_TEMP_UPLOAD: AtomicU64 = AtomicU64::new(0);
+
+/// An uploaded file (from a multipart form or a raw request body) that was written
+/// to a temporary file as it arrived, so that we only hold one chunk of it in memory
+/// while reading the request. It is hashed as it is written, and if it doesn't need
+/// to be changed before it is stored, it is streamed from here to storage.
+/// The temporary file is deleted when this is dropped.
+pub struct TempUpload {
+    pub path: PathBuf,
+    pub byte_size: usize,
+    // The SHA-256 hash of the file (in hex)
+    pub content_hash: String,
+}
+
+impl Drop for TempUpload {
+    fn drop(&mut self) {
+        // The file is in the temporary directory, so it isn't worth failing over
+        std::fs::remove_file(&self.path).ok();
+    }
+}
+
+/// Writes an upload to a TempUpload chunk by chunk, giving up as soon as it goes over
+/// the upload limit (in which case the temporary file is deleted)
+pub struct TempUploadWriter {
+    upload: TempUpload,
+    file: File,
+    hasher: Sha256,
+    max_bytes: usize,
+}
+
+impl TempUploadWriter {
+    /// Create a new, empty temporary file
+    pub async fn new(limits: &UploadLimits) -> Result<TempUploadWriter, ServerError> {
+        let path = std::env::temp_dir().join(format!(
+            "image-api-upload-{}-{}",
+            std::process::id(),
+            NEXT_TEMP_UPLOAD.fetch_add(1, Ordering::Relaxed)
+        ));
+        let file = OpenOptions::new().write(true).create_new(true).open(&path).await?;
+        Ok(TempUploadWriter {
+            upload: TempUpload {
+                path,
+                byte_size: 0,
+                content_hash: String::new(),
+            },
+            file,
+            hasher: Sha256::new(),
+            max_bytes: limits.max_bytes,
+        })
+    }
+
+    /// Append a chunk of the upload, unless that would make it too large
+    pub async fn write(&mut self, chunk: &[u8]) -> Result<(), ServerError> {
+        if self.upload.byte_size + chunk.len() > self.max_bytes {
+            return Err(too_large(self.max_bytes));
+        }
+        self.file.write_all(chunk).await?;
+        self.hasher.update(chunk);
+        self.upload.byte_size += chunk.len();
+        Ok(())
+    }
+
+    /// Finish writing the file once the whole upload has been written
+    pub async fn finish(mut self) -> Result<TempUpload, ServerError> {
+        self.file.flush().await?;
+        self.upload.content_hash = format!("{:x}", self.hasher.finalize());
+        Ok(self.upload)
+    }
+}
@@ -0,0 +1,455 @@
+use std::io::{Cursor, ErrorKind};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use axum::http::StatusCode;
+use chrono::{DateTime, Utc};
+use tokio::sync::Mutex;
+use image::{
+    codecs::gif::{GifDecoder, GifEncoder, Repeat},
+    AnimationDecoder, DynamicImage, Frame, ImageError, ImageFormat, ImageOutputFormat,
+};
+use photon_rs::{
+    transform::{crop, resize, SamplingFilter},
+    PhotonImage,
+};
+use serde::Deserialize;
+
+use crate::config::TransformConfig;
+use crate::error::ServerError;
+use crate::sanitize_image::{color_table_length, skip_gif_sub_blocks};
+use crate::storage::{check_key, Storage};
+use crate::upload_image::{
+    encode_image, encode_in_format, encode_webp, file_extension, mime_type, to_dynamic_image,
+    to_photon_image,
+};
+use crate::watermark::{apply_watermark, Watermark};
+
+// The quality used for lossy formats when `q` isn't given
+static DEFAULT_QUALITY: u8 = 80;
+// The extension of files that are being written to the cache
+static TEMPORARY_EXTENSION: &str = "tmp";
+// The identifier of the GIF application extension that says how often an animation loops
+static GIF_LOOP_IDENTIFIER: &[u8] = b"\x0BNETSCAPE2.0";
+// The share of `max_cache_bytes` (in percent) that eviction shrinks the cache to, so
+// that we don't have to evict (and look at every file in the cache) on every new file
+static EVICT_TO_PERCENT: u64 = 90;
+
+/// The query parameters of the `GET /files/{key}` endpoint, which describe how
+/// the stored file should be transformed before it is sent back. If none are
+/// given, the file is sent as-is.
+/// `w` and `h` are the width and height to resize to (if only one is given, the
+/// other follows from the aspect ratio), `fit` says how to resize to both (see Fit),
+/// `format` is the format to encode the result in (the file's own format by default)
+/// and `q` is the quality (1 to 100) for JPEG and WebP.
+/// `watermark=false` asks for the file without our watermark (if one is configured),
+/// which only internal users may do (see `get_file`).
+#[derive(Deserialize)]
+pub struct TransformParams {
+    w: Option<u32>,
+    h: Option<u32>,
+    fit: Option<Fit>,
+    format: Option<OutputFormat>,
+    q: Option<u8>,
+    pub watermark: Option<bool>,
+}
+
+/// How an image is resized when both a width and a height are given
+#[derive(Deserialize, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum Fit {
+    // Scale the image to fill the size, cropping off whatever doesn't fit (around the center)
+    Cover,
+    // Scale the image to fit inside the size, keeping its aspect ratio (the default)
+    Contain,
+    // Stretch the image to exactly the size
+    Fill,
+}
+
+/// The formats a transformed image can be encoded in
+#[derive(Deserialize, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum OutputFormat {
+    Webp,
+    Jpeg,
+    Png,
+}
+
+impl OutputFormat {
+    fn image_format(self) -> ImageFormat {
+        match self {
+            OutputFormat::Webp => ImageFormat::WebP,
+            OutputFormat::Jpeg => ImageFormat::Jpeg,
+            OutputFormat::Png => ImageFormat::Png,
+        }
+    }
+}
+
+/// How many bytes are in the cache of transformed files, shared by every request so that
+/// we only have to look at the files in the cache when it goes over its limit. It is
+/// None until we first cache a file, when we count the files that were already there.
+#[derive(Clone, Default)]
+pub struct CacheSize(Arc<Mutex<Option<u64>>>);
+
+/// A file that is ready to be sent to the client (see serve_file.rs)
+pub struct ServedFile {
+    pub bytes: Vec<u8>,
+    pub content_type: String,
+    // When the file was stored (or, for transformed files, cached)
+    pub last_modified: Option<DateTime<Utc>>,
+}
+
+/// Fetch a stored file, transformed according to the query parameters (see
+/// TransformParams) and with the watermark drawn onto it (if it is given; see
+/// watermark.rs). Transformed files are cached on disk (in the configured
+/// cache directory) under a name made from the key and the parameters, so each
+/// transformation is only done once. Gives a 404 if the file doesn't exist, and a
+/// 400 if the parameters are invalid or not allowed by the config.
+pub async fn get_transformed_file(
+    key: &str,
+    params: TransformParams,
+    watermark: Option<Arc<Watermark>>,
+    storage: &dyn Storage,
+    config: &TransformConfig,
+    cache_size: &CacheSize,
+) -> Result<ServedFile, ServerError> {
+    if params.w.is_none()
+        && params.h.is_none()
+        && params.fit.is_none()
+        && params.format.is_none()
+        && params.q.is_none()
+        && watermark.is_none()
+    {
+        let bytes = storage.get(key).await?.ok_or_else(|| not_found(key))?;
+        let content_type = ImageFormat::from_path(key)
+            .map(mime_type)
+            .unwrap_or("application/octet-stream");
+        return Ok(ServedFile {
+            bytes,
+            content_type: content_type.to_owned(),
+            last_modified: storage.last_modified(key).await?,
+        });
+    }
+
+    check_params(&params, config)?;
+    // The key becomes part of a path, so it must be a plain filename
+    check_key(key)?;
+    let format = match params.format {
+        Some(format) => format.image_format(),
+        None => original_format(key)?,
+    };
+    let fit = params.fit.unwrap_or(Fit::Contain);
+    let quality = params.q.unwrap_or(DEFAULT_QUALITY);
+    // Other formats ignore the quality, so every quality shares the same cached file
+    let quality_name = match format {
+        ImageFormat::Jpeg | ImageFormat::WebP => format!("-q{quality}"),
+        _ => String::new(),
+    };
+    let watermark_name = match &watermark {
+        Some(watermark) => format!("-wm{}", watermark.fingerprint),
+        None => String::new(),
+    };
+    let cache_path = config.cache_dir.join(format!(
+        "{key}.w{}-h{}-{}{quality_name}{watermark_name}.{}",
+        params.w.unwrap_or_default(),
+        params.h.unwrap_or_default(),
+        fit_name(fit),
+        file_extension(format)
+    ));
+    if let Ok(bytes) = tokio::fs::read(&cache_path).await {
+        return Ok(ServedFile {
+            bytes,
+            content_type: mime_type(format).to_owned(),
+            last_modified: cached_at(&cache_path).await,
+        });
+    }
+
+    let original = storage.get(key).await?.ok_or_else(|| not_found(key))?;
+    let (width, height) = (params.w, params.h);
+    // Decoding, resizing, watermarking and encoding are CPU-intensive, so we do them
+    // on a blocking thread
+    let bytes = tokio::task::spawn_blocking(move || {
+        transform(&original, width, height, fit, watermark.as_deref(), format, quality)
+    })
+    .await??;
+
+    let cached = async {
+        tokio::fs::create_dir_all(&config.cache_dir).await?;
+        // Write to a temporary file first so that a request can never read a half-written file
+        let temporary_path = cache_path.with_extension(TEMPORARY_EXTENSION);
+        tokio::fs::write(&temporary_path, &bytes).await?;
+        tokio::fs::rename(&temporary_path, &cache_path).await?;
+        let mut cached_bytes = cache_size.0.lock().await;
+        *cached_bytes = match cached_bytes.map(|total| total + bytes.len() as u64) {
+            Some(total) if total <= config.max_cache_bytes => Some(total),
+            _ => Some(evict_cached_files(config.cache_dir.clone(), config.max_cache_bytes).await?),
+        };
+        Ok::<_, ServerError>(())
+    };
+    // A cache we can't write to would make every request transform the image again
+    cached.await.map_err(|err| err.with_context("while caching the transformed image"))?;
+    Ok(ServedFile {
+        bytes,
+        content_type: mime_type(format).to_owned(),
+        last_modified: cached_at(&cache_path).await,
+    })
+}
+
+/// Count the bytes in the cache, and if it is larger than `max_bytes`, delete the oldest
+/// files in it until it is no larger than EVICT_TO_PERCENT of that. Returns the number of
+/// bytes left in the cache. This is run when a new file takes the cache (as far as
+/// CacheSize knows) over `max_bytes`, so that the cache can't fill up the disk.
+async fn evict_cached_files(cache_dir: PathBuf, max_bytes: u64) -> Result<u64, ServerError> {
+    tokio::task::spawn_blocking(move || {
+        let mut files = vec![];
+        for entry in std::fs::read_dir(&cache_dir)? {
+            let path = entry?.path();
+            // Files that are still being written aren't part of the cache yet
+            if path.extension().is_some_and(|extension| extension == TEMPORARY_EXTENSION) {
+                continue;
+            }
+            let metadata = std::fs::metadata(&path)?;
+            if metadata.is_file() {
+                files.push((metadata.modified()?, metadata.len(), path));
+            }
+        }
+        let mut total_bytes: u64 = files.iter().map(|(_, size, _)| size).sum();
+        if total_bytes <= max_bytes {
+            return Ok(total_bytes);
+        }
+        let target_bytes = max_bytes / 100 * EVICT_TO_PERCENT;
+        files.sort();
+        for (_, size, path) in files {
+            if total_bytes <= target_bytes {
+                break;
+            }
+            match std::fs::remove_file(&path) {
+                // Another request may have deleted the file already
+                Err(err) if err.kind() != ErrorKind::NotFound => return Err(err.into()),
+                _ => total_bytes -= size,
+            }
+        }
+        Ok(total_bytes)
+    })
+    .await?
+}
+
+/// When the transformed file was cached, or None if it couldn't be
+async fn cached_at(cache_path: &Path) -> Option<DateTime<Utc>> {
+    let metadata = tokio::fs::metadata(cache_path).await.ok()?;
+    Some(metadata.modified().ok()?.into())
+}
+
+/// Make sure the requested size and quality are ones we allow, so that clients can't make us do
+/// (and cache) an unbounded amount of work
+fn check_params(params: &TransformParams, config: &TransformConfig) -> Result<(), ServerError> {
+    for size in [params.w, params.h].into_iter().flatten() {
+        if size == 0 || size > config.max_dimension {
+            return Err(ServerError::new(
+                StatusCode::BAD_REQUEST,
+                format!("w and h must be between 1 and {}", config.max_dimension),
+            ));
+        }
+        if !config.allowed_sizes.is_empty() && !config.allowed_sizes.contains(&size) {
+            return Err(ServerError::new(
+                StatusCode::BAD_REQUEST,
+                format!("w and h must be one of {:?}", config.allowed_sizes),
+            ));
+        }
+    }
+    if let Some(quality) = params.q {
+        if !(1..=100).contains(&quality) {
+            return Err(ServerError::new(
+                StatusCode::BAD_REQUEST,
+                "q must be between 1 and 100".to_owned(),
+            ));
+        }
+        if !config.allowed_qualities.is_empty() && !config.allowed_qualities.contains(&quality) {
+            return Err(ServerError::new(
+                StatusCode::BAD_REQUEST,
+                format!("q must be one of {:?}", config.allowed_qualities),
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// Transformed files keep the format of the original (which is in its key) unless
+/// another one is asked for, so that e.g. watermarking a GIF doesn't turn it into a PNG
+fn original_format(key: &str) -> Result<ImageFormat, ServerError> {
+    ImageFormat::from_path(key).map_err(|_| {
+        ServerError::new(
+            StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            format!("Unable to transform {key}, since its format is unknown"),
+        )
+    })
+}
+
+/// How the fit is written in the names of cached files
+fn fit_name(fit: Fit) -> &'static str {
+    match fit {
+        Fit::Cover => "cover",
+        Fit::Contain => "contain",
+        Fit::Fill => "fill",
+    }
+}
+
+/// Decode the original file, resize it, draw the watermark (if any) and encode the result.
+/// `quality` only applies to JPEG and WebP, and other formats are encoded like uploads
+/// that have to be re-encoded (see `encode_in_format`).
+fn transform(
+    original: &[u8],
+    width: Option<u32>,
+    height: Option<u32>,
+    fit: Fit,
+    watermark: Option<&Watermark>,
+    format: ImageFormat,
+    quality: u8,
+) -> Result<Vec<u8>, ServerError> {
+    // A GIF stays a GIF (keeping its animation) unless another format is asked for
+    if format == ImageFormat::Gif {
+        return transform_gif(original, width, height, fit, watermark);
+    }
+    let decoded = image::load_from_memory(original).map_err(undecodable_original)?;
+    let mut image = resize_image(to_photon_image(&decoded), width, height, fit);
+    if let Some(watermark) = watermark {
+        apply_watermark(&mut image, watermark);
+    }
+    match format {
+        ImageFormat::WebP => encode_webp(
+            &image.get_raw_pixels(),
+            image.get_width(),
+            image.get_height(),
+            Some(quality as f32),
+        ),
+        ImageFormat::Jpeg => encode_image(&image, ImageOutputFormat::Jpeg(quality)),
+        format => encode_in_format(&to_dynamic_image(&image)?, format),
+    }
+}
+
+/// Like `transform`, for a GIF that stays a GIF: every frame of an animated GIF is
+/// resized and watermarked, keeping its timing and how often it loops. Frames are encoded
+/// as soon as they are decoded, so that a long animation is never held in memory all at once.
+fn transform_gif(
+    original: &[u8],
+    width: Option<u32>,
+    height: Option<u32>,
+    fit: Fit,
+    watermark: Option<&Watermark>,
+) -> Result<Vec<u8>, ServerError> {
+    let decoder = GifDecoder::new(Cursor::new(original)).map_err(undecodable_original)?;
+    let mut bytes = vec![];
+    {
+        let mut encoder = GifEncoder::new(&mut bytes);
+        // The decoder doesn't tell us how often the animation loops, so we read it ourselves
+        if let Some(repeat) = gif_repeat(original) {
+            encoder.set_repeat(repeat).map_err(unencodable)?;
+        }
+        for frame in decoder.into_frames() {
+            // Each frame is the whole picture at that point of the animation
+            let frame = frame.map_err(undecodable_original)?;
+            let delay = frame.delay();
+            let decoded = DynamicImage::ImageRgba8(frame.into_buffer());
+            let mut image = resize_image(to_photon_image(&decoded), width, height, fit);
+            if let Some(watermark) = watermark {
+                apply_watermark(&mut image, watermark);
+            }
+            let frame = Frame::from_parts(to_dynamic_image(&image)?.to_rgba8(), 0, 0, delay);
+            encoder.encode_frame(frame).map_err(unencodable)?;
+        }
+    }
+    Ok(bytes)
+}
+
+/// How often a GIF's animation loops, from its NETSCAPE2.0 application extension (a loop
+/// count of 0 means forever). GIFs without one (or that are malformed) only play once,
+/// so they give None, in which case the transformed GIF doesn't get one either.
+/// See sanitize_image.rs for the structure of a GIF.
+fn gif_repeat(bytes: &[u8]) -> Option<Repeat> {
+    let flags = *bytes.get(10)?;
+    let mut position = 13 + color_table_length(flags);
+    loop {
+        match *bytes.get(position)? {
+            0x2C => {
+                let flags = *bytes.get(position + 9)?;
+                position = skip_gif_sub_blocks(bytes, position + 11 + color_table_length(flags))?;
+            }
+            0x21 => {
+                let label = *bytes.get(position + 1)?;
+                if label == 0xFF && bytes.get(position + 2..position + 14)? == GIF_LOOP_IDENTIFIER {
+                    // A sub-block with 3 bytes: 1 (the ID of the loop count) and the loop count
+                    if let [3, 1, low, high] = *bytes.get(position + 14..position + 18)? {
+                        return Some(match u16::from_le_bytes([low, high]) {
+                            0 => Repeat::Infinite,
+                            count => Repeat::Finite(count),
+                        });
+                    }
+                }
+                position = skip_gif_sub_blocks(bytes, position + 2)?;
+            }
+            // The trailer, or something we don't understand
+            _ => return None,
+        }
+    }
+}
+
+/// The error for stored files that can't be decoded, which should never happen
+/// since they were validated when they were stored
+fn undecodable_original(err: ImageError) -> ServerError {
+    ServerError::new(
+        StatusCode::INTERNAL_SERVER_ERROR,
+        format!("Unable to decode stored image: {err}"),
+    )
+}
+
+/// The error for transformed images that can't be encoded
+fn unencodable(err: ImageError) -> ServerError {
+    ServerError::new(
+        StatusCode::INTERNAL_SERVER_ERROR,
+        format!("Unable to encode image: {err}"),
+    )
+}
+
+/// Resize the image to the requested size (see TransformParams and Fit)
+fn resize_image(mut image: PhotonImage, width: Option<u32>, height: Option<u32>, fit: Fit) -> PhotonImage {
+    // Use u64 so that large images can't overflow the multiplications
+    let (original_width, original_height) = (image.get_width() as u64, image.get_height() as u64);
+    let scale = |size: u64, numerator: u32, denominator: u64| (size * numerator as u64 / denominator).max(1) as u32;
+    match (width, height) {
+        (None, None) => image,
+        (Some(width), None) => {
+            let height = scale(original_height, width, original_width);
+            resize(&image, width, height, SamplingFilter::Lanczos3)
+        }
+        (None, Some(height)) => {
+            let width = scale(original_width, height, original_height);
+            resize(&image, width, height, SamplingFilter::Lanczos3)
+        }
+        (Some(width), Some(height)) => match fit {
+            Fit::Fill => resize(&image, width, height, SamplingFilter::Lanczos3),
+            Fit::Contain | Fit::Cover => {
+                // Compare the aspect ratios to see which side limits the scale
+                let wider = original_width * height as u64 > original_height * width as u64;
+                let (scaled_width, scaled_height) = if wider == matches!(fit, Fit::Contain) {
+                    (width, scale(original_height, width, original_width))
+                } else {
+                    (scale(original_width, height, original_height), height)
+                };
+                image = resize(&image, scaled_width, scaled_height, SamplingFilter::Lanczos3);
+                if let Fit::Cover = fit {
+                    // Crop off the parts that overflow the size, keeping the center
+                    let (width, height) = (width.min(scaled_width), height.min(scaled_height));
+                    let x1 = (scaled_width - width) / 2;
+                    let y1 = (scaled_height - height) / 2;
+                    image = crop(&mut image, x1, y1, x1 + width, y1 + height);
+                }
+                image
+            }
+        },
+    }
+}
+
+/// The error for files that aren't in storage
+fn not_found(key: &str) -> ServerError {
+    ServerError::new(StatusCode::NOT_FOUND, format!("No file found named {key}"))
+}
@@ -1,17 +1,176 @@
-use photon_rs::{base64_to_image, native::save_image};
-
-// The name of the local directory we should store uploaded files in
-pub static UPLOAD_DIR: &str = "uploaded_files";
-// The route (from the root) that clients should use to access uploaded files
-pub static FILES_ROUTE: &str = "/files";
-
-/// Upload an image, providing  its base64-encoded data,
-/// and use its image ID to derive its filename. Then return
-/// a url to the uploaded image.
-pub fn upload(base64str: &str, id: i32) -> String {
-    let image = base64_to_image(base64str);
-    let path = format!("{UPLOAD_DIR}/{id}.png");
-    save_image(image, &path);
-    // Returned path should include site prefix:
-    format!("http://localhost:3000{FILES_ROUTE}/{id}.png")
-}
\ No newline at end of file
+use std::io::Cursor;
+
+use axum::http::StatusCode;
+use image::{codecs::tiff::TiffEncoder, DynamicImage, ImageFormat, ImageOutputFormat, RgbaImage};
+use photon_rs::PhotonImage;
+
+use crate::error::ServerError;
+use crate::storage::Storage;
+use crate::validate_image::UploadedImage;
+
+// The quality we re-encode JPEGs at, which is high enough that the loss isn't noticeable
+static JPEG_QUALITY: u8 = 92;
+// libwebp can't encode images that are wider or taller than this
+static WEBP_MAX_DIMENSION: u32 = 16383;
+// How hard libwebp tries to shrink lossless files (its default)
+static WEBP_LOSSLESS_EFFORT: f32 = 75.0;
+
+/// Upload an image's original file as-is (i.e. without re-encoding it), and use
+/// the hash of its content and its format to derive its filename (e.g. `<hash>.jpg`),
+/// so that identical files are only stored once. Then return the storage key of the
+/// uploaded image (which is just its filename).
+/// Files that are still in the temporary file they were uploaded to are streamed from there.
+pub async fn upload(storage: &dyn Storage, uploaded_image: &UploadedImage) -> Result<String, ServerError> {
+    let format = uploaded_image.format;
+    let filename = file_key(&uploaded_image.content_hash, format);
+    match &uploaded_image.temp_upload {
+        Some(temp_upload) => storage.put_file(&filename, &temp_upload.path, mime_type(format)).await?,
+        None => storage.put(&filename, uploaded_image.bytes.clone(), mime_type(format)).await?,
+    }
+    Ok(filename)
+}
+
+/// The storage key of an image's original file, e.g. `<hash>.jpg`
+pub fn file_key(content_hash: &str, format: ImageFormat) -> String {
+    format!("{content_hash}.{}", file_extension(format))
+}
+
+/// Work out the format of an image file from its contents (rather than trusting
+/// what the user told us). Returns None for formats we don't accept, i.e. anything
+/// other than PNG, JPEG, GIF, WebP, BMP, ICO and TIFF. Some of these (e.g. TIFF)
+/// can't be displayed by every browser, but they can be converted when they are
+/// served (see transform_image.rs).
+pub fn sniff_format(bytes: &[u8]) -> Option<ImageFormat> {
+    match image::guess_format(bytes).ok()? {
+        format @ (ImageFormat::Png
+        | ImageFormat::Jpeg
+        | ImageFormat::Gif
+        | ImageFormat::WebP
+        | ImageFormat::Bmp
+        | ImageFormat::Ico
+        | ImageFormat::Tiff) => Some(format),
+        _ => None,
+    }
+}
+
+/// The mime type (i.e. `Content-Type`) of one of the formats we accept
+pub fn mime_type(format: ImageFormat) -> &'static str {
+    match format {
+        ImageFormat::Png => "image/png",
+        ImageFormat::Jpeg => "image/jpeg",
+        ImageFormat::Gif => "image/gif",
+        ImageFormat::WebP => "image/webp",
+        ImageFormat::Bmp => "image/bmp",
+        ImageFormat::Ico => "image/x-icon",
+        ImageFormat::Tiff => "image/tiff",
+        _ => "application/octet-stream",
+    }
+}
+
+/// The file extension we use for a format, e.g. `jpg`
+pub fn file_extension(format: ImageFormat) -> &'static str {
+    format.extensions_str().first().copied().unwrap_or("bin")
+}
+
+/// Convert a decoded image for use with photon (e.g. so that we can crop it).
+/// Animated images only keep their first frame.
+pub fn to_photon_image(image: &DynamicImage) -> PhotonImage {
+    let image = image.to_rgba8();
+    let (width, height) = image.dimensions();
+    PhotonImage::new(image.into_raw(), width, height)
+}
+
+/// Convert an image back from photon (e.g. after cropping it) so that it can be encoded
+pub fn to_dynamic_image(image: &PhotonImage) -> Result<DynamicImage, ServerError> {
+    let (width, height) = (image.get_width(), image.get_height());
+    let buffer = RgbaImage::from_raw(width, height, image.get_raw_pixels()).ok_or_else(|| {
+        ServerError::new(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "Image has the wrong number of pixels".to_owned(),
+        )
+    })?;
+    Ok(DynamicImage::ImageRgba8(buffer))
+}
+
+/// Encode a decoded image as a file in the given format (e.g. PNG)
+pub fn encode_image(image: &PhotonImage, format: ImageOutputFormat) -> Result<Vec<u8>, ServerError> {
+    let image = match (to_dynamic_image(image)?, &format) {
+        // JPEGs can't have an alpha channel
+        (image, ImageOutputFormat::Jpeg(_)) => DynamicImage::ImageRgb8(image.to_rgb8()),
+        (image, _) => image,
+    };
+    let mut bytes = vec![];
+    image
+        .write_to(&mut bytes, format)
+        .map_err(|err| {
+            ServerError::new(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                format!("Unable to encode image: {err}"),
+            )
+        })?;
+    Ok(bytes)
+}
+
+/// Encode an image in one of the formats we accept, e.g. the format of its original
+/// file (none of the encoders write metadata into the file)
+pub fn encode_in_format(image: &DynamicImage, format: ImageFormat) -> Result<Vec<u8>, ServerError> {
+    let mut bytes = vec![];
+    let result = match format {
+        // Losslessly, so that we don't lose quality
+        ImageFormat::WebP => {
+            let rgba = image.to_rgba8();
+            return encode_webp(&rgba, rgba.width(), rgba.height(), None);
+        }
+        ImageFormat::Tiff => {
+            let rgba = image.to_rgba8();
+            TiffEncoder::new(Cursor::new(&mut bytes)).encode(
+                &rgba,
+                rgba.width(),
+                rgba.height(),
+                image::ColorType::Rgba8,
+            )
+        }
+        // JPEGs can't have an alpha channel
+        ImageFormat::Jpeg => DynamicImage::ImageRgb8(image.to_rgb8())
+            .write_to(&mut bytes, ImageOutputFormat::Jpeg(JPEG_QUALITY)),
+        format => image.write_to(&mut bytes, ImageOutputFormat::from(format)),
+    };
+    result.map_err(|err| {
+        ServerError::new(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            format!("Unable to encode image: {err}"),
+        )
+    })?;
+    Ok(bytes)
+}
+
+/// Encode RGBA pixels as a WebP, with the given quality (0 to 100) or losslessly if it is
+/// None. Gives a 400 for images that are too large for WebP, rather than letting libwebp fail.
+pub fn encode_webp(
+    pixels: &[u8],
+    width: u32,
+    height: u32,
+    quality: Option<f32>,
+) -> Result<Vec<u8>, ServerError> {
+    if width > WEBP_MAX_DIMENSION || height > WEBP_MAX_DIMENSION {
+        return Err(ServerError::new(
+            StatusCode::BAD_REQUEST,
+            format!(
+                "WebP images can be at most {WEBP_MAX_DIMENSION}x{WEBP_MAX_DIMENSION} pixels, \
+                 but this one is {width}x{height}"
+            ),
+        ));
+    }
+    let encoder = webp::Encoder::from_rgba(pixels, width, height);
+    let encoded = match quality {
+        Some(quality) => encoder.encode_simple(false, quality),
+        None => encoder.encode_simple(true, WEBP_LOSSLESS_EFFORT),
+    };
+    let encoded = encoded.map_err(|err| {
+        ServerError::new(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            format!("Unable to encode image: {err:?}"),
+        )
+    })?;
+    Ok(encoded.to_vec())
+}
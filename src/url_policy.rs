@@ -0,0 +1,218 @@
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, ToSocketAddrs};
+
+use axum::http::StatusCode;
+use url::Url;
+
+use crate::config::UrlPolicyConfig;
+use crate::error::ServerError;
+
+/// Check that an image URL given to us by a user is safe to fetch (or to have Imagga
+/// fetch), so that it can't be used to reach internal services (i.e. SSRF). The URL
+/// must use http or https, its host must be allowed by the configured domain lists,
+/// and every address the host resolves to must be public.
+/// Gives a HTTP 400 error explaining why the URL was rejected otherwise.
+/// Resolving the host is blocking, so it is done on a blocking thread.
+pub async fn check_image_url(image_url: &str, policy: &UrlPolicyConfig) -> Result<(), ServerError> {
+    let (host, port) = parse_image_url(image_url)?;
+    let policy = policy.clone();
+    tokio::task::spawn_blocking(move || resolve_allowed(&host, port, &policy))
+        .await?
+        .map_err(|err| rejected(&err.to_string()))?;
+    Ok(())
+}
+
+/// Check the scheme of the URL and return its host and port
+fn parse_image_url(image_url: &str) -> Result<(String, u16), ServerError> {
+    let url = Url::parse(image_url).map_err(|err| rejected(&format!("it is invalid ({err})")))?;
+    if !matches!(url.scheme(), "http" | "https") {
+        return Err(rejected("only http and https URLs are allowed"));
+    }
+    let host = url
+        .host_str()
+        .ok_or_else(|| rejected("it has no host"))?
+        // IPv6 hosts are written in brackets in URLs
+        .trim_start_matches('[')
+        .trim_end_matches(']')
+        .to_owned();
+    let port = url.port_or_known_default().unwrap_or(80);
+    Ok((host, port))
+}
+
+/// Resolve `host` the way ureq would, but fail if the host isn't allowed or resolves
+/// to an address that isn't public. This is also used as the resolver for our own
+/// downloads (see mirror_image.rs), so that redirects and DNS records that change
+/// after `check_image_url` can't get around the policy.
+pub fn resolve_allowed(host: &str, port: u16, policy: &UrlPolicyConfig) -> io::Result<Vec<SocketAddr>> {
+    let host = host.trim_end_matches('.').to_ascii_lowercase();
+    if policy.denied_domains.iter().any(|domain| matches_domain(&host, domain)) {
+        return Err(policy_error(format!("{host} is on the list of denied domains")));
+    }
+    if !policy.allowed_domains.is_empty()
+        && !policy.allowed_domains.iter().any(|domain| matches_domain(&host, domain))
+    {
+        return Err(policy_error(format!("{host} is not on the list of allowed domains")));
+    }
+    let addresses: Vec<SocketAddr> = (host.as_str(), port).to_socket_addrs()?.collect();
+    if let Some(address) = addresses.iter().find(|address| !is_public(address.ip())) {
+        return Err(policy_error(format!(
+            "{host} resolves to {}, which is not a public address",
+            address.ip()
+        )));
+    }
+    Ok(addresses)
+}
+
+/// Same as `resolve_allowed`, for the `host:port` strings that ureq asks its resolver for
+pub fn resolve_netloc_allowed(netloc: &str, policy: &UrlPolicyConfig) -> io::Result<Vec<SocketAddr>> {
+    let (host, port) = netloc
+        .rsplit_once(':')
+        .and_then(|(host, port)| Some((host, port.parse().ok()?)))
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, format!("Invalid host {netloc:?}")))?;
+    resolve_allowed(host.trim_start_matches('[').trim_end_matches(']'), port, policy)
+}
+
+/// Whether the host is the domain or one of its subdomains
+/// (e.g. `cdn.example.com` matches `example.com`)
+fn matches_domain(host: &str, domain: &str) -> bool {
+    let domain = domain.trim_matches('.').to_ascii_lowercase();
+    host == domain || host.ends_with(&format!(".{domain}"))
+}
+
+/// Whether the address is on the public internet, i.e. it isn't private, loopback,
+/// link-local (e.g. cloud metadata services), or otherwise reserved
+fn is_public(address: IpAddr) -> bool {
+    match address {
+        IpAddr::V4(address) => is_public_v4(address),
+        IpAddr::V6(address) => match address.to_ipv4_mapped().or_else(|| embedded_ipv4(address)) {
+            Some(address) => is_public_v4(address),
+            None => is_public_v6(address),
+        },
+    }
+}
+
+/// The IPv4 address that an IPv6 address reaches, for the kinds of IPv6 addresses that
+/// are translated (or tunnelled) to IPv4, so that they can't be used to reach private
+/// IPv4 addresses: NAT64 (`64:ff9b::a.b.c.d`), 6to4 (`2002:aabb:ccdd::/48`) and the
+/// deprecated IPv4-compatible addresses (`::a.b.c.d`)
+fn embedded_ipv4(address: Ipv6Addr) -> Option<Ipv4Addr> {
+    let octets = address.octets();
+    let last_32_bits = Ipv4Addr::new(octets[12], octets[13], octets[14], octets[15]);
+    match address.segments() {
+        [0x64, 0xff9b, 0, 0, 0, 0, _, _] => Some(last_32_bits),
+        [0x2002, ..] => Some(Ipv4Addr::new(octets[2], octets[3], octets[4], octets[5])),
+        // :: and ::1 are IPv6's own unspecified and loopback addresses
+        [0, 0, 0, 0, 0, 0, _, _] if !address.is_unspecified() && !address.is_loopback() => {
+            Some(last_32_bits)
+        }
+        _ => None,
+    }
+}
+
+fn is_public_v4(address: Ipv4Addr) -> bool {
+    let [first, second, third, _] = address.octets();
+    !(address.is_private()
+        || address.is_loopback()
+        || address.is_link_local()
+        || address.is_unspecified()
+        || address.is_broadcast()
+        || address.is_documentation()
+        || address.is_multicast()
+        // 0.0.0.0/8 ("this network")
+        || first == 0
+        // 100.64.0.0/10 (carrier-grade NAT)
+        || (first == 100 && (64..128).contains(&second))
+        // 192.0.0.0/24 (protocol assignments)
+        || (first == 192 && second == 0 && third == 0)
+        // 198.18.0.0/15 (benchmarking)
+        || (first == 198 && (18..20).contains(&second))
+        // 240.0.0.0/4 (reserved)
+        || first >= 240)
+}
+
+fn is_public_v6(address: Ipv6Addr) -> bool {
+    let first_segment = address.segments()[0];
+    !(address.is_loopback()
+        || address.is_unspecified()
+        || address.is_multicast()
+        // fc00::/7 (unique local)
+        || (first_segment & 0xfe00) == 0xfc00
+        // fe80::/10 (link-local)
+        || (first_segment & 0xffc0) == 0xfe80
+        // 2001:db8::/32 (documentation)
+        || (first_segment == 0x2001 && address.segments()[1] == 0x0db8))
+}
+
+/// An error that ureq will report when our resolver refuses a host
+fn policy_error(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::PermissionDenied, msg)
+}
+
+/// The error for image URLs that break the policy
+fn rejected(reason: &str) -> ServerError {
+    ServerError::new(
+        StatusCode::BAD_REQUEST,
+        format!("The image URL is not allowed: {reason}"),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn is_public_address(address: &str) -> bool {
+        is_public(address.parse().unwrap())
+    }
+
+    #[test]
+    fn public_addresses_are_allowed() {
+        assert!(is_public_address("93.184.216.34"));
+        assert!(is_public_address("2606:2800:220:1:248:1893:25c8:1946"));
+        assert!(is_public_address("::ffff:93.184.216.34"));
+        assert!(is_public_address("64:ff9b::93.184.216.34"));
+        assert!(is_public_address("2002:5db8:d822::1"));
+    }
+
+    #[test]
+    fn loopback_and_unspecified_addresses_are_rejected() {
+        assert!(!is_public_address("127.0.0.1"));
+        assert!(!is_public_address("0.0.0.0"));
+        assert!(!is_public_address("::1"));
+        assert!(!is_public_address("::"));
+    }
+
+    #[test]
+    fn private_addresses_are_rejected() {
+        assert!(!is_public_address("10.0.0.1"));
+        assert!(!is_public_address("172.16.0.1"));
+        assert!(!is_public_address("192.168.1.1"));
+        assert!(!is_public_address("100.64.0.1"));
+        assert!(!is_public_address("fd00::1"));
+    }
+
+    #[test]
+    fn link_local_addresses_are_rejected() {
+        assert!(!is_public_address("169.254.169.254"));
+        assert!(!is_public_address("fe80::1"));
+    }
+
+    #[test]
+    fn ipv4_mapped_addresses_are_checked_as_ipv4() {
+        assert!(!is_public_address("::ffff:127.0.0.1"));
+        assert!(!is_public_address("::ffff:169.254.169.254"));
+        assert!(!is_public_address("::ffff:10.0.0.1"));
+    }
+
+    #[test]
+    fn ipv4_translated_addresses_are_checked_as_ipv4() {
+        // NAT64
+        assert!(!is_public_address("64:ff9b::127.0.0.1"));
+        assert!(!is_public_address("64:ff9b::a9fe:a9fe"));
+        // 6to4 (2002:c0a8:0101::/48 is 192.168.1.1)
+        assert!(!is_public_address("2002:c0a8:0101::1"));
+        assert!(!is_public_address("2002:7f00:1::"));
+        // IPv4-compatible
+        assert!(!is_public_address("::127.0.0.1"));
+        assert!(!is_public_address("::10.0.0.1"));
+    }
+}
@@ -0,0 +1,161 @@
+use std::io::Cursor;
+use std::sync::Arc;
+
+use axum::http::StatusCode;
+use image::{io::Reader, DynamicImage, ImageError, ImageFormat};
+use sha2::{Digest, Sha256};
+
+use crate::config::UploadLimits;
+use crate::error::ServerError;
+use crate::exif_metadata::{read_exif, ExifMetadata};
+use crate::perceptual_hash::perceptual_hash;
+use crate::sanitize_image::apply_orientation;
+use crate::search_image::color_histogram;
+use crate::temp_upload::TempUpload;
+use crate::upload_image::sniff_format;
+
+/// An uploaded image which we have checked is within our limits and decoded.
+/// The original file is kept so that it can be stored as-is (or with its metadata
+/// removed, see sanitize_image.rs). The decoded image is always upright.
+pub struct UploadedImage {
+    pub bytes: Vec<u8>,
+    pub format: ImageFormat,
+    pub image: DynamicImage,
+    // The SHA-256 hash of the file (in hex), which it is stored under
+    pub content_hash: String,
+    // The dHash of the image, for finding near-duplicates (see perceptual_hash.rs)
+    pub perceptual_hash: i64,
+    // The share of the image's pixels in each color bin (see search_image.rs)
+    pub color_histogram: Vec<f32>,
+    // The EXIF metadata of the original file, if it has any (see exif_metadata.rs)
+    pub exif: Option<ExifMetadata>,
+    // Where we downloaded the image from, if we are mirroring it (see mirror_image.rs)
+    pub source_url: Option<String>,
+    // The temporary file the image was uploaded to, as long as it still has the same
+    // content as `bytes`, so that it can be streamed to storage (see temp_upload.rs)
+    pub temp_upload: Option<Arc<TempUpload>>,
+}
+
+/// Decode the base64-encoded image from a `POST /images` request and validate it (see
+/// `validate_image`). Decoding is CPU-intensive, so it is done on a blocking thread.
+pub async fn validate_base64_image(
+    image_base64: String,
+    limits: UploadLimits,
+) -> Result<UploadedImage, ServerError> {
+    // Every 4 base64 characters encode 3 bytes, so we can reject an image that is
+    // too large before decoding it
+    if image_base64.len() / 4 * 3 > limits.max_bytes + 2 {
+        return Err(too_large(limits.max_bytes));
+    }
+    tokio::task::spawn_blocking(move || {
+        let bytes = base64::decode(image_base64.trim()).map_err(|err| {
+            ServerError::new(StatusCode::BAD_REQUEST, format!("Invalid base64 image: {err}"))
+        })?;
+        validate_image(bytes, &limits)
+    })
+    .await?
+}
+
+/// Validate an image file that was uploaded to us as-is (see `validate_image`)
+/// on a blocking thread. It was hashed while it was written, so it isn't hashed again.
+pub async fn validate_image_file(
+    upload: TempUpload,
+    limits: UploadLimits,
+) -> Result<UploadedImage, ServerError> {
+    let bytes = tokio::fs::read(&upload.path).await?;
+    let content_hash = upload.content_hash.clone();
+    let mut uploaded_image =
+        tokio::task::spawn_blocking(move || validate_hashed_image(bytes, content_hash, &limits)).await??;
+    uploaded_image.temp_upload = Some(Arc::new(upload));
+    Ok(uploaded_image)
+}
+
+/// Check that an uploaded file is an image in a format we accept (415 otherwise),
+/// that it isn't too large (413 otherwise), and then decode it (400 if it can't be).
+/// The dimensions are checked before decoding the pixels, so that a small file
+/// claiming to be a huge image (i.e. a decompression bomb) can't use up our memory.
+/// Decoding takes a while, so the async functions above call this on a blocking thread.
+pub fn validate_image(bytes: Vec<u8>, limits: &UploadLimits) -> Result<UploadedImage, ServerError> {
+    if bytes.len() > limits.max_bytes {
+        return Err(too_large(limits.max_bytes));
+    }
+    let content_hash = format!("{:x}", Sha256::digest(&bytes));
+    validate_hashed_image(bytes, content_hash, limits)
+}
+
+/// `validate_image` for a file whose SHA-256 hash we already have, and which we
+/// know is within `limits.max_bytes`
+fn validate_hashed_image(
+    bytes: Vec<u8>,
+    content_hash: String,
+    limits: &UploadLimits,
+) -> Result<UploadedImage, ServerError> {
+    let format = sniff_format(&bytes).ok_or_else(|| {
+        ServerError::new(
+            StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            "Unsupported image format (expected PNG, JPEG, GIF, WebP, BMP, ICO or TIFF)".to_owned(),
+        )
+    })?;
+
+    let (width, height) = Reader::with_format(Cursor::new(&bytes), format)
+        .into_dimensions()
+        .map_err(undecodable)?;
+    if width == 0 || height == 0 {
+        return Err(ServerError::new(
+            StatusCode::BAD_REQUEST,
+            "Image has no pixels".to_owned(),
+        ));
+    }
+    if width > limits.max_dimension || height > limits.max_dimension {
+        return Err(ServerError::new(
+            StatusCode::PAYLOAD_TOO_LARGE,
+            format!(
+                "Image is {width}x{height} pixels, but images can be at most {0}x{0} pixels",
+                limits.max_dimension
+            ),
+        ));
+    }
+    if width as u64 * height as u64 > limits.max_pixels {
+        return Err(ServerError::new(
+            StatusCode::PAYLOAD_TOO_LARGE,
+            format!(
+                "Image has {} pixels, but images can have at most {} pixels",
+                width as u64 * height as u64,
+                limits.max_pixels
+            ),
+        ));
+    }
+
+    let exif = read_exif(&bytes);
+    let image = image::load_from_memory_with_format(&bytes, format).map_err(undecodable)?;
+    let image = apply_orientation(image, exif.as_ref().and_then(|exif| exif.orientation));
+    let perceptual_hash = perceptual_hash(&image);
+    let color_histogram = color_histogram(&image);
+    Ok(UploadedImage {
+        bytes,
+        format,
+        image,
+        content_hash,
+        perceptual_hash,
+        color_histogram,
+        exif,
+        source_url: None,
+        temp_upload: None,
+    })
+}
+
+/// The error for images that are larger than `max_bytes`
+pub fn too_large(max_bytes: usize) -> ServerError {
+    ServerError::new(
+        StatusCode::PAYLOAD_TOO_LARGE,
+        format!("Images can be at most {max_bytes} bytes"),
+    )
+}
+
+/// The error for images that claim to be in a format we accept but can't be decoded
+fn undecodable(err: ImageError) -> ServerError {
+    ServerError::new(
+        StatusCode::BAD_REQUEST,
+        format!("Unable to decode image: {err}"),
+    )
+}
@@ -0,0 +1,184 @@
+use entity::image;
+use entity::image_variant;
+use entity::prelude::*;
+use photon_rs::{
+    transform::{resize, SamplingFilter},
+    PhotonImage,
+};
+use sea_orm::{
+    ActiveModelTrait, ColumnTrait, ConnectionTrait, DatabaseConnection, EntityTrait, QueryFilter, Set,
+    TransactionTrait,
+};
+
+use crate::config::{UploadLimits, VariantConfig};
+use crate::error::ServerError;
+use crate::storage::Storage;
+use crate::upload_image::{encode_webp, to_photon_image};
+use crate::validate_image::validate_image;
+
+static VARIANT_MIME_TYPE: &str = "image/webp";
+
+/// A variant that has been uploaded to storage
+pub struct SavedVariant {
+    pub name: String,
+    pub storage_key: String,
+    pub width: u32,
+    pub height: u32,
+}
+
+/// A variant that has been resized and encoded, but not uploaded yet
+struct EncodedVariant {
+    size: u32,
+    bytes: Vec<u8>,
+    width: u32,
+    height: u32,
+}
+
+/// Generate and upload a resized version of the image for each of the configured
+/// sizes (the longest side of the variant), named after the original and its size
+/// (e.g. `<hash>_600.webp`).
+/// Images are never scaled up, so sizes that are at least as large as the image are
+/// skipped. Resizing is CPU-intensive, so it is done on a blocking thread.
+pub async fn save_variants(
+    storage: &dyn Storage,
+    image: &PhotonImage,
+    name: &str,
+    config: &VariantConfig,
+) -> Result<Vec<SavedVariant>, ServerError> {
+    let image = image.clone();
+    let config = config.clone();
+    let encoded = tokio::task::spawn_blocking(move || encode_variants(&image, &config)).await??;
+
+    let mut variants = Vec::with_capacity(encoded.len());
+    for variant in encoded {
+        let storage_key = variant_key(name, variant.size);
+        storage.put(&storage_key, variant.bytes, VARIANT_MIME_TYPE).await?;
+        variants.push(SavedVariant {
+            name: variant.size.to_string(),
+            storage_key,
+            width: variant.width,
+            height: variant.height,
+        });
+    }
+    Ok(variants)
+}
+
+/// The storage key of the variant of an image with the given size
+pub fn variant_key(name: &str, size: u32) -> String {
+    format!("{name}_{size}.webp")
+}
+
+/// Resize the image to each of the configured sizes and encode the results as WebP
+fn encode_variants(image: &PhotonImage, config: &VariantConfig) -> Result<Vec<EncodedVariant>, ServerError> {
+    let (width, height) = (image.get_width(), image.get_height());
+    let mut sizes = config.sizes.clone();
+    sizes.sort();
+    sizes.dedup();
+    sizes
+        .into_iter()
+        .filter(|size| *size > 0 && *size < width.max(height))
+        .map(|size| {
+            // Scale the longest side down to `size`, keeping the aspect ratio
+            // (u64 so that large images can't overflow the multiplication)
+            let (variant_width, variant_height) = if width >= height {
+                (size, (height as u64 * size as u64 / width as u64).max(1) as u32)
+            } else {
+                ((width as u64 * size as u64 / height as u64).max(1) as u32, size)
+            };
+            let resized = resize(image, variant_width, variant_height, SamplingFilter::Lanczos3);
+            let bytes = encode_webp(
+                &resized.get_raw_pixels(),
+                variant_width,
+                variant_height,
+                Some(config.quality),
+            )?;
+            Ok(EncodedVariant {
+                size,
+                bytes,
+                width: variant_width,
+                height: variant_height,
+            })
+        })
+        .collect()
+}
+
+/// Record the variants of an image in the ImageVariant table
+pub async fn insert_variants(
+    image_id: i32,
+    variants: Vec<SavedVariant>,
+    db: &impl ConnectionTrait,
+) -> Result<(), ServerError> {
+    let models = variants
+        .into_iter()
+        .map(|variant| image_variant::ActiveModel {
+            image_id: Set(image_id),
+            name: Set(variant.name),
+            storage_key: Set(variant.storage_key),
+            mime_type: Set(VARIANT_MIME_TYPE.to_owned()),
+            width: Set(variant.width as i32),
+            height: Set(variant.height as i32),
+        })
+        .collect::<Vec<_>>();
+    if models.len() > 0 {
+        ImageVariant::insert_many(models).exec(db).await?;
+    }
+    Ok(())
+}
+
+/// Generate the variants of every stored image whose variants haven't been generated yet
+/// (e.g. images stored before variants were introduced). This is run with
+/// `cargo run -- backfill-variants`. Files are decoded (and turned upright) like uploads.
+/// Images whose file can't be found or decoded within the upload limits are skipped, so
+/// that one bad image doesn't stop the backfill. Every other image is marked as done,
+/// even if it is too small to get any variants, so that it isn't processed again.
+pub async fn backfill_variants(
+    db: &DatabaseConnection,
+    storage: &dyn Storage,
+    config: &VariantConfig,
+    limits: UploadLimits,
+) -> Result<(), ServerError> {
+    let images = Image::find()
+        .filter(image::Column::StorageKey.is_not_null())
+        .filter(image::Column::VariantsGenerated.eq(false))
+        .all(db)
+        .await?;
+    println!("Generating variants for {} images", images.len());
+
+    for image in images {
+        let storage_key = image.storage_key.clone().unwrap_or_default();
+        let bytes = match storage.get(&storage_key).await? {
+            Some(bytes) => bytes,
+            None => {
+                eprintln!("Skipping image {}: {storage_key} is missing from storage", image.id);
+                continue;
+            }
+        };
+        let decoded = tokio::task::spawn_blocking(move || {
+            validate_image(bytes, &limits).map(|decoded| to_photon_image(&decoded.image))
+        })
+        .await?;
+        let decoded = match decoded {
+            Ok(decoded) => decoded,
+            Err(err) => {
+                eprintln!("Skipping image {}: unable to decode {storage_key}: {}", image.id, err.msg());
+                continue;
+            }
+        };
+        // Images stored before files were named after their hash are named after their id
+        let name = image.content_hash.clone().unwrap_or_else(|| image.id.to_string());
+        let variants = save_variants(storage, &decoded, &name, config).await?;
+        println!("Image {}: generated {} variants", image.id, variants.len());
+        let image_id = image.id;
+        let txn = db.begin().await?;
+        insert_variants(image_id, variants, &txn).await?;
+        let active_model: image::ActiveModel = image.into();
+        image::ActiveModel {
+            variants_generated: Set(true),
+            ..active_model
+        }
+        .update(&txn)
+        .await?;
+        txn.commit().await?;
+    }
+    Ok(())
+}
@@ -0,0 +1,284 @@
+use std::sync::Arc;
+
+use image::{Rgba, RgbaImage};
+use imageproc::drawing::draw_text_mut;
+use photon_rs::{
+    multiple::watermark as overlay_image,
+    transform::{crop, resize, SamplingFilter},
+    PhotonImage,
+};
+use rusttype::{Font, Scale};
+use sha2::{Digest, Sha256};
+
+use crate::config::{WatermarkConfig, WatermarkPosition};
+use crate::upload_image::to_photon_image;
+
+// The font text watermarks are drawn in (see assets/Roboto-LICENSE.txt)
+static FONT: &[u8] = include_bytes!("../assets/Roboto-Regular.ttf");
+// Text is drawn at a fixed size (in pixels) and scaled with the image
+static TEXT_SIZE: f32 = 90.0;
+
+/// Our watermark, ready to be drawn onto the images we serve (see `apply_watermark`)
+pub struct Watermark {
+    // The image or text to overlay, with the configured opacity already applied
+    overlay: PhotonImage,
+    position: WatermarkPosition,
+    scale: f32,
+    // Identifies this watermark in the names of cached files, so that changing the
+    // watermark doesn't serve files with the old one
+    pub fingerprint: String,
+}
+
+/// Load the configured watermark, or None if no watermark is configured.
+/// Like `load_config`, this panics if the configuration is invalid
+/// (e.g. the watermark image can't be read) so that the problem is noticed on startup.
+pub fn load_watermark(config: &WatermarkConfig) -> Option<Arc<Watermark>> {
+    let mut fingerprint = Sha256::new();
+    let overlay = match (&config.image, &config.text) {
+        (Some(_), Some(_)) => panic!("The watermark can be an image or text, but not both"),
+        (Some(path), None) => {
+            let bytes = std::fs::read(path)
+                .unwrap_or_else(|err| panic!("Unable to read watermark image {}: {err}", path.display()));
+            let decoded = image::load_from_memory(&bytes)
+                .unwrap_or_else(|err| panic!("Unable to decode watermark image {}: {err}", path.display()));
+            fingerprint.update(&bytes);
+            to_photon_image(&decoded)
+        }
+        (None, Some(text)) => {
+            fingerprint.update(text.as_bytes());
+            render_text(text)
+        }
+        (None, None) => return None,
+    };
+    if !(0.0..=1.0).contains(&config.opacity) {
+        panic!("The watermark opacity must be between 0 and 1, not {}", config.opacity);
+    }
+    if !(config.scale > 0.0 && config.scale <= 1.0) {
+        panic!("The watermark scale must be above 0 and at most 1, not {}", config.scale);
+    }
+    fingerprint.update(format!("{:?} {} {}", config.position, config.opacity, config.scale));
+    Some(Arc::new(Watermark {
+        overlay: with_opacity(overlay, config.opacity),
+        position: config.position,
+        scale: config.scale,
+        fingerprint: format!("{:x}", fingerprint.finalize())[..8].to_owned(),
+    }))
+}
+
+/// Draw text (in white) onto a transparent image that is just large enough for it
+fn render_text(text: &str) -> PhotonImage {
+    let font = Font::try_from_bytes(FONT).expect("The watermark font is invalid");
+    let scale = Scale::uniform(TEXT_SIZE);
+    // A canvas large enough for the text at that size
+    let width = font
+        .glyphs_for(text.chars())
+        .map(|glyph| glyph.scaled(scale).h_metrics().advance_width)
+        .sum::<f32>()
+        .ceil() as u32
+        + 1;
+    let v_metrics = font.v_metrics(scale);
+    let height = (v_metrics.ascent - v_metrics.descent).ceil() as u32 + 1;
+    let mut canvas = RgbaImage::new(width, height);
+    draw_text_mut(&mut canvas, Rgba([255, 255, 255, 255]), 0, 0, scale, &font, text);
+
+    // Crop off the transparent space around the text
+    let pixels = canvas.into_raw();
+    let drawn: Vec<(u32, u32)> = pixels
+        .chunks_exact(4)
+        .enumerate()
+        .filter(|(_, pixel)| pixel[3] > 0)
+        .map(|(index, _)| (index as u32 % width, index as u32 / width))
+        .collect();
+    let x1 = drawn.iter().map(|(x, _)| *x).min().unwrap_or(0);
+    let y1 = drawn.iter().map(|(_, y)| *y).min().unwrap_or(0);
+    let x2 = drawn.iter().map(|(x, _)| *x + 1).max().unwrap_or(width);
+    let y2 = drawn.iter().map(|(_, y)| *y + 1).max().unwrap_or(height);
+    crop(&mut PhotonImage::new(pixels, width, height), x1, y1, x2, y2)
+}
+
+/// Make an image more transparent, from 0 (invisible) to 1 (as it is)
+fn with_opacity(image: PhotonImage, opacity: f32) -> PhotonImage {
+    let (width, height) = (image.get_width(), image.get_height());
+    let mut pixels = image.get_raw_pixels();
+    for pixel in pixels.chunks_exact_mut(4) {
+        pixel[3] = (pixel[3] as f32 * opacity).round() as u8;
+    }
+    PhotonImage::new(pixels, width, height)
+}
+
+/// Draw the watermark onto an image. The watermark is scaled to fit in the configured
+/// share of the image's width and height, and placed in the configured position with a
+/// small margin.
+pub fn apply_watermark(image: &mut PhotonImage, watermark: &Watermark) {
+    let (width, height) = (image.get_width(), image.get_height());
+    let (overlay_width, overlay_height) = (watermark.overlay.get_width(), watermark.overlay.get_height());
+    let fit = (width as f32 * watermark.scale / overlay_width as f32)
+        .min(height as f32 * watermark.scale / overlay_height as f32);
+    let scaled_width = ((overlay_width as f32 * fit) as u32).clamp(1, width);
+    let scaled_height = ((overlay_height as f32 * fit) as u32).clamp(1, height);
+    let overlay = resize(&watermark.overlay, scaled_width, scaled_height, SamplingFilter::Lanczos3);
+
+    let margin = width.min(height) / 50;
+    let left = margin.min(width - scaled_width);
+    let top = margin.min(height - scaled_height);
+    let right = width - scaled_width - left;
+    let bottom = height - scaled_height - top;
+    let (x, y) = match watermark.position {
+        WatermarkPosition::TopLeft => (left, top),
+        WatermarkPosition::TopRight => (right, top),
+        WatermarkPosition::BottomLeft => (left, bottom),
+        WatermarkPosition::BottomRight => (right, bottom),
+        WatermarkPosition::Center => ((width - scaled_width) / 2, (height - scaled_height) / 2),
+    };
+    overlay_image(image, &overlay, x, y);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Pixels of the images we draw onto, which are black wherever the watermark isn't
+    static BLACK: [u8; 4] = [0, 0, 0, 255];
+
+    fn solid(width: u32, height: u32, pixel: [u8; 4]) -> PhotonImage {
+        PhotonImage::new(pixel.repeat((width * height) as usize), width, height)
+    }
+
+    /// An opaque white watermark of the given size
+    fn white_watermark(width: u32, height: u32, position: WatermarkPosition, scale: f32) -> Watermark {
+        Watermark {
+            overlay: solid(width, height, [255, 255, 255, 255]),
+            position,
+            scale,
+            fingerprint: String::new(),
+        }
+    }
+
+    /// Draw the watermark onto a black image, and return the box (x1, y1, x2, y2) it covers
+    fn watermarked_box(width: u32, height: u32, watermark: &Watermark) -> (u32, u32, u32, u32) {
+        let mut image = solid(width, height, BLACK);
+        apply_watermark(&mut image, watermark);
+        let covered: Vec<(u32, u32)> = image
+            .get_raw_pixels()
+            .chunks_exact(4)
+            .enumerate()
+            .filter(|(_, pixel)| pixel[0] > 128)
+            .map(|(index, _)| (index as u32 % width, index as u32 / width))
+            .collect();
+        assert!(!covered.is_empty(), "the watermark wasn't drawn");
+        (
+            covered.iter().map(|(x, _)| *x).min().unwrap(),
+            covered.iter().map(|(_, y)| *y).min().unwrap(),
+            covered.iter().map(|(x, _)| *x + 1).max().unwrap(),
+            covered.iter().map(|(_, y)| *y + 1).max().unwrap(),
+        )
+    }
+
+    #[test]
+    fn watermarks_are_scaled_to_the_image() {
+        // 25% of 1000x500 fits a 250x125 watermark, with a margin of 10 pixels
+        let watermark = white_watermark(200, 100, WatermarkPosition::BottomRight, 0.25);
+        assert_eq!(watermarked_box(1000, 500, &watermark), (740, 365, 990, 490));
+        assert_eq!(watermarked_box(2000, 1000, &watermark), (1480, 730, 1980, 980));
+    }
+
+    #[test]
+    fn watermarks_keep_their_aspect_ratio() {
+        // The height is what limits a 200x100 watermark on a wide image
+        let watermark = white_watermark(200, 100, WatermarkPosition::TopLeft, 0.5);
+        assert_eq!(watermarked_box(1000, 100, &watermark), (2, 2, 102, 52));
+    }
+
+    #[test]
+    fn watermarks_are_drawn_in_each_position() {
+        let positions = [
+            (WatermarkPosition::TopLeft, (10, 10)),
+            (WatermarkPosition::TopRight, (740, 10)),
+            (WatermarkPosition::BottomLeft, (10, 365)),
+            (WatermarkPosition::BottomRight, (740, 365)),
+            (WatermarkPosition::Center, (375, 187)),
+        ];
+        for (position, (x, y)) in positions {
+            let watermark = white_watermark(200, 100, position, 0.25);
+            assert_eq!(watermarked_box(1000, 500, &watermark), (x, y, x + 250, y + 125), "{position:?}");
+        }
+    }
+
+    #[test]
+    fn watermarks_stay_inside_the_image() {
+        // A watermark as large as the image leaves no room for the margin
+        let watermark = white_watermark(100, 100, WatermarkPosition::BottomRight, 1.0);
+        assert_eq!(watermarked_box(300, 100, &watermark), (198, 0, 298, 100));
+        // A very wide watermark is still at least a pixel high
+        let watermark = white_watermark(1000, 10, WatermarkPosition::TopLeft, 1.0);
+        assert_eq!(watermarked_box(7, 3, &watermark), (0, 0, 7, 1));
+    }
+
+    #[test]
+    fn watermarks_are_drawn_on_tiny_images() {
+        // The watermark shrinks to a single pixel, with no margin
+        let positions = [
+            (WatermarkPosition::TopLeft, (0, 0)),
+            (WatermarkPosition::BottomRight, (1, 39)),
+            (WatermarkPosition::Center, (0, 19)),
+        ];
+        for (position, (x, y)) in positions {
+            let watermark = white_watermark(200, 100, position, 0.25);
+            assert_eq!(watermarked_box(1, 1, &watermark), (0, 0, 1, 1), "{position:?}");
+            assert_eq!(watermarked_box(2, 40, &watermark), (x, y, x + 1, y + 1), "{position:?}");
+        }
+    }
+
+    fn text_config(opacity: f32, scale: f32) -> WatermarkConfig {
+        WatermarkConfig {
+            text: Some("Test".to_owned()),
+            opacity,
+            scale,
+            ..WatermarkConfig::default()
+        }
+    }
+
+    #[test]
+    fn no_watermark_is_loaded_unless_configured() {
+        assert!(load_watermark(&WatermarkConfig::default()).is_none());
+    }
+
+    #[test]
+    fn text_watermarks_are_loaded() {
+        let watermark = load_watermark(&text_config(0.5, 0.25)).unwrap();
+        assert!(watermark.overlay.get_width() > watermark.overlay.get_height());
+        // The opacity is applied to the rendered text
+        let alphas = watermark.overlay.get_raw_pixels().chunks_exact(4).map(|pixel| pixel[3]).max();
+        assert_eq!(alphas, Some(128));
+        // Changing any setting changes the fingerprint
+        let other = load_watermark(&text_config(0.5, 0.3)).unwrap();
+        assert_ne!(watermark.fingerprint, other.fingerprint);
+    }
+
+    #[test]
+    #[should_panic(expected = "not both")]
+    fn watermarks_cannot_be_both_an_image_and_text() {
+        load_watermark(&WatermarkConfig {
+            image: Some("logo.png".into()),
+            ..text_config(0.5, 0.25)
+        });
+    }
+
+    #[test]
+    #[should_panic(expected = "opacity")]
+    fn opacity_must_be_at_most_1() {
+        load_watermark(&text_config(1.5, 0.25));
+    }
+
+    #[test]
+    #[should_panic(expected = "scale")]
+    fn scale_must_be_above_0() {
+        load_watermark(&text_config(0.5, 0.0));
+    }
+
+    #[test]
+    #[should_panic(expected = "scale")]
+    fn scale_must_be_at_most_1() {
+        load_watermark(&text_config(0.5, 1.5));
+    }
+}